@@ -0,0 +1,101 @@
+// ** Balance status
+// While a balance is running (or paused) its arguments are saved in the tree of tree roots as
+// |BALANCE_OBJECTID (-4)| TEMPORARY_ITEM| 0|
+// so that it can be resumed after a crash or remount. Each of the three block group types has
+// its own set of filters.
+use crate::btrfs::{check_len, read_le_u32, read_le_u64};
+
+// BtrfsBalanceItem::flags
+pub const BTRFS_BALANCE_DATA: u64 = 1 << 0;
+pub const BTRFS_BALANCE_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BALANCE_METADATA: u64 = 1 << 2;
+pub const BTRFS_BALANCE_FORCE: u64 = 1 << 3;
+pub const BTRFS_BALANCE_RESUME: u64 = 1 << 4;
+
+// BtrfsBalanceArgs::flags, which filters are active
+pub const BTRFS_BALANCE_ARGS_PROFILES: u64 = 1 << 0;
+pub const BTRFS_BALANCE_ARGS_USAGE: u64 = 1 << 1;
+pub const BTRFS_BALANCE_ARGS_DEVID: u64 = 1 << 2;
+pub const BTRFS_BALANCE_ARGS_DRANGE: u64 = 1 << 3;
+pub const BTRFS_BALANCE_ARGS_VRANGE: u64 = 1 << 4;
+pub const BTRFS_BALANCE_ARGS_LIMIT: u64 = 1 << 5;
+pub const BTRFS_BALANCE_ARGS_LIMIT_RANGE: u64 = 1 << 6;
+pub const BTRFS_BALANCE_ARGS_STRIPES_RANGE: u64 = 1 << 7;
+pub const BTRFS_BALANCE_ARGS_CONVERT: u64 = 1 << 8;
+pub const BTRFS_BALANCE_ARGS_SOFT: u64 = 1 << 9;
+pub const BTRFS_BALANCE_ARGS_USAGE_RANGE: u64 = 1 << 10;
+
+/// Size of the filter arguments of one block group type
+const BALANCE_ARGS_SIZE: usize = 0x88;
+
+/// Filters of one block group type. `usage` and `limit` share their storage with the
+/// min/max range variants, which one applies depends on `flags`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BtrfsBalanceArgs {
+    pub profiles: u64,    // 0x00: BTRFS_BLOCK_GROUP_* profile bits
+    pub usage: u64,       // 0x08: usage percentage, or usage_min/usage_max
+    pub devid: u64,       // 0x10
+    pub pstart: u64,      // 0x18: physical range on devid
+    pub pend: u64,        // 0x20
+    pub vstart: u64,      // 0x28: logical range
+    pub vend: u64,        // 0x30
+    pub target: u64,      // 0x38: profile to convert to
+    pub flags: u64,       // 0x40: BTRFS_BALANCE_ARGS_*
+    pub limit: u64,       // 0x48: number of chunks, or limit_min/limit_max
+    pub stripes_min: u32, // 0x50
+    pub stripes_max: u32, // 0x54
+}
+
+/// Balance status item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsBalanceItem {
+    pub flags: u64, // 0x00: BTRFS_BALANCE_*
+    pub data: BtrfsBalanceArgs,
+    pub meta: BtrfsBalanceArgs,
+    pub sys: BtrfsBalanceArgs,
+}
+
+impl BtrfsBalanceArgs {
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, BALANCE_ARGS_SIZE)?;
+        Ok(BtrfsBalanceArgs {
+            profiles: read_le_u64(buffer, 0x00),
+            usage: read_le_u64(buffer, 0x08),
+            devid: read_le_u64(buffer, 0x10),
+            pstart: read_le_u64(buffer, 0x18),
+            pend: read_le_u64(buffer, 0x20),
+            vstart: read_le_u64(buffer, 0x28),
+            vend: read_le_u64(buffer, 0x30),
+            target: read_le_u64(buffer, 0x38),
+            flags: read_le_u64(buffer, 0x40),
+            limit: read_le_u64(buffer, 0x48),
+            stripes_min: read_le_u32(buffer, 0x50),
+            stripes_max: read_le_u32(buffer, 0x54),
+        })
+    }
+
+    /// (min, max) usage when BTRFS_BALANCE_ARGS_USAGE_RANGE is set
+    pub fn usage_range(&self) -> (u32, u32) {
+        (self.usage as u32, (self.usage >> 32) as u32)
+    }
+
+    /// (min, max) chunk count when BTRFS_BALANCE_ARGS_LIMIT_RANGE is set
+    pub fn limit_range(&self) -> (u32, u32) {
+        (self.limit as u32, (self.limit >> 32) as u32)
+    }
+}
+
+impl BtrfsBalanceItem {
+    /// Deserializes the balance item (0x1c0 bytes, the last 0x20 are unused)
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x08 + 3 * BALANCE_ARGS_SIZE)?;
+        let args =
+            |idx: usize| BtrfsBalanceArgs::from_buffer(&buffer[0x08 + idx * BALANCE_ARGS_SIZE..]);
+        Ok(BtrfsBalanceItem {
+            flags: read_le_u64(buffer, 0x00),
+            data: args(0)?,
+            meta: args(1)?,
+            sys: args(2)?,
+        })
+    }
+}
@@ -9,48 +9,290 @@
 pub const BTRFS_SUPER_INFO_OFFSET: u64 = 0x10000; // Primary superblock at 64KB
 pub const BTRFS_SUPER_INFO_SIZE: usize = 4096; // One page/block
 pub const BTRFS_DEFAULT_BLOCK_SIZE: usize = 16384; // 16 KB
-use std::io::{Read, Seek, SeekFrom};
+pub const BTRFS_DEFAULT_SECTOR_SIZE: usize = 4096; // 4 KB
+/// Number of tree blocks for_each_leaf requests at once
+pub const READAHEAD_NODES: usize = 256;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
 
-use crate::btrfs::{BtrfsInternalNode, BtrfsKey, BtrfsLeafNode};
+use crate::btrfs::{
+    BtrfsHeader, BtrfsInternalNode, BtrfsKey, BtrfsLeafNode, BtrfsSuperblock, BTRFS_ROOT_ITEM_KEY,
+    BTRFS_ROOT_TREE_OBJECTID,
+};
+use crate::cache::NodeCache;
+use crate::chunk::{ChunkMap, PhysicalAddress};
+use crate::csum::ChecksumType;
+use crate::devices::DeviceSet;
+use crate::raid56;
+use crate::root_tree::{find_root_item, BtrfsRootItem};
+use crate::storage::{BlockStorage, MappedStorage, MmapFile, ReadOnlyFile, WritableStorage};
+use crate::view::NodeView;
 
-pub struct BTree {
-    pub root: Option<Node>,
-    pub device: BlockDevice,
+pub struct BTree<S: BlockStorage = File> {
+    pub tree_id: u64, // objectid of the tree, BTRFS_ROOT_TREE_OBJECTID for the tree of tree roots
+    pub root: Option<Arc<Node>>,
+    pub root_item: Option<BtrfsRootItem>, // None for the trees found through the superblock
+    pub devices: DeviceSet<S>,
+    pub superblock: BtrfsSuperblock,
+    pub chunk_map: ChunkMap,
+    pub cache: Arc<NodeCache>, // shared by every tree opened from this one
+    bad_copies: Arc<Mutex<Vec<BadCopy>>>, // shared like the cache, see bad_copies()
 }
 
-pub struct BlockDevice {
-    pub handle: std::fs::File,
-    pub size: usize,
+/// A copy of a mirrored block that couldn't be used, found while reading another copy instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadCopy {
+    pub logical: u64,
+    pub mirror: usize, // 1 for the first stripe of the chunk
+    pub devid: u64,
+    pub physical: u64,
+    pub reason: String,
 }
 
-#[derive(Clone)]
+/// Byte addressed access to a device. Block reads use the node and sector sizes of the
+/// filesystem once they are known from the superblock.
+/// The device can be a window of its storage (a partition of a disk image), in which case all
+/// offsets are relative to the start of the window.
+/// Clones share the storage, so every tree opened from a BTree reads through the same handle.
+pub struct BlockDevice<S: BlockStorage = File> {
+    pub handle: Arc<S>,
+    pub start: u64,      // offset of the device in the storage
+    pub size: u64,       // size of the device in bytes
+    pub nodesize: u32,   // size of a tree block
+    pub sectorsize: u32, // smallest unit of data
+}
+
+impl<S: BlockStorage> Clone for BlockDevice<S> {
+    fn clone(&self) -> Self {
+        BlockDevice {
+            handle: self.handle.clone(),
+            start: self.start,
+            size: self.size,
+            nodesize: self.nodesize,
+            sectorsize: self.sectorsize,
+        }
+    }
+}
+
+/// A leaf found by BTree::leaf_for
+#[derive(Clone, Debug)]
+pub struct LeafPosition {
+    pub leaf: Arc<Node>,             // always a Node::Leaf
+    pub next_leaf: Option<BtrfsKey>, // first key of the following leaf, None for the last one
+}
+
+#[derive(Clone, Debug)]
 pub enum Node {
     Internal(BtrfsInternalNode),
     Leaf(BtrfsLeafNode),
 }
 
-impl BlockDevice {
+impl Node {
+    /// Parses a tree block as a leaf or an internal node depending on its level
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        if BtrfsHeader::from_buffer(buffer)?.level == 0 {
+            Ok(Node::Leaf(BtrfsLeafNode::from_buffer(buffer)?))
+        } else {
+            Ok(Node::Internal(BtrfsInternalNode::from_buffer(buffer)?))
+        }
+    }
+
+    pub fn header(&self) -> &BtrfsHeader {
+        match self {
+            Node::Internal(node) => &node.header,
+            Node::Leaf(leaf) => &leaf.header,
+        }
+    }
+}
+
+impl BlockDevice<File> {
     pub fn new(path: &str) -> Result<Self, std::io::Error> {
         let handle = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)?;
-        let size = handle.metadata()?.len() as usize;
-        Ok(BlockDevice { handle, size })
+        Self::from_storage(handle)
     }
-    pub fn read_block(&mut self, block_ptr: u64) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = vec![0; self.size];
-        self.handle
-            .seek(SeekFrom::Start(block_ptr * self.size as u64))
-            .expect("Failed to seek the block ptr");
+}
+
+impl BlockDevice<ReadOnlyFile> {
+    /// Opens the device without write permission. Fails if it can't be opened read-only, and
+    /// the resulting device has no write methods at all.
+    pub fn open_read_only(path: &str) -> Result<Self, std::io::Error> {
+        Self::from_storage(ReadOnlyFile::open(path)?)
+    }
+}
+
+impl<S: BlockStorage> BlockDevice<S> {
+    /// Wraps any storage backend, e.g. an in-memory image or a ReadOnlyFile
+    pub fn from_storage(handle: S) -> Result<Self, std::io::Error> {
+        let size = handle.size()?;
+        Self::from_storage_window(handle, 0, size)
+    }
+
+    /// Uses the `len` bytes of the storage starting at `start` as the device, e.g. one
+    /// partition of a whole disk image (see partition::find_btrfs_partitions)
+    pub fn from_storage_window(handle: S, start: u64, len: u64) -> Result<Self, std::io::Error> {
+        Self::from_shared_storage_window(Arc::new(handle), start, len)
+    }
+
+    /// Same as from_storage_window on storage shared with other devices, e.g. the partitions
+    /// of one disk image
+    pub fn from_shared_storage_window(
+        handle: Arc<S>,
+        start: u64,
+        len: u64,
+    ) -> Result<Self, std::io::Error> {
+        let storage_size = handle.size()?;
+        if start.checked_add(len).is_none_or(|end| end > storage_size) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "window of {} bytes at {} is past the end of the storage ({} bytes)",
+                    len, start, storage_size
+                ),
+            ));
+        }
+        Ok(BlockDevice {
+            handle,
+            start,
+            size: len,
+            nodesize: BTRFS_DEFAULT_BLOCK_SIZE as u32,
+            sectorsize: BTRFS_DEFAULT_SECTOR_SIZE as u32,
+        })
+    }
+
+    /// Checks that `len` bytes at `offset` are inside the device and returns where they are in
+    /// the storage
+    fn storage_offset(&self, offset: u64, len: usize) -> Result<u64, std::io::Error> {
+        if offset
+            .checked_add(len as u64)
+            .is_none_or(|end| end > self.size)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} bytes at {} are past the end of the device ({} bytes)",
+                    len, offset, self.size
+                ),
+            ));
+        }
+        Ok(self.start + offset)
+    }
+
+    /// Reads `len` bytes at byte `offset`. Reads that would go past the end of the device fail
+    /// with UnexpectedEof instead of returning fewer bytes.
+    pub fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>, std::io::Error> {
+        let storage_offset = self.storage_offset(offset, len)?;
+        let mut buffer = vec![0; len];
         self.handle
-            .read_exact(&mut buffer)
-            .expect("Failed to read the data to buffer");
+            .read_at(storage_offset, &mut buffer)
+            .map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("read of {} bytes at {} failed: {}", len, offset, err),
+                )
+            })?;
         Ok(buffer)
     }
+
+    /// Reads a tree block (nodesize bytes) at a physical byte address. Tree pointers hold
+    /// logical addresses, BTree::read_node maps them through the chunk map before calling this.
+    pub fn read_block(&self, bytenr: u64) -> Result<Vec<u8>, std::io::Error> {
+        self.read_at(bytenr, self.nodesize as usize)
+    }
+
+    /// Reads a tree block at each physical byte address, letting the storage keep all the reads
+    /// in flight at once when it can
+    pub fn read_blocks(&self, bytenrs: &[u64]) -> Result<Vec<Vec<u8>>, std::io::Error> {
+        let len = self.nodesize as usize;
+        let offsets = bytenrs
+            .iter()
+            .map(|&bytenr| self.storage_offset(bytenr, len))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut blocks = vec![vec![0; len]; bytenrs.len()];
+        let mut reads: Vec<_> = offsets
+            .into_iter()
+            .zip(blocks.iter_mut().map(|block| block.as_mut_slice()))
+            .collect();
+        self.handle.read_batch(&mut reads)?;
+        Ok(blocks)
+    }
+
+    /// Reads a sector (sectorsize bytes) at a physical byte address
+    pub fn read_sector(&self, bytenr: u64) -> Result<Vec<u8>, std::io::Error> {
+        self.read_at(bytenr, self.sectorsize as usize)
+    }
+
+    /// Reads the primary superblock and checks its magic
+    pub fn read_superblock(&self) -> Result<BtrfsSuperblock, std::io::Error> {
+        let buffer = self.read_at(BTRFS_SUPER_INFO_OFFSET, BTRFS_SUPER_INFO_SIZE)?;
+        let superblock = BtrfsSuperblock::from_buffer(&buffer)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        if !superblock.has_magic() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Not a valid BTRFS filesystem",
+            ));
+        }
+        Ok(superblock)
+    }
+
+    /// Switches block reads to the node and sector sizes of the filesystem
+    pub fn set_block_sizes(&mut self, superblock: &BtrfsSuperblock) -> Result<(), std::io::Error> {
+        let valid = |size: u32| size.is_power_of_two() && size >= 512;
+        if !valid(superblock.nodesize) || !valid(superblock.sectorsize) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "invalid nodesize {} or sectorsize {} in superblock",
+                    superblock.nodesize, superblock.sectorsize
+                ),
+            ));
+        }
+        self.nodesize = superblock.nodesize;
+        self.sectorsize = superblock.sectorsize;
+        Ok(())
+    }
+}
+
+impl BlockDevice<MmapFile> {
+    /// Maps the whole device read-only into memory
+    pub fn open_mmap(path: &str) -> Result<Self, std::io::Error> {
+        Self::from_storage(MmapFile::open(path)?)
+    }
 }
 
-impl BTree {
+impl<S: MappedStorage> BlockDevice<S> {
+    /// Borrows the tree block (nodesize bytes) at a physical byte address
+    pub fn block_bytes(&self, bytenr: u64) -> Result<&[u8], std::io::Error> {
+        let len = self.nodesize as usize;
+        let start = self.storage_offset(bytenr, len)?;
+        usize::try_from(start)
+            .ok()
+            .and_then(|start| self.handle.bytes().get(start..start.checked_add(len)?))
+            .ok_or_else(|| std::io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl<S: WritableStorage> BlockDevice<S> {
+    /// Writes a block at a physical byte address and flushes it to the storage. Fails while
+    /// the storage is shared with another device or tree.
+    pub fn write_block(&mut self, bytenr: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        let storage_offset = self.storage_offset(bytenr, data.len())?;
+        let handle = Arc::get_mut(&mut self.handle).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::ResourceBusy,
+                "storage is shared with other open devices",
+            )
+        })?;
+        handle.write_at(storage_offset, data)?;
+        handle.flush()
+    }
+}
+
+impl BTree<File> {
     pub fn new(device_path: &str) -> Result<Self, std::io::Error> {
         // /dev/sda2          # Second partition on first SATA drive
         // /dev/nvme0n1p1     # First partition on NVMe drive
@@ -58,64 +300,734 @@ impl BTree {
         // for testing and development:
         // "./test_fs.img"    # Regular file simulating a block device
         // "/tmp/btrfs.img"   # Temporary filesystem image
+        Self::from_device(BlockDevice::new(device_path)?)
+    }
+}
 
-        let mut device = BlockDevice::new(device_path)?;
-        // In BTRFS, superblock is typically at block 0
-        let superblock_data = device.read_block(0)?;
-        let root_ptr = {
-            let magic = &superblock_data[0..8];
-            if magic != b"_BHRfs_M" {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Not a valid BTRFS filesystem",
-                ));
-            }
-            // Root tree pointer is at offset 0x68 (104 bytes) in superblock
-            // we will use byte order conversion because BTRFS uses little-endian
-            let ptr_bytes = &superblock_data[104..112];
-            u64::from_le_bytes(ptr_bytes.try_into().unwrap())
+impl BTree<ReadOnlyFile> {
+    /// Opens the filesystem for analysis only, see BlockDevice::open_read_only
+    pub fn open_read_only(device_path: &str) -> Result<Self, std::io::Error> {
+        Self::from_device(BlockDevice::open_read_only(device_path)?)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl BTree<crate::uring::UringFile> {
+    /// Opens the filesystem read-only on io_uring, for batched reads in for_each_leaf
+    pub fn open_uring(device_path: &str) -> Result<Self, std::io::Error> {
+        Self::from_device(BlockDevice::from_storage(crate::uring::UringFile::open(
+            device_path,
+        )?)?)
+    }
+}
+
+impl BTree<MmapFile> {
+    /// Opens the filesystem on a read-only memory mapping, see scan_range for zero-copy scans
+    pub fn open_mmap(device_path: &str) -> Result<Self, std::io::Error> {
+        Self::from_device(BlockDevice::open_mmap(device_path)?)
+    }
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// Opens the tree on an already opened device, whatever its storage backend.
+    /// Reads the superblock, bootstraps the chunk map from its sys_chunk_array, completes it
+    /// from the chunk tree and loads the root of the tree of tree roots.
+    pub fn from_device(device: BlockDevice<S>) -> Result<Self, std::io::Error> {
+        Self::from_device_with_cache(device, Arc::new(NodeCache::default()))
+    }
+
+    /// Same as from_device, with nodes cached in `cache`, which may be shared with other trees
+    pub fn from_device_with_cache(
+        device: BlockDevice<S>,
+        cache: Arc<NodeCache>,
+    ) -> Result<Self, std::io::Error> {
+        Self::from_device_set_with_cache(DeviceSet::from_device(device)?, cache)
+    }
+
+    /// Opens a filesystem spanning several devices, see DeviceSet::assemble.
+    /// Devices listed in the chunk tree may be missing from the set, the filesystem is then
+    /// opened degraded (see is_degraded): blocks with a copy left on the present devices, or
+    /// that parity can rebuild, are still read, the others fail with a NotFound error naming
+    /// the missing device.
+    pub fn from_device_set(devices: DeviceSet<S>) -> Result<Self, std::io::Error> {
+        Self::from_device_set_with_cache(devices, Arc::new(NodeCache::default()))
+    }
+
+    pub fn from_device_set_with_cache(
+        devices: DeviceSet<S>,
+        cache: Arc<NodeCache>,
+    ) -> Result<Self, std::io::Error> {
+        let superblock = devices.superblock.clone();
+        let chunk_map = ChunkMap::from_superblock(&superblock)?;
+
+        let mut tree = BTree {
+            tree_id: BTRFS_ROOT_TREE_OBJECTID,
+            root: None,
+            root_item: None,
+            devices,
+            superblock,
+            chunk_map,
+            cache,
+            bad_copies: Arc::default(),
         };
-        todo!()
+
+        let chunk_root = tree.read_node(
+            tree.superblock.chunk_root,
+            tree.superblock.chunk_root_generation,
+        )?;
+        let mut chunk_leaves = Vec::new();
+        tree.collect_leaves(
+            &chunk_root,
+            &BtrfsKey::MIN,
+            &BtrfsKey::MAX,
+            &mut chunk_leaves,
+        )?;
+        for leaf in &chunk_leaves {
+            tree.chunk_map.insert_items(leaf.iter_items())?;
+            tree.devices.insert_dev_items(leaf.iter_items())?;
+        }
+        tree.root = Some(tree.read_node(tree.superblock.root, tree.superblock.generation)?);
+        Ok(tree)
+    }
+
+    /// Whether devices of the filesystem are missing, see DeviceSet::missing_devices
+    pub fn is_degraded(&self) -> bool {
+        !self.devices.is_complete()
     }
 
-    pub fn search<'a>(&'a self, key: &BtrfsKey) -> Option<&'a [u8]> {
+    /// Opens another tree (extent, csum, fs tree, a subvolume...) through its ROOT_ITEM in the
+    /// tree of tree roots. The new tree shares the device, chunk map and node cache.
+    pub fn open_tree(&self, tree_id: u64) -> Result<Self, std::io::Error> {
+        let min = BtrfsKey::new(tree_id, BTRFS_ROOT_ITEM_KEY, 0);
+        let max = BtrfsKey::new(tree_id, BTRFS_ROOT_ITEM_KEY, u64::MAX);
+        let leaves = self.leaves_in_range(&min, &max)?;
+        let (_, root_item) =
+            find_root_item(leaves.iter().flat_map(|leaf| leaf.iter_items()), tree_id)?.ok_or_else(
+                || {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("no ROOT_ITEM for tree {}", tree_id),
+                    )
+                },
+            )?;
+
+        let mut tree = BTree {
+            tree_id,
+            root: None,
+            root_item: None,
+            devices: self.devices.clone(),
+            superblock: self.superblock.clone(),
+            chunk_map: self.chunk_map.clone(),
+            cache: self.cache.clone(),
+            bad_copies: self.bad_copies.clone(),
+        };
+        tree.root = Some(tree.read_node(root_item.bytenr, root_item.generation)?);
+        tree.root_item = Some(root_item);
+        Ok(tree)
+    }
+
+    /// Returns the payload of the item with exactly this key
+    pub fn search(&self, key: &BtrfsKey) -> Result<Option<Vec<u8>>, std::io::Error> {
         match &self.root {
-            None => None,
+            None => Ok(None),
             Some(node) => self.search_node(node, key),
         }
     }
 
-    pub fn search_node<'a>(&'a self, node: &'a Node, search_key: &BtrfsKey) -> Option<&'a [u8]> {
+    pub fn search_node(
+        &self,
+        node: &Node,
+        search_key: &BtrfsKey,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
         match node {
             Node::Leaf(leaf) => {
                 match leaf.items.binary_search_by(|item| item.key.cmp(search_key)) {
-                    Ok(idx) => {
-                        let item = &leaf.items[idx];
-                        let start = item.data_offset as usize;
-                        let end = start + item.data_size as usize;
-                        Some(&leaf.data[start..end])
-                    }
-                    Err(_) => None,
+                    Ok(idx) => Ok(leaf.item_data(idx).map(|data| data.to_vec())),
+                    Err(_) => Ok(None),
                 }
             }
             Node::Internal(node) => {
-                match node.keys.binary_search(search_key) {
-                    Ok(idx) => {
-                        // Key found - follow corresponding pointer
-                        // Note: In a real implementation, you'd need to load the block
-                        // pointed to by block_ptrs[idx]
-                        todo!()
+                // keys[i] is the smallest key under block_ptrs[i]
+                let idx = match node.keys.binary_search(search_key) {
+                    Ok(idx) => idx,
+                    Err(idx) if idx > 0 => idx - 1,
+                    Err(_) => return Ok(None),
+                };
+                let child = self.read_node(node.block_ptrs[idx], node.generations[idx])?;
+                self.search_node(&child, search_key)
+            }
+        }
+    }
+
+    /// Returns, in key order, every leaf that may hold keys in `min..=max`
+    pub fn leaves_in_range(
+        &self,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+    ) -> Result<Vec<BtrfsLeafNode>, std::io::Error> {
+        let mut leaves = Vec::new();
+        if let Some(root) = &self.root {
+            self.collect_leaves(root, min, max, &mut leaves)?;
+        }
+        Ok(leaves)
+    }
+
+    /// Returns the leaf that would hold `key`, along with the first key of the leaf after it
+    /// (None for the last leaf), which is where to continue a scan past this leaf
+    pub fn leaf_for(&self, key: &BtrfsKey) -> Result<Option<LeafPosition>, std::io::Error> {
+        let Some(mut node) = self.root.clone() else {
+            return Ok(None);
+        };
+        let mut next_leaf = None;
+        loop {
+            let child = match node.as_ref() {
+                Node::Leaf(_) => {
+                    return Ok(Some(LeafPosition {
+                        leaf: node,
+                        next_leaf,
+                    }))
+                }
+                Node::Internal(internal) => {
+                    let idx = match internal.keys.binary_search(key) {
+                        Ok(idx) => idx,
+                        Err(idx) => idx.saturating_sub(1),
+                    };
+                    if let Some(next) = internal.keys.get(idx + 1) {
+                        next_leaf = Some(next.clone());
                     }
-                    Err(idx) if idx > 0 => {
-                        // Key not found - follow the pointer just before insertion point
-                        // In a real implementation, you'd load the child node and continue searching
-                        todo!()
+                    self.read_node(internal.block_ptrs[idx], internal.generations[idx])?
+                }
+            };
+            node = child;
+        }
+    }
+
+    /// Returns, in key order, a copy of every (key, payload) in `min..=max`
+    pub fn items_in_range(
+        &self,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+    ) -> Result<Vec<(BtrfsKey, Vec<u8>)>, std::io::Error> {
+        let mut items = Vec::new();
+        self.for_each_leaf(min, max, |leaf| {
+            items.extend(
+                leaf.iter_items()
+                    .filter(|(key, _)| (min..=max).contains(key))
+                    .map(|(key, data)| (key.clone(), data.to_vec())),
+            );
+            Ok(())
+        })?;
+        Ok(items)
+    }
+
+    fn collect_leaves(
+        &self,
+        node: &Node,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        leaves: &mut Vec<BtrfsLeafNode>,
+    ) -> Result<(), std::io::Error> {
+        match node {
+            Node::Leaf(leaf) => leaves.push(leaf.clone()),
+            Node::Internal(node) => {
+                for (idx, &block_ptr) in node.block_ptrs.iter().enumerate() {
+                    // child idx holds keys from keys[idx] up to keys[idx + 1]
+                    let starts_after_max = node.keys[idx] > *max;
+                    let ends_before_min = node.keys.get(idx + 1).is_some_and(|next| next <= min);
+                    if starts_after_max {
+                        break;
+                    }
+                    if ends_before_min {
+                        continue;
+                    }
+                    let child = self.read_node(block_ptr, node.generations[idx])?;
+                    self.collect_leaves(&child, min, max, leaves)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every leaf that may hold keys in `min..=max`, in key order.
+    ///
+    /// The tree is walked one level at a time and all the children needed at a level are
+    /// requested together (READAHEAD_NODES at a time), so a backend such as UringFile can keep
+    /// them in flight at once.
+    pub fn for_each_leaf<F>(
+        &self,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        mut f: F,
+    ) -> Result<(), std::io::Error>
+    where
+        F: FnMut(&BtrfsLeafNode) -> Result<(), std::io::Error>,
+    {
+        let Some(root) = &self.root else {
+            return Ok(());
+        };
+        let mut level = vec![root.clone()];
+        loop {
+            let mut children = Vec::new(); // (logical, generation) of the next level
+            for node in &level {
+                match node.as_ref() {
+                    Node::Leaf(leaf) => f(leaf)?,
+                    Node::Internal(node) => {
+                        for idx in 0..node.block_ptrs.len() {
+                            let starts_after_max = node.keys[idx] > *max;
+                            let ends_before_min =
+                                node.keys.get(idx + 1).is_some_and(|next| next <= min);
+                            if starts_after_max {
+                                break;
+                            }
+                            if !ends_before_min {
+                                children.push((node.block_ptrs[idx], node.generations[idx]));
+                            }
+                        }
+                    }
+                }
+            }
+            if children.is_empty() {
+                return Ok(());
+            }
+            // leaves are handed out as they arrive instead of holding a whole level of them
+            level.clear();
+            let mut next = Vec::new();
+            for batch in children.chunks(READAHEAD_NODES) {
+                for node in self.read_nodes(batch)? {
+                    match node.as_ref() {
+                        Node::Leaf(leaf) => f(leaf)?,
+                        Node::Internal(_) => next.push(node),
                     }
-                    Err(_) => None,
                 }
             }
+            level = next;
         }
     }
+
+    /// Same as read_node for several (logical, generation) pointers, reading the ones that
+    /// aren't cached with a single BlockDevice::read_blocks per device. Blocks whose first
+    /// copy can't be used are read again one by one, trying the other mirrors.
+    pub fn read_nodes(&self, ptrs: &[(u64, u64)]) -> Result<Vec<Arc<Node>>, std::io::Error> {
+        let mut nodes: Vec<Option<Arc<Node>>> = ptrs
+            .iter()
+            .map(|&(logical, generation)| self.cache.get(logical, generation))
+            .collect();
+        // the missing blocks, grouped by device: devid -> (index in ptrs, physical offset)
+        let mut missing: BTreeMap<u64, Vec<(usize, u64)>> = BTreeMap::new();
+        for idx in (0..ptrs.len()).filter(|&idx| nodes[idx].is_none()) {
+            let physical = self.node_location(ptrs[idx].0)?;
+            missing
+                .entry(physical.devid)
+                .or_default()
+                .push((idx, physical.offset));
+        }
+        let mut blocks = Vec::new();
+        for (devid, reads) in missing {
+            let offsets: Vec<u64> = reads.iter().map(|&(_, offset)| offset).collect();
+            match self.devices.device(devid)?.read_blocks(&offsets) {
+                Ok(read) => blocks.extend(
+                    reads
+                        .iter()
+                        .map(|&(idx, _)| idx)
+                        .zip(read.into_iter().map(Some)),
+                ),
+                Err(_) => blocks.extend(reads.iter().map(|&(idx, _)| (idx, None))),
+            }
+        }
+
+        for (idx, block) in blocks {
+            let (logical, generation) = ptrs[idx];
+            let checked = block.and_then(|block| {
+                self.check_tree_block(logical, &block, Some(generation))
+                    .ok()
+            });
+            let node = match checked {
+                Some(node) => node,
+                None => self.read_tree_block(logical, Some(generation))?,
+            };
+            let node = Arc::new(node);
+            self.cache.insert(
+                logical,
+                generation,
+                node.clone(),
+                self.superblock.nodesize as usize,
+            );
+            nodes[idx] = Some(node);
+        }
+        Ok(nodes.into_iter().flatten().collect())
+    }
+
+    /// Where the first readable copy of the tree block at a logical address is
+    fn node_location(&self, logical: u64) -> Result<PhysicalAddress, std::io::Error> {
+        let copies = self.tree_block_copies(logical)?;
+        match copies
+            .iter()
+            .find(|copy| self.devices.devices.contains_key(&copy.devid))
+        {
+            Some(copy) => Ok(*copy),
+            None => Err(self.devices.device(copies[0].devid).err().unwrap()),
+        }
+    }
+
+    /// Returns the tree block at a logical address, from the cache or from the device.
+    /// `generation` is the one recorded by the parent pointer (or the superblock / ROOT_ITEM
+    /// for a root), a block written by another transaction is rejected.
+    pub fn read_node(&self, logical: u64, generation: u64) -> Result<Arc<Node>, std::io::Error> {
+        if let Some(node) = self.cache.get(logical, generation) {
+            return Ok(node);
+        }
+        let node = Arc::new(self.read_tree_block(logical, Some(generation))?);
+        self.cache.insert(
+            logical,
+            generation,
+            node.clone(),
+            self.superblock.nodesize as usize,
+        );
+        Ok(node)
+    }
+
+    /// Reads and parses the tree block at a logical address, bypassing the cache
+    pub fn read_node_uncached(&self, logical: u64) -> Result<Node, std::io::Error> {
+        self.read_tree_block(logical, None)
+    }
+
+    /// Copies of mirrored blocks that failed to read or verify so far, in the order they were
+    /// found. Shared by every tree opened from this one.
+    pub fn bad_copies(&self) -> Vec<BadCopy> {
+        self.bad_copies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    fn record_bad_copy(&self, logical: u64, mirror: usize, copy: PhysicalAddress, reason: String) {
+        self.bad_copies
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(BadCopy {
+                logical,
+                mirror: mirror + 1,
+                devid: copy.devid,
+                physical: copy.offset,
+                reason,
+            });
+    }
+
+    /// Reads a tree block trying each copy in turn, until one passes check_tree_block. The
+    /// copies that fail are recorded (see bad_copies), copies on missing devices are skipped.
+    /// In a RAID5/6 chunk the block is rebuilt from parity when its only copy fails.
+    fn read_tree_block(
+        &self,
+        logical: u64,
+        generation: Option<u64>,
+    ) -> Result<Node, std::io::Error> {
+        let copies = self.tree_block_copies(logical)?;
+        self.read_copies(logical, &copies, |device, copy| {
+            let block = device.read_block(copy.offset)?;
+            self.check_tree_block(logical, &block, generation)
+        })
+        .or_else(|err| {
+            self.rebuild_from_parity(logical, self.superblock.nodesize as usize, err, |block| {
+                self.check_tree_block(logical, block, generation)
+            })
+        })
+    }
+
+    /// Rebuilds `len` bytes at a logical address from the rest of its RAID5/6 row, after
+    /// reading them failed with `err`, and returns what `check` makes of them. With RAID6 the
+    /// rebuild from P is tried first, then the one from Q in case P is bad as well.
+    /// Outside of RAID5/6 chunks, and when the row can't be rebuilt, fails with `err` (and why
+    /// the rebuild failed).
+    fn rebuild_from_parity<T, F>(
+        &self,
+        logical: u64,
+        len: usize,
+        err: std::io::Error,
+        mut check: F,
+    ) -> Result<T, std::io::Error>
+    where
+        F: FnMut(&[u8]) -> Result<T, std::io::Error>,
+    {
+        let Some(row) = self.chunk_map.parity_row(logical)? else {
+            return Err(err);
+        };
+        let nr_data = row.data.len();
+        let read: Vec<Option<Vec<u8>>> = row
+            .members()
+            .iter()
+            .enumerate()
+            .map(|(idx, member)| {
+                if idx == row.index {
+                    return None;
+                }
+                let device = self.devices.device(member.devid).ok()?;
+                device.read_at(member.offset, len).ok()
+            })
+            .collect();
+        let mut attempts = vec![read.clone()];
+        if row.q.is_some() && read[nr_data].is_some() {
+            let mut without_p = read;
+            without_p[nr_data] = None;
+            attempts.push(without_p);
+        }
+
+        let mut why = None;
+        for mut attempt in attempts {
+            let rebuilt = raid56::rebuild(&mut attempt, nr_data)
+                .and_then(|()| check(attempt[row.index].as_deref().unwrap()));
+            match rebuilt {
+                Ok(value) => return Ok(value),
+                Err(rebuild_err) => why = Some(rebuild_err),
+            }
+        }
+        Err(std::io::Error::new(
+            err.kind(),
+            format!(
+                "{}; rebuilding it from parity failed: {}",
+                err,
+                why.unwrap()
+            ),
+        ))
+    }
+
+    /// Every copy of the tree block at a logical address. A tree block is never split over
+    /// stripes, one that would be is rejected.
+    fn tree_block_copies(&self, logical: u64) -> Result<Vec<PhysicalAddress>, std::io::Error> {
+        let mut pieces = self
+            .chunk_map
+            .map_range(logical, self.superblock.nodesize as u64)?;
+        if pieces.len() != 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("tree block {} crosses a stripe boundary", logical),
+            ));
+        }
+        Ok(pieces.remove(0).copies)
+    }
+
+    /// Calls `read` with each present copy of the data at a logical address until it succeeds
+    fn read_copies<'a, T, F>(
+        &'a self,
+        logical: u64,
+        copies: &[PhysicalAddress],
+        mut read: F,
+    ) -> Result<T, std::io::Error>
+    where
+        F: FnMut(&'a BlockDevice<S>, PhysicalAddress) -> Result<T, std::io::Error>,
+    {
+        let mut errors = Vec::new();
+        let mut tried = false;
+        for (mirror, &copy) in copies.iter().enumerate() {
+            let device = match self.devices.device(copy.devid) {
+                Ok(device) => device,
+                Err(err) => {
+                    errors.push(format!("mirror {}: {}", mirror + 1, err));
+                    continue;
+                }
+            };
+            tried = true;
+            match read(device, copy) {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    errors.push(format!("mirror {}: {}", mirror + 1, err));
+                    self.record_bad_copy(logical, mirror, copy, err.to_string());
+                }
+            }
+        }
+        Err(std::io::Error::new(
+            if tried {
+                std::io::ErrorKind::InvalidData
+            } else {
+                std::io::ErrorKind::NotFound
+            },
+            format!(
+                "no good copy of {} among {}: {}",
+                logical,
+                copies.len(),
+                errors.join("; ")
+            ),
+        ))
+    }
+
+    /// Verifies the checksum, address and generation of a tree block and parses it
+    fn check_tree_block(
+        &self,
+        logical: u64,
+        block: &[u8],
+        generation: Option<u64>,
+    ) -> Result<Node, std::io::Error> {
+        verify_tree_block(&self.superblock, logical, block, generation)?;
+        Node::from_buffer(block)
+    }
+
+    /// Reads `len` bytes at a logical address, e.g. file data. The range is split at chunk and
+    /// stripe boundaries (see ChunkMap::map_range) into one read per piece on the device
+    /// holding it. Each piece is read from its first copy for which `verify(logical, data)`
+    /// holds, the copies rejected on the way are recorded like bad tree block copies. A piece
+    /// of a RAID5/6 chunk that fails is rebuilt from parity.
+    pub fn read_logical<F>(
+        &self,
+        logical: u64,
+        len: usize,
+        mut verify: F,
+    ) -> Result<Vec<u8>, std::io::Error>
+    where
+        F: FnMut(u64, &[u8]) -> bool,
+    {
+        let mut data = Vec::with_capacity(len);
+        for piece in self.chunk_map.map_range(logical, len as u64)? {
+            let piece_len = piece.len as usize;
+            let mut check = |part: Vec<u8>| {
+                if !verify(piece.logical, &part) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "{} bytes at {} failed verification",
+                            piece_len, piece.logical
+                        ),
+                    ));
+                }
+                Ok(part)
+            };
+            let part = self
+                .read_copies(piece.logical, &piece.copies, |device, copy| {
+                    check(device.read_at(copy.offset, piece_len)?)
+                })
+                .or_else(|err| {
+                    self.rebuild_from_parity(piece.logical, piece_len, err, |part| {
+                        check(part.to_vec())
+                    })
+                })?;
+            data.extend_from_slice(&part);
+        }
+        Ok(data)
+    }
+}
+
+// Zero-copy access for storage held in memory. Nodes are viewed in place instead of being
+// parsed into owned nodes, and don't go through the node cache.
+impl<S: MappedStorage> BTree<S> {
+    /// Views the tree block at a logical address in place
+    /// (the first good copy of it, see read_node)
+    pub fn view_node(&self, logical: u64) -> Result<NodeView<'_>, std::io::Error> {
+        let copies = self.tree_block_copies(logical)?;
+        self.read_copies(logical, &copies, |device, copy| {
+            let block = device.block_bytes(copy.offset)?;
+            verify_tree_block(&self.superblock, logical, block, None)?;
+            NodeView::from_buffer(block)
+        })
+    }
+
+    /// Calls `f` with every (key, payload) in `min..=max`, in key order, without copying them
+    pub fn scan_range<F>(
+        &self,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        mut f: F,
+    ) -> Result<(), std::io::Error>
+    where
+        F: FnMut(BtrfsKey, &[u8]),
+    {
+        match &self.root {
+            None => Ok(()),
+            Some(root) => self.scan_node(self.view_node(root.header().block_nr)?, min, max, &mut f),
+        }
+    }
+
+    fn scan_node<F>(
+        &self,
+        node: NodeView<'_>,
+        min: &BtrfsKey,
+        max: &BtrfsKey,
+        f: &mut F,
+    ) -> Result<(), std::io::Error>
+    where
+        F: FnMut(BtrfsKey, &[u8]),
+    {
+        match node {
+            NodeView::Leaf(leaf) => {
+                let start = leaf.binary_search(min).unwrap_or_else(|idx| idx);
+                for idx in start..leaf.len() {
+                    let key = leaf.key(idx);
+                    if key > *max {
+                        break;
+                    }
+                    if let Some(data) = leaf.item_data(idx) {
+                        f(key, data);
+                    }
+                }
+            }
+            NodeView::Internal(node) => {
+                let first = node.child_for(min).unwrap_or(0);
+                for idx in first..node.len() {
+                    if node.key(idx) > *max {
+                        break;
+                    }
+                    let child = self.view_node(node.block_ptr(idx))?;
+                    check_generation(
+                        node.block_ptr(idx),
+                        child.header().generation,
+                        node.generation(idx),
+                    )?;
+                    self.scan_node(child, min, max, f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks a copy of a tree block: its checksum (csum_type of the superblock, over everything
+/// after the checksum field), the address in its header and, when known, its generation
+fn verify_tree_block(
+    superblock: &BtrfsSuperblock,
+    logical: u64,
+    block: &[u8],
+    generation: Option<u64>,
+) -> Result<(), std::io::Error> {
+    let header = BtrfsHeader::from_buffer(block)?;
+    let csum_type = ChecksumType::from_superblock(superblock)?;
+    if !csum_type.verify(&block[0x20..], &header.checksum) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("checksum mismatch in tree block {}", logical),
+        ));
+    }
+    check_block_nr(logical, header.block_nr)?;
+    if let Some(expected) = generation {
+        check_generation(logical, header.generation, expected)?;
+    }
+    Ok(())
+}
+
+/// Fails when a tree block wasn't written by the transaction its parent points to
+fn check_generation(logical: u64, generation: u64, expected: u64) -> Result<(), std::io::Error> {
+    if generation != expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "tree block {} has generation {}, expected {}",
+                logical, generation, expected
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails when the header of a tree block doesn't carry the address it was read from
+fn check_block_nr(logical: u64, block_nr: u64) -> Result<(), std::io::Error> {
+    if block_nr != logical {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("tree block at {} claims to be at {}", logical, block_nr),
+        ));
+    }
+    Ok(())
+}
+
+// Everything that modifies the tree needs writable storage, so none of it can be called on a
+// tree opened with BTree::open_read_only.
+impl<S: WritableStorage> BTree<S> {
     /// To insert items into a tree
     pub fn insert() {
         todo!()
@@ -129,10 +1041,6 @@ impl BTree {
     pub fn create_node() {
         todo!()
     }
-    /// To fetch data using block pointers
-    pub fn read_node() {
-        todo!()
-    }
     /// To persist node changes to disk
     pub fn write_node() {
         todo!()
@@ -146,3 +1054,361 @@ impl BTree {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::BTRFS_ROOT_ITEM_KEY;
+    use crate::chunk::{
+        BTRFS_BLOCK_GROUP_DATA, BTRFS_BLOCK_GROUP_DUP, BTRFS_BLOCK_GROUP_METADATA,
+        BTRFS_BLOCK_GROUP_RAID0, BTRFS_BLOCK_GROUP_RAID1, BTRFS_BLOCK_GROUP_RAID5,
+        BTRFS_BLOCK_GROUP_RAID6,
+    };
+    use crate::test_image::{internal, leaf, root_item, TestImage, CHUNK_LOGICAL, NODESIZE};
+
+    const ROOT: u64 = CHUNK_LOGICAL + 0x1000;
+    const LEAF_A: u64 = CHUNK_LOGICAL + 0x2000;
+    const LEAF_B: u64 = CHUNK_LOGICAL + 0x3000;
+    const FS_LEAF: u64 = CHUNK_LOGICAL + 0x4000;
+
+    fn root_item_key(id: u64) -> BtrfsKey {
+        BtrfsKey::new(id, BTRFS_ROOT_ITEM_KEY, 0)
+    }
+
+    /// Root tree with an internal root over two leaves: trees 1..=3 and 5..=7. Tree 5 has a
+    /// real ROOT_ITEM pointing at a leaf with a single item.
+    fn two_level_image() -> Vec<u8> {
+        let mut image = TestImage::new(ROOT, CHUNK_LOGICAL);
+        let items = |ids: &[u64]| -> Vec<(BtrfsKey, Vec<u8>)> {
+            ids.iter()
+                .map(|&id| (root_item_key(id), vec![id as u8; 8]))
+                .collect()
+        };
+        let mut items_b = items(&[5, 6, 7]);
+        items_b[0].1 = root_item(FS_LEAF);
+        image.put_node(LEAF_A, &leaf(LEAF_A, 1, &items(&[1, 2, 3])));
+        image.put_node(LEAF_B, &leaf(LEAF_B, 1, &items_b));
+        let fs_items = [(BtrfsKey::new(256, 1, 0), vec![0xaa; 4])];
+        image.put_node(FS_LEAF, &leaf(FS_LEAF, 5, &fs_items));
+        let ptrs = [(root_item_key(1), LEAF_A), (root_item_key(5), LEAF_B)];
+        image.put_node(ROOT, &internal(ROOT, 1, 1, &ptrs));
+        image.into_image()
+    }
+
+    #[test]
+    fn reads_are_byte_addressed_and_bounded() {
+        let mut device = BlockDevice::from_storage(two_level_image()).unwrap();
+        let superblock = device.read_superblock().unwrap();
+        device.set_block_sizes(&superblock).unwrap();
+        assert_eq!(device.nodesize, NODESIZE);
+
+        let magic = device.read_at(BTRFS_SUPER_INFO_OFFSET + 0x40, 8).unwrap();
+        assert_eq!(&magic, b"_BHRfS_M");
+        assert_eq!(device.read_block(0).unwrap().len(), NODESIZE as usize);
+
+        let err = device.read_at(device.size - 4, 8).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(device.read_at(u64::MAX, 1).is_err());
+    }
+
+    #[test]
+    fn search_descends_through_internal_nodes() {
+        let tree =
+            BTree::from_device(BlockDevice::from_storage(two_level_image()).unwrap()).unwrap();
+        assert!(tree.chunk_map.lookup(ROOT).is_some());
+
+        assert_eq!(tree.search(&root_item_key(2)).unwrap(), Some(vec![2; 8]));
+        assert_eq!(tree.search(&root_item_key(7)).unwrap(), Some(vec![7; 8]));
+        assert_eq!(tree.search(&root_item_key(4)).unwrap(), None);
+        assert_eq!(tree.search(&BtrfsKey::MIN).unwrap(), None);
+
+        let leaves = tree
+            .leaves_in_range(&root_item_key(6), &BtrfsKey::MAX)
+            .unwrap();
+        assert_eq!(leaves.len(), 1);
+        assert_eq!(leaves[0].header.block_nr, LEAF_B);
+        assert_eq!(
+            tree.leaves_in_range(&BtrfsKey::MIN, &BtrfsKey::MAX)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn open_tree_shares_the_file() {
+        let path = std::env::temp_dir().join(format!("btrfs-shared-{}.img", std::process::id()));
+        std::fs::write(&path, two_level_image()).unwrap();
+
+        let mut device = BlockDevice::new(path.to_str().unwrap()).unwrap();
+        device.write_block(0, &[0; 16]).unwrap();
+        let root_tree = BTree::new(path.to_str().unwrap()).unwrap();
+        let fs_tree = root_tree.open_tree(5).unwrap();
+        let root_device = root_tree.devices.device(1).unwrap();
+        assert!(Arc::ptr_eq(
+            &root_device.handle,
+            &fs_tree.devices.device(1).unwrap().handle
+        ));
+
+        // writes need the storage to themselves
+        let mut shared = root_device.clone();
+        let err = shared.write_block(0, &[0; 16]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn trees_share_the_node_cache() {
+        let cache = Arc::new(NodeCache::new(1 << 20));
+        let device = BlockDevice::from_storage(two_level_image()).unwrap();
+        let root_tree = BTree::from_device_with_cache(device, cache.clone()).unwrap();
+        let misses = cache.stats().misses;
+
+        let fs_tree = root_tree.open_tree(5).unwrap();
+        assert_eq!(fs_tree.tree_id, 5);
+        assert_eq!(
+            fs_tree.search(&BtrfsKey::new(256, 1, 0)).unwrap(),
+            Some(vec![0xaa; 4])
+        );
+        // LEAF_B was read to find the ROOT_ITEM, then FS_LEAF as the root of the fs tree
+        assert_eq!(cache.stats().misses, misses + 2);
+
+        // opening it again is served from the cache
+        let again = root_tree.open_tree(5).unwrap();
+        assert!(again.root.is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, misses + 2));
+        assert!(root_tree.open_tree(4).is_err());
+    }
+
+    #[test]
+    fn rejects_blocks_from_another_generation() {
+        let tree =
+            BTree::from_device(BlockDevice::from_storage(two_level_image()).unwrap()).unwrap();
+        let err = tree.read_node(LEAF_A, 2).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn scan_range_views_items_in_place() {
+        let tree =
+            BTree::from_device(BlockDevice::from_storage(two_level_image()).unwrap()).unwrap();
+        let mut ids = Vec::new();
+        tree.scan_range(&root_item_key(2), &root_item_key(6), |key, data| {
+            assert!(!data.is_empty());
+            ids.push(key.object_id);
+        })
+        .unwrap();
+        assert_eq!(ids, [2, 3, 5, 6]);
+
+        let NodeView::Internal(root) = tree.view_node(ROOT).unwrap() else {
+            panic!("expected an internal root");
+        };
+        assert_eq!(root.block_ptr(1), LEAF_B);
+        assert!(tree.view_node(LEAF_A + 0x100).is_err());
+    }
+
+    #[test]
+    fn for_each_leaf_walks_level_by_level() {
+        let tree =
+            BTree::from_device(BlockDevice::from_storage(two_level_image()).unwrap()).unwrap();
+        let mut leaves = Vec::new();
+        tree.for_each_leaf(&BtrfsKey::MIN, &BtrfsKey::MAX, |leaf| {
+            leaves.push(leaf.header.block_nr);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(leaves, [LEAF_A, LEAF_B]);
+
+        leaves.clear();
+        tree.for_each_leaf(&root_item_key(6), &root_item_key(7), |leaf| {
+            leaves.push(leaf.header.block_nr);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(leaves, [LEAF_B]);
+        assert_eq!(tree.cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn rejects_images_without_magic() {
+        let mut image = two_level_image();
+        image[BTRFS_SUPER_INFO_OFFSET as usize + 0x40] = 0;
+        let err = BTree::from_device(BlockDevice::from_storage(image).unwrap())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    const MIRRORED: u64 = 0x80_0000;
+
+    /// Root tree leaf with tree 5 rooted at MIRRORED, in a chunk with a copy per stripe
+    fn mirrored_images(devices: u64, type_: u64, stripes: &[(u64, u64)]) -> Vec<Vec<u8>> {
+        let mut image = TestImage::with_devices(ROOT, CHUNK_LOGICAL, devices);
+        image.add_chunk(MIRRORED, 0x10_0000, type_, stripes);
+        let root_items = [(root_item_key(5), root_item(MIRRORED))];
+        image.put_node(ROOT, &leaf(ROOT, 1, &root_items));
+        let fs_items = [(BtrfsKey::new(256, 1, 0), vec![0xcc; 4])];
+        image.put_node(MIRRORED, &leaf(MIRRORED, 5, &fs_items));
+        image.put_data(MIRRORED + 0x1000, b"file data");
+        image.finish()
+    }
+
+    fn open_images(images: Vec<Vec<u8>>) -> BTree<Vec<u8>> {
+        let devices = images
+            .into_iter()
+            .map(|image| BlockDevice::from_storage(image).unwrap())
+            .collect();
+        BTree::from_device_set(DeviceSet::assemble(devices).unwrap().remove(0)).unwrap()
+    }
+
+    #[test]
+    fn raid1_reads_fall_back_to_the_good_mirror() {
+        let stripes = [(1, 0x20_0000), (2, 0x10_0000)];
+        let mut images = mirrored_images(
+            2,
+            BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID1,
+            &stripes,
+        );
+        images[0][0x20_0000 + 0x800] ^= 0xff; // tree block, caught by its checksum
+        images[0][0x20_1000] ^= 0xff; // data, caught by `verify`
+
+        let tree = open_images(images);
+        let fs_tree = tree.open_tree(5).unwrap();
+        assert_eq!(
+            fs_tree.search(&BtrfsKey::new(256, 1, 0)).unwrap(),
+            Some(vec![0xcc; 4])
+        );
+        let data = tree
+            .read_logical(MIRRORED + 0x1000, 9, |_, data| data == b"file data")
+            .unwrap();
+        assert_eq!(data, b"file data");
+
+        let bad = tree.bad_copies();
+        assert_eq!(bad.len(), 2);
+        assert_eq!(
+            (bad[0].logical, bad[0].mirror, bad[0].devid),
+            (MIRRORED, 1, 1)
+        );
+        assert_eq!(bad[0].physical, 0x20_0000);
+        assert!(bad[0].reason.contains("checksum"));
+        assert_eq!((bad[1].logical, bad[1].devid), (MIRRORED + 0x1000, 1));
+    }
+
+    #[test]
+    fn dup_fails_when_every_copy_is_bad() {
+        let stripes = [(1, 0x20_0000), (1, 0x30_0000)];
+        let mut images = mirrored_images(
+            1,
+            BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP,
+            &stripes,
+        );
+        images[0][0x20_0000 + 0x800] ^= 0xff;
+        let tree = open_images(images.clone());
+        assert!(tree.view_node(MIRRORED).is_ok());
+        assert!(tree.open_tree(5).is_ok());
+        assert_eq!(tree.bad_copies().len(), 2);
+        assert!(tree.bad_copies().iter().all(|bad| bad.mirror == 1));
+
+        images[0][0x30_0000 + 0x800] ^= 0xff;
+        let tree = open_images(images);
+        let err = tree.open_tree(5).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("mirror 2"));
+        assert_eq!(tree.bad_copies().len(), 2);
+    }
+
+    #[test]
+    fn raid0_reads_are_split_per_device() {
+        const DATA: u64 = 0x100_0000;
+        let mut image = TestImage::with_devices(ROOT, CHUNK_LOGICAL, 2);
+        let stripes = [(1, 0x20_0000), (2, 0x20_0000)];
+        image.add_chunk(
+            DATA,
+            0x40_0000,
+            BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID0,
+            &stripes,
+        );
+        image.put_node(ROOT, &leaf(ROOT, 1, &[]));
+        // 64KiB stripes: logical DATA.. on device 1, DATA + 64KiB.. on device 2, then device 1
+        // again right after its first stripe
+        image.put_physical(1, 0x20_0000 + 0xfffc, b"abcd");
+        image.put_physical(2, 0x20_0000, &[b'e'; 0x1_0000]);
+        image.put_physical(1, 0x21_0000, b"fgh");
+        let tree = open_images(image.finish());
+
+        let mut pieces = Vec::new();
+        let data = tree
+            .read_logical(DATA + 0xfffc, 0x1_0007, |logical, data| {
+                pieces.push((logical, data.len()));
+                true
+            })
+            .unwrap();
+        assert_eq!(&data[..4], b"abcd");
+        assert!(data[4..0x1_0004].iter().all(|&b| b == b'e'));
+        assert_eq!(&data[0x1_0004..], b"fgh");
+        assert_eq!(
+            pieces,
+            [
+                (DATA + 0xfffc, 4),
+                (DATA + 0x1_0000, 0x1_0000),
+                (DATA + 0x2_0000, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn raid5_and_raid6_rebuild_bad_stripes_from_parity() {
+        // row 0 of MIRRORED: data pieces on devices 1, 2 (3), P and Q on the next ones
+        let stripes = [(1, 0x20_0000), (2, 0x20_0000), (3, 0x20_0000)];
+        let mut images = mirrored_images(
+            3,
+            BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID5,
+            &stripes,
+        );
+        images[0][0x20_1000] ^= 0xff;
+        let tree = open_images(images);
+        let data = tree
+            .read_logical(MIRRORED + 0x1000, 9, |_, data| data == b"file data")
+            .unwrap();
+        assert_eq!(data, b"file data");
+        assert_eq!(tree.bad_copies()[0].devid, 1);
+
+        let stripes = [
+            (1, 0x20_0000),
+            (2, 0x20_0000),
+            (3, 0x20_0000),
+            (4, 0x20_0000),
+        ];
+        let mut images = mirrored_images(
+            4,
+            BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID6,
+            &stripes,
+        );
+        images[0][0x20_0800] ^= 0xff; // the fs tree leaf
+        images[2][0x20_0800] ^= 0xff; // and P, so Q is needed
+        let tree = open_images(images.clone());
+        let fs_tree = tree.open_tree(5).unwrap();
+        assert_eq!(
+            fs_tree.search(&BtrfsKey::new(256, 1, 0)).unwrap(),
+            Some(vec![0xcc; 4])
+        );
+
+        images[3][0x20_0800] ^= 0xff; // Q as well
+        let err = open_images(images).open_tree(5).err().unwrap();
+        assert!(err.to_string().contains("rebuilding it from parity failed"));
+    }
+
+    #[test]
+    fn leaf_for_tells_where_the_next_leaf_starts() {
+        let tree =
+            BTree::from_device(BlockDevice::from_storage(two_level_image()).unwrap()).unwrap();
+        let position = tree.leaf_for(&root_item_key(4)).unwrap().unwrap();
+        assert_eq!(position.leaf.header().block_nr, LEAF_A);
+        assert_eq!(position.next_leaf, Some(root_item_key(5)));
+        let position = tree.leaf_for(&BtrfsKey::MAX).unwrap().unwrap();
+        assert_eq!(position.leaf.header().block_nr, LEAF_B);
+        assert_eq!(position.next_leaf, None);
+    }
+}
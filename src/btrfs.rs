@@ -1,5 +1,9 @@
+// The banner doc comments below are followed by blank lines
+#![allow(clippy::empty_line_after_doc_comments)]
 use std::cmp::Ordering;
 
+use serde::{Deserialize, Serialize};
+
 /// ***************************************************************************************
 ///Link for further info: https://btrfs.readthedocs.io/en/latest/dev/dev-btrfs-design.html*
 /// ***************************************************************************************
@@ -22,10 +26,10 @@ pub struct BtrfsLeafNode {
 /// Checksum is only calculated before writing the block to disk.
 #[derive(Clone, Debug)]
 pub struct BtrfsHeader {
-    pub checksum: u32,  // for data integrity
-    pub fsid: [u8; 16], // file system identifier
-    pub block_nr: u64,  // physical block number
-    pub flags: u64,     // node type flags
+    pub checksum: [u8; 0x20], // for data integrity, csum_type dependent, over everything after it
+    pub fsid: [u8; 16],       // file system identifier
+    pub block_nr: u64,        // logical address of this block
+    pub flags: u64,           // node type flags
     pub chunk_tree_uuid: [u8; 16],
     pub generation: u64, // transaction Id that allocated the block
     pub owner: u64,      //which tree node this blongs to
@@ -48,15 +52,40 @@ pub struct BtrfsItems {
     pub data_size: u32,   // item size
 }
 
-#[allow(dead_code)]
 /// Key Structure
 /// The offset field indicates the byte offset for a particular item in the object
 /// for file extents, offset is the byte offset of the start of the extent in the file
 #[derive(Clone, Debug)]
 pub struct BtrfsKey {
-    object_id: u64, // identifies the object (file, directory, etc) allocated dynamically on creation
-    type_id: u8,    // what kind of item is this (data, extent,directory)
-    offset: u64,    //position within the object
+    pub object_id: u64, // identifies the object (file, directory, etc) allocated dynamically on creation
+    pub type_id: u8,    // what kind of item is this (data, extent,directory)
+    pub offset: u64,    //position within the object
+}
+
+impl BtrfsKey {
+    /// Size of a key as stored on disk (objectid, type, offset)
+    pub const SIZE: usize = 0x11;
+    /// Smallest and largest possible keys, for unbounded range scans
+    pub const MIN: BtrfsKey = BtrfsKey::new(0, 0, 0);
+    pub const MAX: BtrfsKey = BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX);
+
+    pub const fn new(object_id: u64, type_id: u8, offset: u64) -> Self {
+        BtrfsKey {
+            object_id,
+            type_id,
+            offset,
+        }
+    }
+
+    /// Deserializes an on-disk key. The buffer must be at least 0x11 bytes long.
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, Self::SIZE)?;
+        Ok(BtrfsKey {
+            object_id: read_le_u64(buffer, 0x00),
+            type_id: buffer[0x08],
+            offset: read_le_u64(buffer, 0x09),
+        })
+    }
 }
 
 /// Internal Nodes (lvl >0)
@@ -64,8 +93,98 @@ pub struct BtrfsKey {
 #[derive(Clone, Debug)]
 pub struct BtrfsInternalNode {
     pub header: BtrfsHeader,
-    pub keys: Vec<BtrfsKey>,  // used for searching
-    pub block_ptrs: Vec<u64>, // points to child node
+    pub keys: Vec<BtrfsKey>,   // used for searching
+    pub block_ptrs: Vec<u64>,  // points to child node (logical address)
+    pub generations: Vec<u64>, // generation the child was written in
+}
+
+impl BtrfsHeader {
+    /// Size of the header at the start of every tree block
+    pub const SIZE: usize = 0x65;
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, Self::SIZE)?;
+        Ok(BtrfsHeader {
+            checksum: buffer[0x00..0x20].try_into().unwrap(),
+            fsid: buffer[0x20..0x30].try_into().unwrap(),
+            block_nr: read_le_u64(buffer, 0x30),
+            flags: read_le_u64(buffer, 0x38),
+            chunk_tree_uuid: buffer[0x40..0x50].try_into().unwrap(),
+            generation: read_le_u64(buffer, 0x50),
+            owner: read_le_u64(buffer, 0x58),
+            nritems: read_le_u32(buffer, 0x60),
+            level: buffer[0x64],
+        })
+    }
+}
+
+impl BtrfsLeafNode {
+    /// Size of an item header (key, data offset, data size) following the node header
+    pub const ITEM_SIZE: usize = 0x19;
+
+    /// Parses a leaf. Item data offsets are relative to the end of the header, so `data` is
+    /// everything after the header.
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        let header = BtrfsHeader::from_buffer(buffer)?;
+        let nritems = header.nritems as usize;
+        check_len(buffer, BtrfsHeader::SIZE + nritems * Self::ITEM_SIZE)?;
+
+        let mut items = Vec::with_capacity(nritems);
+        for idx in 0..nritems {
+            let item = &buffer[BtrfsHeader::SIZE + idx * Self::ITEM_SIZE..];
+            items.push(BtrfsItems {
+                key: BtrfsKey::from_buffer(item)?,
+                data_offset: read_le_u32(item, 0x11),
+                data_size: read_le_u32(item, 0x15),
+            });
+        }
+        Ok(BtrfsLeafNode {
+            header,
+            items,
+            data: buffer[BtrfsHeader::SIZE..].to_vec(),
+        })
+    }
+
+    /// Returns the payload of the idx-th item, or None if the item points outside the leaf
+    pub fn item_data(&self, idx: usize) -> Option<&[u8]> {
+        let item = self.items.get(idx)?;
+        let start = item.data_offset as usize;
+        let end = start.checked_add(item.data_size as usize)?;
+        self.data.get(start..end)
+    }
+
+    /// Iterates over the (key, payload) pairs stored in this leaf, in key order
+    pub fn iter_items(&self) -> impl Iterator<Item = (&BtrfsKey, &[u8])> {
+        (0..self.items.len())
+            .filter_map(move |idx| Some((&self.items[idx].key, self.item_data(idx)?)))
+    }
+}
+
+impl BtrfsInternalNode {
+    /// Size of a key pointer (key, block pointer, generation) following the node header
+    pub const KEY_PTR_SIZE: usize = 0x21;
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        let header = BtrfsHeader::from_buffer(buffer)?;
+        let nritems = header.nritems as usize;
+        check_len(buffer, BtrfsHeader::SIZE + nritems * Self::KEY_PTR_SIZE)?;
+
+        let mut keys = Vec::with_capacity(nritems);
+        let mut block_ptrs = Vec::with_capacity(nritems);
+        let mut generations = Vec::with_capacity(nritems);
+        for idx in 0..nritems {
+            let key_ptr = &buffer[BtrfsHeader::SIZE + idx * Self::KEY_PTR_SIZE..];
+            keys.push(BtrfsKey::from_buffer(key_ptr)?);
+            block_ptrs.push(read_le_u64(key_ptr, 0x11));
+            generations.push(read_le_u64(key_ptr, 0x19));
+        }
+        Ok(BtrfsInternalNode {
+            header,
+            keys,
+            block_ptrs,
+            generations,
+        })
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -77,6 +196,96 @@ pub enum KeyTypes {
     CHUNK_ITEM,     // block group info
 }
 
+// On-disk values of BtrfsKey::type_id
+pub const BTRFS_INODE_ITEM_KEY: u8 = 1; // stat data of an inode, offset 0
+pub const BTRFS_INODE_REF_KEY: u8 = 12; // name of an inode in its parent, offset is the parent
+pub const BTRFS_XATTR_ITEM_KEY: u8 = 24; // extended attributes, offset is the name hash
+pub const BTRFS_VERITY_DESC_ITEM_KEY: u8 = 36; // fs-verity descriptor of an inode
+pub const BTRFS_VERITY_MERKLE_ITEM_KEY: u8 = 37; // fs-verity merkle tree of an inode
+pub const BTRFS_ORPHAN_ITEM_KEY: u8 = 48; // inode or root pending deletion
+pub const BTRFS_DIR_ITEM_KEY: u8 = 84; // directory entries, offset is the name hash
+pub const BTRFS_DIR_INDEX_KEY: u8 = 96; // directory entries, offset is the entry's sequence number
+pub const BTRFS_EXTENT_DATA_KEY: u8 = 108; // file extent, offset is the position in the file
+pub const BTRFS_EXTENT_CSUM_KEY: u8 = 128; // data checksums of a logical range
+pub const BTRFS_ROOT_ITEM_KEY: u8 = 132; // root of a tree, in the tree of tree roots
+pub const BTRFS_FREE_SPACE_INFO_KEY: u8 = 198; // per block group summary in the free space tree
+pub const BTRFS_FREE_SPACE_EXTENT_KEY: u8 = 199; // free range, described by the key alone
+pub const BTRFS_FREE_SPACE_BITMAP_KEY: u8 = 200; // free ranges as a bitmap of sectors
+pub const BTRFS_DEV_ITEM_KEY: u8 = 216; // a device of the filesystem, in the chunk tree
+pub const BTRFS_CHUNK_ITEM_KEY: u8 = 228; // logical to physical mapping of a chunk
+pub const BTRFS_QGROUP_STATUS_KEY: u8 = 240; // quota tree global state
+pub const BTRFS_QGROUP_INFO_KEY: u8 = 242; // usage of one qgroup
+pub const BTRFS_QGROUP_LIMIT_KEY: u8 = 244; // limits of one qgroup
+pub const BTRFS_QGROUP_RELATION_KEY: u8 = 246; // child/parent qgroup membership
+pub const BTRFS_TEMPORARY_ITEM_KEY: u8 = 248; // not persistent across mounts, e.g. balance status
+pub const BTRFS_PERSISTENT_ITEM_KEY: u8 = 249; // persistent state, e.g. device stats
+pub const BTRFS_UUID_KEY_SUBVOL: u8 = 251; // subvolume uuid -> subvolume id
+pub const BTRFS_UUID_KEY_RECEIVED_SUBVOL: u8 = 252; // received uuid -> subvolume ids
+
+// Object ids of the well known trees (root tree ROOT_ITEM keys)
+pub const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+pub const BTRFS_CHUNK_TREE_OBJECTID: u64 = 3;
+pub const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+pub const BTRFS_CSUM_TREE_OBJECTID: u64 = 7;
+pub const BTRFS_QUOTA_TREE_OBJECTID: u64 = 8;
+pub const BTRFS_UUID_TREE_OBJECTID: u64 = 9;
+pub const BTRFS_FREE_SPACE_TREE_OBJECTID: u64 = 10;
+
+// Object ids used inside trees
+pub const BTRFS_DEV_ITEMS_OBJECTID: u64 = 1; // DEV_ITEM, offset is the devid
+pub const BTRFS_FIRST_CHUNK_TREE_OBJECTID: u64 = 256; // CHUNK_ITEM, offset is the logical start
+pub const BTRFS_FIRST_FREE_OBJECTID: u64 = 256; // first inode number of an fs tree, its top directory
+pub const BTRFS_DEV_STATS_OBJECTID: u64 = 0; // PERSISTENT_ITEM dev stats, offset is the devid
+pub const BTRFS_BALANCE_OBJECTID: u64 = -4i64 as u64; // TEMPORARY_ITEM balance status
+pub const BTRFS_ORPHAN_OBJECTID: u64 = -5i64 as u64; // ORPHAN_ITEM, offset is the orphan
+pub const BTRFS_EXTENT_CSUM_OBJECTID: u64 = -10i64 as u64; // all EXTENT_CSUM items of the csum tree
+
+/// On-disk timestamp
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BtrfsTimespec {
+    pub sec: u64,  // seconds since the epoch
+    pub nsec: u32, // nanoseconds
+}
+
+impl BtrfsTimespec {
+    /// Size of a timestamp as stored on disk
+    pub const SIZE: usize = 0x0c;
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, Self::SIZE)?;
+        Ok(BtrfsTimespec {
+            sec: read_le_u64(buffer, 0x00),
+            nsec: read_le_u32(buffer, 0x08),
+        })
+    }
+}
+
+/// Reads a little-endian u64 at `offset`. Callers check the buffer length first.
+pub(crate) fn read_le_u64(buffer: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads a little-endian u32 at `offset`. Callers check the buffer length first.
+pub(crate) fn read_le_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a little-endian u16 at `offset`. Callers check the buffer length first.
+pub(crate) fn read_le_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buffer[offset..offset + 2].try_into().unwrap())
+}
+
+/// Fails with InvalidData when an item payload is shorter than its on-disk structure
+pub(crate) fn check_len(buffer: &[u8], len: usize) -> Result<(), std::io::Error> {
+    if buffer.len() < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("item too short: {} bytes, expected {}", buffer.len(), len),
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct BtrfsSuperblock {
     // Magic number for BTRFS_MAGIC: _BHRfS_M (0x4D5F53665248425F)
@@ -217,9 +426,60 @@ pub struct BtrfsChunkStripe {
     pub dev_uuid: [u8; 16], // UUID of the device
 }
 
+impl BtrfsChunkItem {
+    /// Size of the chunk item without its stripes
+    pub const SIZE: usize = 0x30;
+    /// Size of each stripe following the chunk item
+    pub const STRIPE_SIZE: usize = 0x20;
+
+    /// Deserializes a chunk item and its stripes, from the chunk tree or the sys_chunk_array.
+    /// Use on_disk_size() to find where the next one starts in the sys_chunk_array.
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, Self::SIZE)?;
+        let num_stripes = read_le_u16(buffer, 0x2c);
+        check_len(
+            buffer,
+            Self::SIZE + num_stripes as usize * Self::STRIPE_SIZE,
+        )?;
+
+        let stripes = (0..num_stripes as usize)
+            .map(|idx| {
+                let stripe = &buffer[Self::SIZE + idx * Self::STRIPE_SIZE..];
+                BtrfsChunkStripe {
+                    devid: read_le_u64(stripe, 0x00),
+                    offset: read_le_u64(stripe, 0x08),
+                    dev_uuid: stripe[0x10..0x20].try_into().unwrap(),
+                }
+            })
+            .collect();
+
+        Ok(BtrfsChunkItem {
+            size: read_le_u64(buffer, 0x00),
+            owner: read_le_u64(buffer, 0x08),
+            stripe_len: read_le_u64(buffer, 0x10),
+            type_: read_le_u64(buffer, 0x18),
+            io_align: read_le_u32(buffer, 0x20),
+            io_width: read_le_u32(buffer, 0x24),
+            sector_size: read_le_u32(buffer, 0x28),
+            num_stripes,
+            sub_stripes: read_le_u16(buffer, 0x2e),
+            stripes,
+        })
+    }
+
+    pub fn on_disk_size(&self) -> usize {
+        Self::SIZE + self.num_stripes as usize * Self::STRIPE_SIZE
+    }
+}
+
 impl BtrfsSuperblock {
     const MAGIC: &'static [u8; 8] = b"_BHRfS_M";
 
+    /// Checks only the magic number, enough to tell whether a buffer holds a btrfs superblock
+    pub fn has_magic(&self) -> bool {
+        self.magic == u64::from_le_bytes(*Self::MAGIC)
+    }
+
     pub fn from_buffer(buffer: &[u8]) -> Result<Self, &'static str> {
         if buffer.len() < 0x1000 {
             return Err("Buffer too small for superblock");
@@ -233,10 +493,11 @@ impl BtrfsSuperblock {
         let read_u16 = |slice: &[u8]| -> u16 { u16::from_le_bytes(slice.try_into().unwrap()) };
 
         // Read dev_item first since we'll need it for the struct initialization
-        let mut dev_item = BtrfsDevItem::default();
-        BtrfsDevItem::read_from_buff(&mut dev_item, &buffer[0xc9..0x12b]).unwrap();
+        let dev_item = BtrfsDevItem::default()
+            .read_from_buff(&buffer[0xc9..0x12b])
+            .unwrap();
 
-        let mut superblock = Self {
+        let superblock = Self {
             checksum: buffer[0x00..0x20].try_into().unwrap(),
             fsid: buffer[0x20..0x30].try_into().unwrap(),
             bytenr: read_u64(&buffer[0x30..0x38]),
@@ -284,7 +545,7 @@ impl BtrfsSuperblock {
     pub fn verify(&self) -> bool {
         let read_u64 = |slice: &[u8]| -> u64 { u64::from_le_bytes(slice.try_into().unwrap()) };
 
-        if &self.magic != &read_u64(Self::MAGIC) {
+        if self.magic != read_u64(Self::MAGIC) {
             return false;
         }
 
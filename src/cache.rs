@@ -0,0 +1,188 @@
+// ** Tree block cache
+// Every lookup starts at the root of its tree, so the upper levels of the root, extent, csum and
+// fs trees are read over and over. NodeCache keeps parsed nodes in memory up to a byte budget
+// and evicts the least recently used ones first.
+// Entries are keyed by (logical address, generation): a block that is rewritten by a later
+// transaction gets a new generation, so a stale node is never returned for it.
+// One cache is meant to be shared (through an Arc) by all the BTrees of a filesystem.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::btrees::Node;
+
+/// Default budget of a NodeCache, in bytes
+pub const DEFAULT_NODE_CACHE_SIZE: usize = 64 << 20; // 64 MB
+
+/// Counters since the cache was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize, // nodes currently cached
+    pub bytes: usize,   // size of the nodes currently cached
+}
+
+impl CacheStats {
+    /// Fraction of lookups that were served from the cache
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+type CacheKey = (u64, u64); // (logical, generation)
+
+struct CacheEntry {
+    node: Arc<Node>,
+    size: usize,
+    last_used: u64, // key in CacheInner::lru
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    lru: BTreeMap<u64, CacheKey>, // least recently used first
+    tick: u64,
+    stats: CacheStats,
+}
+
+pub struct NodeCache {
+    budget: usize, // bytes
+    inner: Mutex<CacheInner>,
+}
+
+impl Default for NodeCache {
+    fn default() -> Self {
+        NodeCache::new(DEFAULT_NODE_CACHE_SIZE)
+    }
+}
+
+impl NodeCache {
+    /// Creates a cache holding at most `budget` bytes of nodes. A budget of 0 disables caching.
+    pub fn new(budget: usize) -> Self {
+        NodeCache {
+            budget,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Returns the cached node written at `logical` in transaction `generation`
+    pub fn get(&self, logical: u64, generation: u64) -> Option<Arc<Node>> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        inner.tick += 1;
+        let Some(entry) = inner.entries.get_mut(&(logical, generation)) else {
+            inner.stats.misses += 1;
+            return None;
+        };
+        inner.lru.remove(&entry.last_used);
+        entry.last_used = inner.tick;
+        inner.lru.insert(inner.tick, (logical, generation));
+        inner.stats.hits += 1;
+        Some(entry.node.clone())
+    }
+
+    /// Adds a node of `size` bytes, evicting the least recently used nodes to stay in budget
+    pub fn insert(&self, logical: u64, generation: u64, node: Arc<Node>, size: usize) {
+        if size > self.budget {
+            return;
+        }
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+        inner.tick += 1;
+        let key = (logical, generation);
+        let entry = CacheEntry {
+            node,
+            size,
+            last_used: inner.tick,
+        };
+        if let Some(old) = inner.entries.insert(key, entry) {
+            inner.lru.remove(&old.last_used);
+            inner.stats.bytes -= old.size;
+        }
+        inner.lru.insert(inner.tick, key);
+        inner.stats.bytes += size;
+
+        while inner.stats.bytes > self.budget {
+            let Some((_, oldest)) = inner.lru.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.stats.bytes -= evicted.size;
+                inner.stats.evictions += 1;
+            }
+        }
+        inner.stats.entries = inner.entries.len();
+    }
+
+    /// Drops every cached node, keeping the counters
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.lru.clear();
+        inner.stats.entries = 0;
+        inner.stats.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheInner> {
+        // the cache is only ever left inconsistent by a panic inside these methods, which don't
+        // panic, so a poisoned lock is still usable
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::BtrfsLeafNode;
+    use crate::test_image::leaf;
+
+    fn node(logical: u64) -> Arc<Node> {
+        let leaf = BtrfsLeafNode::from_buffer(&leaf(logical, 5, &[])).unwrap();
+        Arc::new(Node::Leaf(leaf))
+    }
+
+    #[test]
+    fn evicts_least_recently_used_within_budget() {
+        let cache = NodeCache::new(300);
+        cache.insert(0x1000, 1, node(0x1000), 100);
+        cache.insert(0x2000, 1, node(0x2000), 100);
+        cache.insert(0x3000, 1, node(0x3000), 100);
+        // touch the oldest so the second one becomes the eviction candidate
+        assert!(cache.get(0x1000, 1).is_some());
+        cache.insert(0x4000, 1, node(0x4000), 100);
+
+        assert!(cache.get(0x2000, 1).is_none());
+        assert!(cache.get(0x1000, 1).is_some());
+        assert!(cache.get(0x4000, 1).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (3, 300, 1));
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+    }
+
+    #[test]
+    fn generation_is_part_of_the_key() {
+        let cache = NodeCache::new(1000);
+        cache.insert(0x1000, 1, node(0x1000), 100);
+        assert!(cache.get(0x1000, 2).is_none());
+        assert!(cache.get(0x1000, 1).is_some());
+
+        // nodes bigger than the whole budget are not cached at all
+        cache.insert(0x2000, 1, node(0x2000), 2000);
+        assert!(cache.get(0x2000, 1).is_none());
+        assert_eq!(cache.stats().bytes, 100);
+    }
+}
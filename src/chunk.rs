@@ -0,0 +1,399 @@
+// ** Chunk tree
+// Tree blocks and file extents are addressed by logical addresses. The logical address space is
+// cut into chunks, and each chunk is backed by one or more stripes on the devices, as described
+// by CHUNK_ITEMs in the chunk tree keyed by
+// |FIRST_CHUNK_TREE_OBJECTID (256)| CHUNK_ITEM| logical start of the chunk|
+// The chunk tree itself lives in SYSTEM chunks, so the superblock carries a copy of the SYSTEM
+// chunk items in sys_chunk_array to bootstrap the translation.
+//
+// SINGLE, DUP and the RAID1 profiles store the whole chunk at the start of each stripe. RAID0
+// and RAID10 cut it into stripe_len pieces dealt round-robin over the stripes (over groups of
+// sub_stripes mirrored stripes for RAID10), so a range can only be read contiguously up to the
+// end of its stripe.
+// RAID5/6 cut it into rows of one stripe_len piece per stripe: the data pieces of the row
+// followed by P (and Q), shifted by one stripe per row so that parity rotates over the devices.
+use std::collections::BTreeMap;
+
+use crate::btrfs::{BtrfsChunkItem, BtrfsKey, BtrfsSuperblock, BTRFS_CHUNK_ITEM_KEY};
+
+// BtrfsChunkItem::type_, what the chunk holds and how it is replicated
+pub const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+pub const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+pub const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
+
+/// Profiles with parity, see raid56
+const BTRFS_BLOCK_GROUP_PARITY: u64 = BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6;
+
+/// Where a logical address lives on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalAddress {
+    pub devid: u64,
+    pub offset: u64,
+}
+
+/// A piece of a logical range stored contiguously, with every copy of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MappedRange {
+    pub logical: u64,
+    pub len: u64,
+    pub copies: Vec<PhysicalAddress>, // mirror 1 first
+}
+
+/// The pieces of a RAID5/6 row at the same offset as a logical address, everything needed to
+/// rebuild it (see raid56::rebuild)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParityRow {
+    pub index: usize,               // the data piece holding the address
+    pub data: Vec<PhysicalAddress>, // data pieces in logical order
+    pub p: PhysicalAddress,
+    pub q: Option<PhysicalAddress>, // RAID6 only
+}
+
+impl ParityRow {
+    /// Data pieces then parity, the order raid56::rebuild expects
+    pub fn members(&self) -> Vec<PhysicalAddress> {
+        let mut members = self.data.clone();
+        members.push(self.p);
+        members.extend(self.q);
+        members
+    }
+}
+
+/// Logical to physical translation, built from the sys_chunk_array and the chunk tree
+#[derive(Debug, Clone, Default)]
+pub struct ChunkMap {
+    chunks: BTreeMap<u64, BtrfsChunkItem>, // keyed by logical start
+}
+
+impl ChunkMap {
+    pub fn new() -> Self {
+        ChunkMap::default()
+    }
+
+    /// Builds the map of the SYSTEM chunks from the superblock, enough to read the chunk tree
+    pub fn from_superblock(superblock: &BtrfsSuperblock) -> Result<Self, std::io::Error> {
+        let array_size = superblock.sys_chunk_array_size as usize;
+        let array = superblock
+            .sys_chunk_array
+            .get(..array_size)
+            .ok_or_else(|| invalid_data("sys_chunk_array_size is larger than the array"))?;
+
+        let mut map = ChunkMap::new();
+        let mut pos = 0;
+        while pos < array.len() {
+            let key = BtrfsKey::from_buffer(&array[pos..])?;
+            pos += BtrfsKey::SIZE;
+            if key.type_id != BTRFS_CHUNK_ITEM_KEY {
+                return Err(invalid_data("unexpected item in sys_chunk_array"));
+            }
+            let chunk = BtrfsChunkItem::from_buffer(&array[pos..])?;
+            pos += chunk.on_disk_size();
+            map.insert(key.offset, chunk)?;
+        }
+        Ok(map)
+    }
+
+    /// Adds the chunk starting at `logical`, rejecting chunks that run past the end of the
+    /// address space
+    pub fn insert(&mut self, logical: u64, chunk: BtrfsChunkItem) -> Result<(), std::io::Error> {
+        if logical.checked_add(chunk.size).is_none() {
+            return Err(invalid_data(&format!(
+                "chunk at {} of {} bytes overflows the logical address space",
+                logical, chunk.size
+            )));
+        }
+        self.chunks.insert(logical, chunk);
+        Ok(())
+    }
+
+    /// Adds the CHUNK_ITEMs among (key, payload) pairs of the chunk tree
+    pub fn insert_items<'a, I>(&mut self, items: I) -> Result<(), std::io::Error>
+    where
+        I: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+    {
+        for (key, data) in items {
+            if key.type_id == BTRFS_CHUNK_ITEM_KEY {
+                self.insert(key.offset, BtrfsChunkItem::from_buffer(data)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the chunk containing `logical`, returning its logical start with it
+    pub fn lookup(&self, logical: u64) -> Option<(u64, &BtrfsChunkItem)> {
+        let (&start, chunk) = self.chunks.range(..=logical).next_back()?;
+        let end = start.checked_add(chunk.size)?;
+        (logical < end).then_some((start, chunk))
+    }
+
+    /// Iterates over (logical start, chunk) in address order
+    pub fn chunks(&self) -> impl Iterator<Item = (u64, &BtrfsChunkItem)> {
+        self.chunks.iter().map(|(&start, chunk)| (start, chunk))
+    }
+
+    /// Translates a logical address to its first copy on disk
+    pub fn map(&self, logical: u64) -> Result<PhysicalAddress, std::io::Error> {
+        Ok(self.map_all(logical)?.remove(0))
+    }
+
+    /// Translates a logical address to every copy of it: one per stripe for DUP and the RAID1
+    /// profiles (mirror 1 is the first stripe), one per sub-stripe for RAID10, a single one for
+    /// SINGLE and RAID0
+    pub fn map_all(&self, logical: u64) -> Result<Vec<PhysicalAddress>, std::io::Error> {
+        Ok(self.map_range(logical, 1)?.remove(0).copies)
+    }
+
+    /// The RAID5/6 row of a logical address, None if its chunk has no parity
+    pub fn parity_row(&self, logical: u64) -> Result<Option<ParityRow>, std::io::Error> {
+        let (start, chunk) = self.lookup(logical).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("logical address {} is not in any chunk", logical),
+            )
+        })?;
+        if chunk.type_ & BTRFS_BLOCK_GROUP_PARITY == 0 {
+            return Ok(None);
+        }
+        Ok(Some(parity_row_in_chunk(start, chunk, logical - start)?.0))
+    }
+
+    /// Splits `len` bytes at `logical` into contiguous pieces, at chunk and stripe boundaries,
+    /// in address order. Each piece can be read with a single I/O from any of its copies.
+    pub fn map_range(&self, logical: u64, len: u64) -> Result<Vec<MappedRange>, std::io::Error> {
+        let end = logical
+            .checked_add(len)
+            .ok_or_else(|| invalid_data("logical range overflows"))?;
+        let mut pieces = Vec::new();
+        let mut pos = logical;
+        while pos < end {
+            let (start, chunk) = self.lookup(pos).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("logical address {} is not in any chunk", pos),
+                )
+            })?;
+            let (copies, contiguous) = map_in_chunk(start, chunk, pos - start)?;
+            let piece_len = contiguous.min(end - pos);
+            pieces.push(MappedRange {
+                logical: pos,
+                len: piece_len,
+                copies,
+            });
+            pos += piece_len;
+        }
+        Ok(pieces)
+    }
+}
+
+/// Copies of the byte at `offset` in the chunk, and how many bytes from there are contiguous
+/// on each of them
+fn map_in_chunk(
+    start: u64,
+    chunk: &BtrfsChunkItem,
+    offset: u64,
+) -> Result<(Vec<PhysicalAddress>, u64), std::io::Error> {
+    if chunk.type_ & BTRFS_BLOCK_GROUP_PARITY != 0 {
+        let (row, contiguous) = parity_row_in_chunk(start, chunk, offset)?;
+        return Ok((vec![row.data[row.index]], contiguous));
+    }
+    if chunk.stripes.is_empty() {
+        return Err(invalid_data("chunk without stripes"));
+    }
+    let copy = |idx: usize, stripe_offset: u64| PhysicalAddress {
+        devid: chunk.stripes[idx].devid,
+        offset: chunk.stripes[idx].offset + stripe_offset,
+    };
+
+    if chunk.type_ & (BTRFS_BLOCK_GROUP_RAID0 | BTRFS_BLOCK_GROUP_RAID10) == 0 {
+        let copies = (0..chunk.stripes.len())
+            .map(|idx| copy(idx, offset))
+            .collect();
+        return Ok((copies, chunk.size - offset));
+    }
+
+    let sub_stripes = if chunk.type_ & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+        chunk.sub_stripes.max(1) as usize
+    } else {
+        1
+    };
+    let groups = chunk.stripes.len() / sub_stripes; // stripes holding different data
+    if chunk.stripe_len == 0 || groups == 0 || !chunk.stripes.len().is_multiple_of(sub_stripes) {
+        return Err(invalid_data(&format!(
+            "chunk {} has an invalid layout: {} stripes of {} bytes, {} sub-stripes",
+            start,
+            chunk.stripes.len(),
+            chunk.stripe_len,
+            chunk.sub_stripes
+        )));
+    }
+    let stripe_nr = offset / chunk.stripe_len; // counting across all the stripes
+    let in_stripe = offset % chunk.stripe_len;
+    let first = (stripe_nr % groups as u64) as usize * sub_stripes;
+    let stripe_offset = (stripe_nr / groups as u64) * chunk.stripe_len + in_stripe;
+    let copies = (first..first + sub_stripes)
+        .map(|idx| copy(idx, stripe_offset))
+        .collect();
+    let contiguous = (chunk.stripe_len - in_stripe).min(chunk.size - offset);
+    Ok((copies, contiguous))
+}
+
+/// The row of the byte at `offset` in a RAID5/6 chunk, and how many bytes from there are
+/// contiguous in each of its pieces
+fn parity_row_in_chunk(
+    start: u64,
+    chunk: &BtrfsChunkItem,
+    offset: u64,
+) -> Result<(ParityRow, u64), std::io::Error> {
+    let nr_parity = if chunk.type_ & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+        2
+    } else {
+        1
+    };
+    let num_stripes = chunk.stripes.len();
+    if chunk.stripe_len == 0 || num_stripes <= nr_parity {
+        return Err(invalid_data(&format!(
+            "chunk {} has an invalid layout: {} stripes of {} bytes with {} parity",
+            start, num_stripes, chunk.stripe_len, nr_parity
+        )));
+    }
+    let nr_data = num_stripes - nr_parity;
+    let stripe_nr = offset / chunk.stripe_len; // counting data pieces only
+    let in_stripe = offset % chunk.stripe_len;
+    let row = stripe_nr / nr_data as u64;
+    let stripe_offset = row * chunk.stripe_len + in_stripe;
+    // piece i of the row (data, then P, then Q) is on stripe (row + i) % num_stripes
+    let member = |idx: usize| {
+        let stripe = &chunk.stripes[(row as usize + idx) % num_stripes];
+        PhysicalAddress {
+            devid: stripe.devid,
+            offset: stripe.offset + stripe_offset,
+        }
+    };
+    let parity_row = ParityRow {
+        index: (stripe_nr % nr_data as u64) as usize,
+        data: (0..nr_data).map(member).collect(),
+        p: member(nr_data),
+        q: (nr_parity == 2).then(|| member(nr_data + 1)),
+    };
+    let contiguous = (chunk.stripe_len - in_stripe).min(chunk.size - offset);
+    Ok((parity_row, contiguous))
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::chunk_item;
+
+    fn map_of(type_: u64, stripes: &[(u64, u64)]) -> ChunkMap {
+        let mut map = ChunkMap::new();
+        let chunk = BtrfsChunkItem::from_buffer(&chunk_item(0x60_0000, type_, stripes)).unwrap();
+        map.insert(0x100_0000, chunk).unwrap();
+        map
+    }
+
+    fn at(devid: u64, offset: u64) -> PhysicalAddress {
+        PhysicalAddress { devid, offset }
+    }
+
+    #[test]
+    fn overflowing_chunks_are_rejected() {
+        let chunk = BtrfsChunkItem::from_buffer(&chunk_item(0x60_0000, 0, &[(1, 0)])).unwrap();
+        let mut map = ChunkMap::new();
+        let error = map.insert(u64::MAX - 0x1000, chunk.clone()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(map.lookup(u64::MAX).is_none());
+
+        map.insert(u64::MAX - 0x60_0000, chunk).unwrap();
+        assert!(map.lookup(u64::MAX - 1).is_some());
+        assert!(map.lookup(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn raid0_deals_stripes_round_robin() {
+        // stripe_len is 64KiB
+        let map = map_of(
+            BTRFS_BLOCK_GROUP_RAID0,
+            &[(1, 0x10_0000), (2, 0x20_0000), (3, 0)],
+        );
+        assert_eq!(map.map_all(0x100_0000 + 0x10).unwrap(), [at(1, 0x10_0010)]);
+        assert_eq!(map.map_all(0x101_0000).unwrap(), [at(2, 0x20_0000)]);
+        assert_eq!(map.map_all(0x103_0004).unwrap(), [at(1, 0x11_0004)]);
+        assert_eq!(map.map_all(0x105_ffff).unwrap(), [at(3, 0x1_ffff)]);
+
+        let pieces = map.map_range(0x100_f000, 0x1_2000).unwrap();
+        let layout: Vec<_> = pieces
+            .iter()
+            .map(|piece| (piece.logical, piece.len, piece.copies.clone()))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (0x100_f000, 0x1000, vec![at(1, 0x10_f000)]),
+                (0x101_0000, 0x1_0000, vec![at(2, 0x20_0000)]),
+                (0x102_0000, 0x1000, vec![at(3, 0)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn raid10_mirrors_within_sub_stripes() {
+        let stripes = [(1, 0), (2, 0), (3, 0x1000), (4, 0x1000)];
+        let map = map_of(BTRFS_BLOCK_GROUP_RAID10, &stripes);
+        assert_eq!(map.map_all(0x100_0008).unwrap(), [at(1, 8), at(2, 8)]);
+        assert_eq!(
+            map.map_all(0x101_0008).unwrap(),
+            [at(3, 0x1008), at(4, 0x1008)]
+        );
+        assert_eq!(
+            map.map_all(0x102_0000).unwrap(),
+            [at(1, 0x1_0000), at(2, 0x1_0000)]
+        );
+        assert_eq!(map.map_range(0x100_0000, 0x3_0000).unwrap().len(), 3);
+        assert!(map.map_range(0x160_0000 - 1, 2).is_err());
+    }
+
+    #[test]
+    fn raid5_and_raid6_rotate_parity() {
+        let stripes = [(1, 0), (2, 0), (3, 0)];
+        let map = map_of(BTRFS_BLOCK_GROUP_RAID5, &stripes);
+        // row 0: D0 D1 P, row 1: P D0 D1 (as stripes 1 2 3)
+        assert_eq!(map.map_all(0x100_0010).unwrap(), [at(1, 0x10)]);
+        assert_eq!(map.map_all(0x101_0010).unwrap(), [at(2, 0x10)]);
+        assert_eq!(map.map_all(0x102_0010).unwrap(), [at(2, 0x1_0010)]);
+        let row = map.parity_row(0x103_0010).unwrap().unwrap();
+        assert_eq!(row.index, 1);
+        assert_eq!(row.data, [at(2, 0x1_0010), at(3, 0x1_0010)]);
+        assert_eq!((row.p, row.q), (at(1, 0x1_0010), None));
+
+        let stripes = [(1, 0), (2, 0), (3, 0), (4, 0)];
+        let map = map_of(BTRFS_BLOCK_GROUP_RAID6, &stripes);
+        let row = map.parity_row(0x104_0000).unwrap().unwrap();
+        assert_eq!(
+            row.members(),
+            [
+                at(3, 0x2_0000),
+                at(4, 0x2_0000),
+                at(1, 0x2_0000),
+                at(2, 0x2_0000)
+            ]
+        );
+        assert_eq!(
+            map_of(BTRFS_BLOCK_GROUP_RAID1, &stripes)
+                .parity_row(0x100_0000)
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,359 @@
+// ** Compressed extents
+// A compressed extent holds disk_num_bytes of compressed data that expand to ram_bytes, and
+// the file extents pointing into it address the decompressed bytes. The whole extent has to be
+// decompressed to read any part of it. Inline extents are compressed the same way.
+// zlib and zstd extents are one plain zlib stream or zstd frame, padded to the sector size.
+// LZO extents are cut into segments of at most one sector of decompressed data:
+// |total compressed length: u32| segment length: u32| LZO1X segment| segment length: u32| ...
+// where a segment length is never split across two sectors: when less than 4 bytes are left
+// in a sector, the next length starts at the following sector and the gap is padding.
+use std::io::Read;
+
+// BtrfsFileExtentItem::compression
+pub const BTRFS_COMPRESS_NONE: u8 = 0;
+pub const BTRFS_COMPRESS_ZLIB: u8 = 1;
+pub const BTRFS_COMPRESS_LZO: u8 = 2;
+pub const BTRFS_COMPRESS_ZSTD: u8 = 3;
+
+/// Largest compressed extent the kernel writes (disk_num_bytes)
+pub const BTRFS_MAX_COMPRESSED: usize = 128 * 1024;
+/// Largest size a compressed extent expands to (ram_bytes)
+pub const BTRFS_MAX_UNCOMPRESSED: usize = 128 * 1024;
+
+/// Size of the LZO length fields
+const LZO_LEN: usize = 4;
+
+fn invalid(what: &str, why: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("bad {} data: {}", what, why),
+    )
+}
+
+/// Decompresses an extent compressed with `compression` (BTRFS_COMPRESS_*) into its ram_bytes
+/// bytes. Output past ram_bytes is dropped, a short output is zero filled as the kernel does.
+/// Extents larger than the kernel ever writes are rejected before anything is allocated.
+pub fn decompress(
+    compression: u8,
+    data: &[u8],
+    ram_bytes: usize,
+    sectorsize: usize,
+) -> Result<Vec<u8>, std::io::Error> {
+    check_extent_sizes(data.len() as u64, ram_bytes as u64)?;
+    if sectorsize == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "sectorsize is 0",
+        ));
+    }
+    let mut out = Vec::with_capacity(ram_bytes);
+    match compression {
+        BTRFS_COMPRESS_NONE => out.extend_from_slice(&data[..data.len().min(ram_bytes)]),
+        BTRFS_COMPRESS_ZLIB => {
+            flate2::read::ZlibDecoder::new(data)
+                .take(ram_bytes as u64)
+                .read_to_end(&mut out)
+                .map_err(|e| invalid("zlib", e))?;
+        }
+        BTRFS_COMPRESS_ZSTD => {
+            let decoder =
+                ruzstd::decoding::StreamingDecoder::new(data).map_err(|e| invalid("zstd", e))?;
+            decoder
+                .take(ram_bytes as u64)
+                .read_to_end(&mut out)
+                .map_err(|e| invalid("zstd", e))?;
+        }
+        BTRFS_COMPRESS_LZO => lzo_segments(data, ram_bytes, sectorsize, &mut out)?,
+        other => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("unknown compression type {}", other),
+            ))
+        }
+    }
+    out.resize(ram_bytes, 0);
+    Ok(out)
+}
+
+/// Checks the on-disk and decompressed sizes of a compressed extent against the kernel limits
+pub fn check_extent_sizes(disk_num_bytes: u64, ram_bytes: u64) -> Result<(), std::io::Error> {
+    if disk_num_bytes > BTRFS_MAX_COMPRESSED as u64 || ram_bytes > BTRFS_MAX_UNCOMPRESSED as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "compressed extent of {} bytes expanding to {}, the limit is {} and {}",
+                disk_num_bytes, ram_bytes, BTRFS_MAX_COMPRESSED, BTRFS_MAX_UNCOMPRESSED
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn read_len(data: &[u8], at: usize) -> Result<usize, std::io::Error> {
+    data.get(at..at + LZO_LEN)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        .ok_or_else(|| invalid("lzo", format!("length at {} past the end", at)))
+}
+
+/// Walks the segments of a btrfs LZO extent
+fn lzo_segments(
+    data: &[u8],
+    ram_bytes: usize,
+    sectorsize: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), std::io::Error> {
+    let total = read_len(data, 0)?;
+    if total > data.len() || total < LZO_LEN {
+        return Err(invalid(
+            "lzo",
+            format!("{} compressed bytes in a {} byte extent", total, data.len()),
+        ));
+    }
+    let mut pos = LZO_LEN;
+    while pos < total && out.len() < ram_bytes {
+        let left_in_sector = sectorsize - pos % sectorsize;
+        if left_in_sector < LZO_LEN {
+            pos += left_in_sector;
+            continue;
+        }
+        let len = read_len(data, pos)?;
+        pos += LZO_LEN;
+        let segment = data
+            .get(pos..pos + len)
+            .filter(|_| pos + len <= total)
+            .ok_or_else(|| invalid("lzo", format!("segment at {} overruns the extent", pos)))?;
+        lzo1x_decompress(segment, out, sectorsize)?;
+        pos += len;
+    }
+    Ok(())
+}
+
+/// Decompresses one LZO1X stream (lzo1x_decompress_safe) of at most `max` bytes, appending it
+/// to `out`
+fn lzo1x_decompress(src: &[u8], out: &mut Vec<u8>, max: usize) -> Result<(), std::io::Error> {
+    let base = out.len();
+    let limit = base + max;
+    let mut ip = 0;
+    let byte = |ip: &mut usize| -> Result<usize, std::io::Error> {
+        let b = *src
+            .get(*ip)
+            .ok_or_else(|| invalid("lzo", "input overrun"))?;
+        *ip += 1;
+        Ok(b as usize)
+    };
+    // a 0 length byte is extended by 255 per following zero byte plus the first non zero one
+    let extend = |ip: &mut usize, base: usize| -> Result<usize, std::io::Error> {
+        let mut len = base;
+        loop {
+            match byte(ip)? {
+                0 => len += 255,
+                b => return Ok(len + b),
+            }
+        }
+    };
+    let literals = |ip: &mut usize, out: &mut Vec<u8>, n: usize| -> Result<(), std::io::Error> {
+        let run = src
+            .get(*ip..*ip + n)
+            .ok_or_else(|| invalid("lzo", "input overrun"))?;
+        if out.len() + n > limit {
+            return Err(invalid("lzo", "output overrun"));
+        }
+        out.extend_from_slice(run);
+        *ip += n;
+        Ok(())
+    };
+    let copy_match = |out: &mut Vec<u8>, distance: usize, len: usize| {
+        if distance == 0 || distance > out.len() - base {
+            return Err(invalid("lzo", format!("match {} bytes back", distance)));
+        }
+        if out.len() + len > limit {
+            return Err(invalid("lzo", "output overrun"));
+        }
+        let from = out.len() - distance;
+        // byte by byte, the match may overlap what it produces
+        for idx in 0..len {
+            out.push(out[from + idx]);
+        }
+        Ok(())
+    };
+
+    // state is the number of literals copied by the last instruction, 4 meaning 4 or more
+    let mut state = 0;
+    if src.first().is_some_and(|&b| b > 17) {
+        let n = byte(&mut ip)? - 17;
+        literals(&mut ip, out, n)?;
+        state = n.min(4);
+    }
+    loop {
+        let t = byte(&mut ip)?;
+        let (distance, len, next) = if t < 16 {
+            if state == 0 {
+                let n = if t == 0 { extend(&mut ip, 15)? } else { t };
+                literals(&mut ip, out, n + 3)?;
+                state = 4;
+                continue;
+            }
+            let low = (t >> 2) + (byte(&mut ip)? << 2);
+            if state == 4 {
+                (low + 1 + 0x800, 3, t & 3)
+            } else {
+                (low + 1, 2, t & 3)
+            }
+        } else if t >= 64 {
+            (
+                ((t >> 2) & 7) + (byte(&mut ip)? << 3) + 1,
+                (t >> 5) + 1,
+                t & 3,
+            )
+        } else if t >= 32 {
+            let len = match t & 31 {
+                0 => extend(&mut ip, 31)?,
+                n => n,
+            } + 2;
+            let tail = byte(&mut ip)? | (byte(&mut ip)? << 8);
+            ((tail >> 2) + 1, len, tail & 3)
+        } else {
+            let len = match t & 7 {
+                0 => extend(&mut ip, 7)?,
+                n => n,
+            } + 2;
+            let tail = byte(&mut ip)? | (byte(&mut ip)? << 8);
+            let distance = ((t & 8) << 11) + (tail >> 2);
+            if distance == 0 {
+                // end of stream
+                return Ok(());
+            }
+            (distance + 0x4000, len, tail & 3)
+        };
+        copy_match(out, distance, len)?;
+        literals(&mut ip, out, next)?;
+        state = next;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// An LZO1X stream made of a single literal run
+    pub(crate) fn lzo_literals(data: &[u8]) -> Vec<u8> {
+        let mut stream = Vec::new();
+        if data.len() <= 238 {
+            stream.push(17 + data.len() as u8);
+        } else {
+            let mut extra = data.len() - 18;
+            stream.push(0);
+            while extra > 255 {
+                stream.push(0);
+                extra -= 255;
+            }
+            stream.push(extra as u8);
+        }
+        stream.extend_from_slice(data);
+        stream.extend_from_slice(&[0x11, 0, 0]);
+        stream
+    }
+
+    /// A btrfs LZO extent of one segment per 4K of `data`
+    pub(crate) fn lzo_extent(data: &[u8]) -> Vec<u8> {
+        let mut extent = vec![0u8; LZO_LEN];
+        for chunk in data.chunks(4096) {
+            let left_in_sector = 4096 - extent.len() % 4096;
+            if left_in_sector < LZO_LEN {
+                extent.resize(extent.len() + left_in_sector, 0);
+            }
+            let segment = lzo_literals(chunk);
+            extent.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+            extent.extend_from_slice(&segment);
+        }
+        let total = extent.len() as u32;
+        extent[..LZO_LEN].copy_from_slice(&total.to_le_bytes());
+        extent
+    }
+
+    pub(crate) fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    pub(crate) fn zstd(data: &[u8]) -> Vec<u8> {
+        ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+    }
+
+    #[test]
+    fn lzo1x_instructions() {
+        // 11 literals, a match of 8 bytes 6 back, 4 literals after a 0 state,
+        // then a 34 byte match 4 back through the extended length of a M3 match
+        let mut stream = vec![17 + 11];
+        stream.extend_from_slice(b"hello world");
+        stream.extend_from_slice(&[(7 << 5) | (5 << 2), 0]);
+        stream.extend_from_slice(&[1]);
+        stream.extend_from_slice(b"xyz!");
+        stream.extend_from_slice(&[32, 1, 3 << 2, 0]);
+        stream.extend_from_slice(&[0x11, 0, 0]);
+
+        let mut expected = b"hello world world wxyz!".to_vec();
+        for _ in 0..34 {
+            expected.push(expected[expected.len() - 4]);
+        }
+        let mut out = Vec::new();
+        lzo1x_decompress(&stream, &mut out, 4096).unwrap();
+        assert_eq!(out, expected);
+
+        assert!(lzo1x_decompress(&stream, &mut Vec::new(), 20).is_err());
+        assert!(lzo1x_decompress(&stream[..20], &mut Vec::new(), 4096).is_err());
+    }
+
+    #[test]
+    fn decompresses_every_type() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 7 + i / 1000) as u8).collect();
+        for (compression, compressed) in [
+            (BTRFS_COMPRESS_ZLIB, zlib(&data)),
+            (BTRFS_COMPRESS_ZSTD, zstd(&data)),
+            (BTRFS_COMPRESS_LZO, lzo_extent(&data)),
+        ] {
+            // sector padding after the stream doesn't matter
+            let mut padded = compressed.clone();
+            padded.resize(padded.len().next_multiple_of(4096), 0);
+            assert_eq!(
+                decompress(compression, &padded, data.len(), 4096).unwrap(),
+                data
+            );
+            let truncated = &compressed[..compressed.len() / 2];
+            assert!(decompress(compression, truncated, data.len(), 4096).is_err());
+        }
+        assert!(decompress(4, &[], 1, 4096).is_err());
+
+        // sizes beyond the kernel limits, or no sector size to walk LZO segments with
+        let lzo = lzo_extent(&data);
+        let err = decompress(BTRFS_COMPRESS_LZO, &lzo, usize::MAX, 4096).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let huge = vec![0u8; BTRFS_MAX_COMPRESSED + 1];
+        assert!(decompress(BTRFS_COMPRESS_ZLIB, &huge, 4096, 4096).is_err());
+        let err = decompress(BTRFS_COMPRESS_LZO, &lzo, data.len(), 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lzo_lengths_skip_the_end_of_a_sector() {
+        // a first segment of 4 + 4 + 4086 bytes leaves 2 bytes in the sector
+        let first = lzo_literals(&[0xaa; 4066]);
+        assert_eq!(first.len(), 4086);
+        let second = lzo_literals(b"tail");
+        let mut extent = vec![0u8; 4];
+        extent.extend_from_slice(&(first.len() as u32).to_le_bytes());
+        extent.extend_from_slice(&first);
+        extent.extend_from_slice(&[0, 0]);
+        extent.extend_from_slice(&(second.len() as u32).to_le_bytes());
+        extent.extend_from_slice(&second);
+        let total = extent.len() as u32;
+        extent[..4].copy_from_slice(&total.to_le_bytes());
+
+        let out = decompress(BTRFS_COMPRESS_LZO, &extent, 4070, 4096).unwrap();
+        assert_eq!(out[..4066], [0xaa; 4066]);
+        assert_eq!(&out[4066..], b"tail");
+    }
+}
@@ -0,0 +1,344 @@
+// ** Checksum tree
+// Data checksums live in the csum tree (objectid 7 in the tree of tree roots) as EXTENT_CSUM items:
+// |EXTENT_CSUM_OBJECTID (-10)| EXTENT_CSUM| logical address of the first sector|
+// The payload is a packed array of checksums, one per `sectorsize` bytes of data, so an item of
+// n * csum_size bytes covers [offset, offset + n * sectorsize). The checksum algorithm and its
+// size come from the superblock's csum_type. Items never overlap and long runs are split over
+// several items, so a range lookup may need more than one.
+use blake2::digest::consts::U32;
+use sha2::Digest;
+
+use crate::btrees::BTree;
+use crate::btrfs::{BtrfsKey, BtrfsSuperblock, BTRFS_EXTENT_CSUM_KEY, BTRFS_EXTENT_CSUM_OBJECTID};
+use crate::storage::BlockStorage;
+
+// BtrfsSuperblock::csum_type
+pub const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+pub const BTRFS_CSUM_TYPE_XXHASH: u16 = 1;
+pub const BTRFS_CSUM_TYPE_SHA256: u16 = 2;
+pub const BTRFS_CSUM_TYPE_BLAKE2: u16 = 3;
+
+/// Checksum algorithm of a filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    Crc32c,   // 4 bytes
+    Xxhash64, // 8 bytes
+    Sha256,   // 32 bytes
+    Blake2b,  // 32 bytes, blake2b-256
+}
+
+impl ChecksumType {
+    pub fn from_raw(csum_type: u16) -> Result<Self, std::io::Error> {
+        match csum_type {
+            BTRFS_CSUM_TYPE_CRC32 => Ok(ChecksumType::Crc32c),
+            BTRFS_CSUM_TYPE_XXHASH => Ok(ChecksumType::Xxhash64),
+            BTRFS_CSUM_TYPE_SHA256 => Ok(ChecksumType::Sha256),
+            BTRFS_CSUM_TYPE_BLAKE2 => Ok(ChecksumType::Blake2b),
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown checksum type {}", other),
+            )),
+        }
+    }
+
+    pub fn from_superblock(superblock: &BtrfsSuperblock) -> Result<Self, std::io::Error> {
+        Self::from_raw(superblock.csum_type)
+    }
+
+    /// Number of bytes a checksum takes in a csum item
+    pub fn size(self) -> usize {
+        match self {
+            ChecksumType::Crc32c => 4,
+            ChecksumType::Xxhash64 => 8,
+            ChecksumType::Sha256 | ChecksumType::Blake2b => 32,
+        }
+    }
+
+    /// Checksum of `data`, size() bytes in the on-disk byte order
+    pub fn compute(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ChecksumType::Crc32c => crc32c::crc32c(data).to_le_bytes().to_vec(),
+            ChecksumType::Xxhash64 => xxhash_rust::xxh64::xxh64(data, 0).to_le_bytes().to_vec(),
+            ChecksumType::Sha256 => sha2::Sha256::digest(data).to_vec(),
+            ChecksumType::Blake2b => blake2::Blake2b::<U32>::digest(data).to_vec(),
+        }
+    }
+
+    /// Compares the checksum of `data` with `expected`, of which only the first size() bytes
+    /// are used (tree block headers reserve 32 bytes whatever the algorithm)
+    pub fn verify(self, data: &[u8], expected: &[u8]) -> bool {
+        expected.get(..self.size()) == Some(&self.compute(data)[..])
+    }
+}
+
+/// An EXTENT_CSUM item split into per-sector checksums
+#[derive(Debug, Clone)]
+pub struct BtrfsExtentCsum {
+    pub start: u64,      // key offset: logical address of the first sector
+    pub sectorsize: u32, // data covered by each checksum
+    pub csum_type: ChecksumType,
+    pub csums: Vec<u8>, // payload, csum_type.size() bytes per sector
+}
+
+impl BtrfsExtentCsum {
+    /// Decodes an EXTENT_CSUM item using the superblock's sectorsize and csum_type
+    pub fn from_item(
+        key: &BtrfsKey,
+        buffer: &[u8],
+        sectorsize: u32,
+        csum_type: ChecksumType,
+    ) -> Result<Self, std::io::Error> {
+        if key.object_id != BTRFS_EXTENT_CSUM_OBJECTID || key.type_id != BTRFS_EXTENT_CSUM_KEY {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "not an EXTENT_CSUM item",
+            ));
+        }
+        if !buffer.len().is_multiple_of(csum_type.size()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "csum item size {} is not a multiple of {}",
+                    buffer.len(),
+                    csum_type.size()
+                ),
+            ));
+        }
+        Ok(BtrfsExtentCsum {
+            start: key.offset,
+            sectorsize,
+            csum_type,
+            csums: buffer.to_vec(),
+        })
+    }
+
+    /// Number of sectors covered by the item
+    pub fn nr_sectors(&self) -> u64 {
+        (self.csums.len() / self.csum_type.size()) as u64
+    }
+
+    /// Logical address just past the last covered sector (saturating for corrupt items)
+    pub fn end(&self) -> u64 {
+        self.start
+            .saturating_add(self.nr_sectors().saturating_mul(self.sectorsize as u64))
+    }
+
+    /// Checksum of the sector starting at `logical`, if the item covers it
+    pub fn sector_csum(&self, logical: u64) -> Option<&[u8]> {
+        if logical < self.start || logical >= self.end() {
+            return None;
+        }
+        let idx = ((logical - self.start) / self.sectorsize as u64) as usize;
+        let size = self.csum_type.size();
+        Some(&self.csums[idx * size..(idx + 1) * size])
+    }
+
+    /// Iterates over (logical address, checksum) of every covered sector, stopping at the end
+    /// of the address space like end()
+    pub fn sectors(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        let start = self.start;
+        let sectorsize = self.sectorsize as u64;
+        self.csums
+            .chunks_exact(self.csum_type.size())
+            .enumerate()
+            .map_while(move |(idx, csum)| {
+                let logical = (idx as u64)
+                    .checked_mul(sectorsize)
+                    .and_then(|offset| start.checked_add(offset))?;
+                Some((logical, csum))
+            })
+    }
+}
+
+/// Widens [logical, logical + len) to sector boundaries, failing on a sectorsize of 0 or a
+/// range past the end of the address space
+fn sector_range(logical: u64, len: u64, sectorsize: u32) -> Result<(u64, u64), std::io::Error> {
+    let invalid = |why: String| std::io::Error::new(std::io::ErrorKind::InvalidData, why);
+    if sectorsize == 0 {
+        return Err(invalid("sectorsize is 0".to_string()));
+    }
+    let sector = sectorsize as u64;
+    let end = logical
+        .checked_add(len)
+        .and_then(|end| end.div_ceil(sector).checked_mul(sector))
+        .ok_or_else(|| invalid(format!("{} bytes at {} overflow", len, logical)))?;
+    Ok((logical - logical % sector, end))
+}
+
+/// Expected checksum of one data sector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectorChecksum {
+    pub logical: u64,          // logical address of the sector
+    pub csum: Option<Vec<u8>>, // None when no checksum is stored (nodatasum data, holes in the tree)
+}
+
+/// Returns the expected checksum of every sector of the logical range [logical, logical + len),
+/// with the range widened to sector boundaries.
+///
+/// `items` are the (key, payload) pairs of the csum tree in key order.
+pub fn data_checksums<'a, I>(
+    items: I,
+    sectorsize: u32,
+    csum_type: ChecksumType,
+    logical: u64,
+    len: u64,
+) -> Result<Vec<SectorChecksum>, std::io::Error>
+where
+    I: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    let (first, end) = sector_range(logical, len, sectorsize)?;
+    let mut sectors: Vec<SectorChecksum> = (first..end)
+        .step_by(sectorsize as usize)
+        .map(|logical| SectorChecksum {
+            logical,
+            csum: None,
+        })
+        .collect();
+
+    for (key, data) in items {
+        if key.object_id != BTRFS_EXTENT_CSUM_OBJECTID || key.type_id != BTRFS_EXTENT_CSUM_KEY {
+            continue;
+        }
+        if key.offset >= end {
+            break;
+        }
+        let item = BtrfsExtentCsum::from_item(key, data, sectorsize, csum_type)?;
+        if item.end() <= first {
+            continue;
+        }
+        let covered = item
+            .sectors()
+            .skip_while(|(logical, _)| *logical < first)
+            .take_while(|(logical, _)| *logical < end);
+        for (logical, csum) in covered {
+            sectors[((logical - first) / sectorsize as u64) as usize].csum = Some(csum.to_vec());
+        }
+    }
+    Ok(sectors)
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// data_checksums of [logical, logical + len) looked up in this tree, the csum tree
+    pub fn data_checksums(
+        &self,
+        logical: u64,
+        len: u64,
+    ) -> Result<Vec<SectorChecksum>, std::io::Error> {
+        let csum_type = ChecksumType::from_superblock(&self.superblock)?;
+        let sectorsize = self.superblock.sectorsize;
+        let (_, end) = sector_range(logical, len, sectorsize)?;
+        // an item starting before the range may still cover it, but no further back than
+        // the sectors of a leaf full of checksums
+        let reach =
+            (self.superblock.nodesize as usize / csum_type.size()) as u64 * sectorsize as u64;
+        let min = BtrfsKey::new(
+            BTRFS_EXTENT_CSUM_OBJECTID,
+            BTRFS_EXTENT_CSUM_KEY,
+            logical.saturating_sub(reach),
+        );
+        let max = BtrfsKey::new(
+            BTRFS_EXTENT_CSUM_OBJECTID,
+            BTRFS_EXTENT_CSUM_KEY,
+            end.saturating_sub(1),
+        );
+        let items = self.items_in_range(&min, &max)?;
+        data_checksums(
+            items.iter().map(|(key, data)| (key, data.as_slice())),
+            sectorsize,
+            csum_type,
+            logical,
+            len,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::fs_and_csum_trees;
+
+    #[test]
+    fn lookup_spans_items_and_gaps() {
+        // sectors 0x1000..0x3000 in one item, 0x4000 in another, 0x3000 has no checksum
+        let k1 = BtrfsKey::new(BTRFS_EXTENT_CSUM_OBJECTID, BTRFS_EXTENT_CSUM_KEY, 0x1000);
+        let k2 = BtrfsKey::new(BTRFS_EXTENT_CSUM_OBJECTID, BTRFS_EXTENT_CSUM_KEY, 0x4000);
+        let d1 = [1u8, 1, 1, 1, 2, 2, 2, 2];
+        let d2 = [4u8, 4, 4, 4];
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![(&k1, &d1), (&k2, &d2)];
+
+        let sectors = data_checksums(items, 0x1000, ChecksumType::Crc32c, 0x2800, 0x2000).unwrap();
+        let expected: Vec<(u64, Option<Vec<u8>>)> = vec![
+            (0x2000, Some(vec![2, 2, 2, 2])),
+            (0x3000, None),
+            (0x4000, Some(vec![4, 4, 4, 4])),
+        ];
+        assert_eq!(
+            sectors
+                .into_iter()
+                .map(|s| (s.logical, s.csum))
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn bad_ranges_are_rejected() {
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![];
+        let err = data_checksums(items, 0, ChecksumType::Crc32c, 0x1000, 0x1000).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![];
+        assert!(
+            data_checksums(items, 0x1000, ChecksumType::Crc32c, u64::MAX - 0x10, 0x20).is_err()
+        );
+
+        let (_, csum_tree) = fs_and_csum_trees(Vec::new(), Vec::new(), &[]);
+        let err = csum_tree.data_checksums(u64::MAX - 0x10, 0x20).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(csum_tree.data_checksums(0, 0).unwrap().is_empty());
+
+        // an item running past the end of the address space stops there
+        let key = BtrfsKey::new(
+            BTRFS_EXTENT_CSUM_OBJECTID,
+            BTRFS_EXTENT_CSUM_KEY,
+            u64::MAX - 0x1fff,
+        );
+        let item =
+            BtrfsExtentCsum::from_item(&key, &[0; 12], 0x1000, ChecksumType::Crc32c).unwrap();
+        assert_eq!(item.end(), u64::MAX);
+        let starts: Vec<u64> = item.sectors().map(|(logical, _)| logical).collect();
+        assert_eq!(starts, [u64::MAX - 0x1fff, u64::MAX - 0xfff]);
+    }
+
+    #[test]
+    fn checksum_algorithms_match_reference_values() {
+        assert_eq!(
+            ChecksumType::Crc32c.compute(b"123456789"),
+            0xe3069283u32.to_le_bytes()
+        );
+        assert_eq!(
+            ChecksumType::Xxhash64.compute(b""),
+            0xef46db3751d8e999u64.to_le_bytes()
+        );
+        assert_eq!(
+            ChecksumType::Sha256.compute(b"abc")[..4],
+            [0xba, 0x78, 0x16, 0xbf]
+        );
+        assert_eq!(
+            ChecksumType::Blake2b.compute(b"abc")[..4],
+            [0xbd, 0xdd, 0x81, 0x3c]
+        );
+
+        let mut header_csum = [0u8; 32];
+        header_csum[..4].copy_from_slice(&ChecksumType::Crc32c.compute(b"data"));
+        assert!(ChecksumType::Crc32c.verify(b"data", &header_csum));
+        assert!(!ChecksumType::Crc32c.verify(b"date", &header_csum));
+    }
+
+    #[test]
+    fn item_size_must_match_csum_size() {
+        let key = BtrfsKey::new(BTRFS_EXTENT_CSUM_OBJECTID, BTRFS_EXTENT_CSUM_KEY, 0);
+        assert!(BtrfsExtentCsum::from_item(&key, &[0; 12], 4096, ChecksumType::Xxhash64).is_err());
+        let item = BtrfsExtentCsum::from_item(&key, &[0; 64], 4096, ChecksumType::Sha256).unwrap();
+        assert_eq!(item.end(), 2 * 4096);
+    }
+}
@@ -0,0 +1,366 @@
+// ** Device sets
+// A filesystem can span several devices. Each of them carries its own copy of the superblock,
+// with the same fsid and its own dev_item (devid, device uuid). The chunk tree lists every
+// device of the filesystem as a DEV_ITEM keyed by
+// |DEV_ITEMS_OBJECTID (1)| DEV_ITEM| devid|
+// and chunk stripes refer to devices by devid.
+// DeviceSet groups opened devices by fsid and finds the device of a stripe by its devid, once
+// the device uuid in the superblock of each device has been checked against its DEV_ITEM.
+// A set may lack some of the devices, the filesystem is then read degraded from the copies and
+// parity left (see BTree::from_device_set).
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::PathBuf;
+
+use crate::btrees::BlockDevice;
+use crate::btrfs::{BtrfsDevItem, BtrfsKey, BtrfsSuperblock, BTRFS_DEV_ITEM_KEY};
+use crate::storage::{BlockStorage, ReadOnlyFile};
+use crate::uuid_tree::format_uuid;
+
+/// The opened devices of one filesystem. Clones share the storage of the devices.
+pub struct DeviceSet<S: BlockStorage = File> {
+    pub fsid: [u8; 16],
+    pub superblock: BtrfsSuperblock, // the most recent of the devices' superblocks
+    pub devices: BTreeMap<u64, BlockDevice<S>>, // by devid
+    pub dev_items: BTreeMap<u64, BtrfsDevItem>, // every device the filesystem is made of
+}
+
+impl<S: BlockStorage> Clone for DeviceSet<S> {
+    fn clone(&self) -> Self {
+        DeviceSet {
+            fsid: self.fsid,
+            superblock: self.superblock.clone(),
+            devices: self.devices.clone(),
+            dev_items: self.dev_items.clone(),
+        }
+    }
+}
+
+impl<S: BlockStorage> DeviceSet<S> {
+    /// A set with a single device
+    pub fn from_device(device: BlockDevice<S>) -> Result<Self, std::io::Error> {
+        let mut sets = Self::assemble(vec![device])?;
+        Ok(sets.remove(0))
+    }
+
+    /// Reads the superblock of every device and groups the devices by fsid. When two devices
+    /// claim the same devid (an old copy of an image, say) the one with the newest superblock
+    /// is kept.
+    pub fn assemble(devices: Vec<BlockDevice<S>>) -> Result<Vec<Self>, std::io::Error> {
+        let mut sets: Vec<DeviceSet<S>> = Vec::new();
+        for mut device in devices {
+            let superblock = device.read_superblock()?;
+            device.set_block_sizes(&superblock)?;
+            let devid = superblock.dev_item.devid;
+
+            let Some(set) = sets.iter_mut().find(|set| set.fsid == superblock.fsid) else {
+                sets.push(DeviceSet {
+                    fsid: superblock.fsid,
+                    dev_items: BTreeMap::from([(devid, superblock.dev_item.clone())]),
+                    devices: BTreeMap::from([(devid, device)]),
+                    superblock,
+                });
+                continue;
+            };
+
+            let newer = superblock.generation > set.superblock.generation;
+            let replaces = match set.devices.get(&devid) {
+                None => true,
+                Some(existing) => superblock.generation > existing.read_superblock()?.generation,
+            };
+            if replaces {
+                set.dev_items.insert(devid, superblock.dev_item.clone());
+                set.devices.insert(devid, device);
+            }
+            if newer {
+                set.superblock = superblock;
+            }
+        }
+        Ok(sets)
+    }
+
+    /// Returns the device with this devid, or a NotFound error naming it
+    pub fn device(&self, devid: u64) -> Result<&BlockDevice<S>, std::io::Error> {
+        self.devices.get(&devid).ok_or_else(|| {
+            let uuid = self
+                .dev_items
+                .get(&devid)
+                .map(|item| format_uuid(&item.device_uuid))
+                .unwrap_or_else(|| "unknown".to_string());
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("device {} (uuid {}) is missing", devid, uuid),
+            )
+        })
+    }
+
+    /// Records the DEV_ITEMs among (key, payload) pairs of the chunk tree. Fails with
+    /// InvalidData when an opened device claims the devid of a DEV_ITEM with another device
+    /// uuid, i.e. it isn't the device the chunk tree means.
+    pub fn insert_dev_items<'a, I>(&mut self, items: I) -> Result<(), std::io::Error>
+    where
+        I: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+    {
+        for (key, data) in items {
+            if key.type_id == BTRFS_DEV_ITEM_KEY {
+                let item = BtrfsDevItem::default().read_from_buff(data)?;
+                // the dev_items of opened devices come from their superblocks (see assemble)
+                if let Some(opened) = self
+                    .dev_items
+                    .get(&item.devid)
+                    .filter(|_| self.devices.contains_key(&item.devid))
+                {
+                    if opened.device_uuid != item.device_uuid {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "device {} has uuid {} but the chunk tree expects {}",
+                                item.devid,
+                                format_uuid(&opened.device_uuid),
+                                format_uuid(&item.device_uuid)
+                            ),
+                        ));
+                    }
+                }
+                self.dev_items.insert(item.devid, item);
+            }
+        }
+        Ok(())
+    }
+
+    /// Devices of the filesystem that weren't opened. Only complete once the DEV_ITEMs of the
+    /// chunk tree have been added; before that only num_devices tells something is missing.
+    pub fn missing_devices(&self) -> Vec<&BtrfsDevItem> {
+        self.dev_items
+            .values()
+            .filter(|item| !self.devices.contains_key(&item.devid))
+            .collect()
+    }
+
+    /// Whether every device of the filesystem is present
+    pub fn is_complete(&self) -> bool {
+        self.devices.len() as u64 >= self.superblock.num_devices
+            && self.missing_devices().is_empty()
+    }
+}
+
+impl DeviceSet<ReadOnlyFile> {
+    /// Opens the given images or block devices read-only and groups them by filesystem
+    pub fn open_paths(paths: &[&str]) -> Result<Vec<Self>, std::io::Error> {
+        let devices = paths
+            .iter()
+            .map(|path| BlockDevice::open_read_only(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::assemble(devices)
+    }
+
+    /// Opens every file of a directory holding a btrfs superblock. Files without one are
+    /// skipped, files that can't be opened or read are returned with the error next to the
+    /// sets.
+    pub fn scan_dir(dir: &str) -> Result<(Vec<Self>, Vec<ScanError>), std::io::Error> {
+        let mut devices = Vec::new();
+        let mut errors = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(path_str) = path.to_str() else {
+                errors.push(ScanError {
+                    path,
+                    error: std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "path is not valid UTF-8",
+                    ),
+                });
+                continue;
+            };
+            let device = match BlockDevice::open_read_only(path_str) {
+                Ok(device) => device,
+                Err(error) => {
+                    errors.push(ScanError { path, error });
+                    continue;
+                }
+            };
+            match device.read_superblock() {
+                Ok(_) => devices.push(device),
+                // too small for a superblock, or no btrfs magic
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData
+                    ) => {}
+                Err(error) => errors.push(ScanError { path, error }),
+            }
+        }
+        Ok((Self::assemble(devices)?, errors))
+    }
+}
+
+/// A file DeviceSet::scan_dir couldn't check for a btrfs superblock
+#[derive(Debug)]
+pub struct ScanError {
+    pub path: PathBuf,
+    pub error: std::io::Error,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrees::{BTree, BTRFS_SUPER_INFO_OFFSET};
+    use crate::btrfs::BTRFS_ROOT_ITEM_KEY;
+    use crate::chunk::{
+        BTRFS_BLOCK_GROUP_DATA, BTRFS_BLOCK_GROUP_METADATA, BTRFS_BLOCK_GROUP_RAID1,
+        BTRFS_BLOCK_GROUP_RAID5,
+    };
+    use crate::test_image::{leaf, root_item, TestImage, CHUNK_LOGICAL};
+
+    const ROOT: u64 = CHUNK_LOGICAL + 0x1000;
+    const META_LOGICAL: u64 = 0x80_0000;
+    const FS_LEAF: u64 = META_LOGICAL + 0x2000;
+    const DATA_LOGICAL: u64 = 0x100_0000;
+
+    /// Two devices: the SYSTEM chunk on device 1, a METADATA chunk holding the fs tree on 2
+    fn two_device_images() -> Vec<Vec<u8>> {
+        let mut image = TestImage::with_devices(ROOT, CHUNK_LOGICAL, 2);
+        image.add_chunk(
+            META_LOGICAL,
+            0x10_0000,
+            BTRFS_BLOCK_GROUP_METADATA,
+            &[(2, 0x10_0000)],
+        );
+        let root_items = [(BtrfsKey::new(5, BTRFS_ROOT_ITEM_KEY, 0), root_item(FS_LEAF))];
+        image.put_node(ROOT, &leaf(ROOT, 1, &root_items));
+        let fs_items = [(BtrfsKey::new(256, 1, 0), vec![0xbb; 4])];
+        image.put_node(FS_LEAF, &leaf(FS_LEAF, 5, &fs_items));
+        image.finish()
+    }
+
+    fn devices(images: Vec<Vec<u8>>) -> Vec<BlockDevice<Vec<u8>>> {
+        images
+            .into_iter()
+            .map(|image| BlockDevice::from_storage(image).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn assembles_devices_by_fsid() {
+        let mut images = two_device_images();
+        images.reverse();
+        let mut other = images[0].clone();
+        other[BTRFS_SUPER_INFO_OFFSET as usize + 0x20] ^= 0xff; // another filesystem
+        images.push(other);
+
+        let sets = DeviceSet::assemble(devices(images)).unwrap();
+        assert_eq!(sets.len(), 2);
+        let set = sets.into_iter().find(|set| set.devices.len() == 2).unwrap();
+        assert_eq!(set.devices.keys().copied().collect::<Vec<_>>(), [1, 2]);
+
+        let tree = BTree::from_device_set(set).unwrap();
+        assert!(tree.devices.is_complete());
+        let fs_tree = tree.open_tree(5).unwrap();
+        assert_eq!(
+            fs_tree.search(&BtrfsKey::new(256, 1, 0)).unwrap(),
+            Some(vec![0xbb; 4])
+        );
+    }
+
+    #[test]
+    fn device_uuids_must_match_the_chunk_tree() {
+        let mut images = two_device_images();
+        // device 2 is another device with the same devid
+        images[1][BTRFS_SUPER_INFO_OFFSET as usize + 0xc9 + 0x42] ^= 0xff;
+        let set = DeviceSet::assemble(devices(images)).unwrap().remove(0);
+        let err = BTree::from_device_set(set).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(
+            "device 2 has uuid fd020202-0202-0202-0202-020202020202 but the chunk tree expects \
+             02020202-0202-0202-0202-020202020202"
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn scan_dir_skips_other_files_and_reports_errors() {
+        let dir = std::env::temp_dir().join(format!("btrfs-scan-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        for (idx, image) in two_device_images().into_iter().enumerate() {
+            std::fs::write(dir.join(format!("dev{}.img", idx + 1)), image).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), b"not a filesystem").unwrap();
+        std::fs::write(dir.join("zeroes.img"), vec![0u8; 0x20000]).unwrap();
+        std::os::unix::fs::symlink(dir.join("gone.img"), dir.join("dangling")).unwrap();
+
+        let (sets, errors) = DeviceSet::scan_dir(dir.to_str().unwrap()).unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].devices.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, dir.join("dangling"));
+        assert_eq!(errors[0].error.kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn opens_degraded_without_a_device() {
+        let mut images = two_device_images();
+        images.truncate(1);
+        let set = DeviceSet::assemble(devices(images)).unwrap().remove(0);
+        assert!(!set.is_complete());
+
+        let tree = BTree::from_device_set(set).unwrap();
+        assert!(tree.is_degraded());
+        let missing = tree.devices.missing_devices();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].devid, 2);
+
+        // the fs tree only has a copy on device 2
+        let err = tree.open_tree(5).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .contains("device 2 (uuid 02020202-0202-0202-0202-020202020202) is missing"));
+    }
+
+    #[test]
+    fn degraded_reads_use_the_remaining_copies() {
+        let mut image = TestImage::with_devices(ROOT, CHUNK_LOGICAL, 3);
+        let raid1 = [(1, 0x20_0000), (2, 0x10_0000)];
+        let raid5 = [(1, 0x30_0000), (2, 0x20_0000), (3, 0x10_0000)];
+        image.add_chunk(
+            META_LOGICAL,
+            0x10_0000,
+            BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID1,
+            &raid1,
+        );
+        image.add_chunk(
+            DATA_LOGICAL,
+            0x10_0000,
+            BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID5,
+            &raid5,
+        );
+        let root_items = [(BtrfsKey::new(5, BTRFS_ROOT_ITEM_KEY, 0), root_item(FS_LEAF))];
+        image.put_node(ROOT, &leaf(ROOT, 1, &root_items));
+        let fs_items = [(BtrfsKey::new(256, 1, 0), vec![0xbb; 4])];
+        image.put_node(FS_LEAF, &leaf(FS_LEAF, 5, &fs_items));
+        image.put_data(DATA_LOGICAL + 0x1_0000, b"on device 2");
+        let mut images = image.finish();
+        images.remove(1);
+
+        let set = DeviceSet::assemble(devices(images)).unwrap().remove(0);
+        let tree = BTree::from_device_set(set).unwrap();
+        assert!(tree.is_degraded());
+        let fs_tree = tree.open_tree(5).unwrap();
+        assert_eq!(
+            fs_tree.search(&BtrfsKey::new(256, 1, 0)).unwrap(),
+            Some(vec![0xbb; 4])
+        );
+        let data = tree
+            .read_logical(DATA_LOGICAL + 0x1_0000, 11, |_, _| true)
+            .unwrap();
+        assert_eq!(data, b"on device 2");
+        // nothing was bad, device 2 is just gone
+        assert!(tree.bad_copies().is_empty());
+    }
+}
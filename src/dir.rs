@@ -0,0 +1,383 @@
+// ** Directory entries
+// Every entry of a directory is stored twice in the fs tree, as
+// |directory inode| DIR_ITEM| name hash|     to find an entry by name
+// |directory inode| DIR_INDEX| sequence number| to list entries in creation order
+// with the same payload: the location key of the target (an INODE_ITEM key, or a ROOT_ITEM key
+// for a subvolume), the file type and the name. The hash is the crc32c of the name, so names
+// whose hashes collide are packed in the same DIR_ITEM one after the other.
+// Each inode also has an INODE_REF keyed by its parent directory, which is how ".." is found.
+// Sequence numbers start at 2 (0 and 1 would be "." and "..", which aren't stored) and only grow,
+// so a listing can be resumed from the index after the last entry returned.
+use std::collections::VecDeque;
+
+use crate::btrees::{BTree, Node};
+use crate::btrfs::{
+    check_len, read_le_u16, read_le_u64, BtrfsKey, BTRFS_DIR_INDEX_KEY, BTRFS_DIR_ITEM_KEY,
+    BTRFS_INODE_ITEM_KEY, BTRFS_INODE_REF_KEY,
+};
+use crate::inode::BtrfsInodeItem;
+use crate::storage::BlockStorage;
+
+// BtrfsDirItem::file_type
+pub const BTRFS_FT_UNKNOWN: u8 = 0;
+pub const BTRFS_FT_REG_FILE: u8 = 1;
+pub const BTRFS_FT_DIR: u8 = 2;
+pub const BTRFS_FT_CHRDEV: u8 = 3;
+pub const BTRFS_FT_BLKDEV: u8 = 4;
+pub const BTRFS_FT_FIFO: u8 = 5;
+pub const BTRFS_FT_SOCK: u8 = 6;
+pub const BTRFS_FT_SYMLINK: u8 = 7;
+pub const BTRFS_FT_XATTR: u8 = 8;
+
+/// Hash of a name as used in DIR_ITEM keys: crc32c seeded with ~1, without the final inversion
+pub fn name_hash(name: &[u8]) -> u64 {
+    // crc32c_append inverts the seed and the result, which undoes both
+    !crc32c::crc32c_append(1, name) as u64
+}
+
+/// One entry of a DIR_ITEM or DIR_INDEX item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsDirItem {
+    pub location: BtrfsKey, // 0x00: INODE_ITEM key of the target, ROOT_ITEM key for a subvolume
+    pub transid: u64,       // 0x11: transaction the entry was created in
+    pub file_type: u8,      // 0x1d: BTRFS_FT_*
+    pub name: Vec<u8>,      // 0x1e: name_len (0x1b) bytes, not NUL terminated
+    pub data: Vec<u8>,      // data_len (0x19) bytes after the name, only used by xattrs
+}
+
+impl BtrfsDirItem {
+    /// Size of an entry before its name
+    pub const HEADER_SIZE: usize = 0x1e;
+
+    /// Splits the payload of a DIR_ITEM (or DIR_INDEX) into its entries
+    pub fn parse_items(buffer: &[u8]) -> Result<Vec<Self>, std::io::Error> {
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < buffer.len() {
+            let entry = &buffer[pos..];
+            check_len(entry, Self::HEADER_SIZE)?;
+            let data_len = read_le_u16(entry, 0x19) as usize;
+            let name_len = read_le_u16(entry, 0x1b) as usize;
+            let name_end = Self::HEADER_SIZE + name_len;
+            check_len(entry, name_end + data_len)?;
+            entries.push(BtrfsDirItem {
+                location: BtrfsKey::from_buffer(entry)?,
+                transid: read_le_u64(entry, 0x11),
+                file_type: entry[0x1d],
+                name: entry[Self::HEADER_SIZE..name_end].to_vec(),
+                data: entry[name_end..name_end + data_len].to_vec(),
+            });
+            pos += name_end + data_len;
+        }
+        Ok(entries)
+    }
+}
+
+/// An entry returned by readdir
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: Vec<u8>,
+    pub inode: u64,    // objectid of the location key, a tree id for a subvolume
+    pub file_type: u8, // BTRFS_FT_*
+    pub index: u64,    // sequence number, resume with index + 1
+}
+
+/// Iterator over the DIR_INDEX entries of a directory, see BTree::readdir. Reads one leaf at
+/// a time.
+pub struct ReadDir<'a, S: BlockStorage> {
+    tree: &'a BTree<S>,
+    next: Option<BtrfsKey>, // where to continue, None once the directory is done
+    max: BtrfsKey,          // last possible DIR_INDEX key of the directory
+    pending: VecDeque<DirEntry>,
+}
+
+impl<S: BlockStorage> ReadDir<'_, S> {
+    /// Queues the entries of the leaf holding `from`
+    fn fill(&mut self, from: BtrfsKey) -> Result<(), std::io::Error> {
+        let Some(position) = self.tree.leaf_for(&from)? else {
+            return Ok(());
+        };
+        let Node::Leaf(leaf) = position.leaf.as_ref() else {
+            unreachable!("leaf_for returns leaves");
+        };
+        for (key, data) in leaf.iter_items() {
+            if *key < from {
+                continue;
+            }
+            if *key > self.max {
+                return Ok(());
+            }
+            for entry in BtrfsDirItem::parse_items(data)? {
+                self.pending.push_back(DirEntry {
+                    name: entry.name,
+                    inode: entry.location.object_id,
+                    file_type: entry.file_type,
+                    index: key.offset,
+                });
+            }
+        }
+        self.next = position.next_leaf.filter(|next| *next <= self.max);
+        Ok(())
+    }
+}
+
+impl<S: BlockStorage> Iterator for ReadDir<'_, S> {
+    type Item = Result<DirEntry, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                return Some(Ok(entry));
+            }
+            let from = self.next.take()?;
+            if let Err(err) = self.fill(from) {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// Lists directory `dir` in sequence order, starting at index `from_index` (0 for the
+    /// whole directory, the index of the last entry seen plus one to resume)
+    pub fn readdir(&self, dir: u64, from_index: u64) -> ReadDir<'_, S> {
+        ReadDir {
+            tree: self,
+            next: Some(BtrfsKey::new(dir, BTRFS_DIR_INDEX_KEY, from_index)),
+            max: BtrfsKey::new(dir, BTRFS_DIR_INDEX_KEY, u64::MAX),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Inode number of the top directory: root_dirid of the ROOT_ITEM for a tree opened with
+    /// open_tree, root_dir_objectid of the superblock for the tree of tree roots
+    pub fn root_dirid(&self) -> u64 {
+        match &self.root_item {
+            Some(root_item) => root_item.root_dirid,
+            None => self.superblock.root_dir_objectid,
+        }
+    }
+
+    /// Finds the entry named `name` in directory `dir` through its DIR_ITEM
+    pub fn lookup_dir(
+        &self,
+        dir: u64,
+        name: &[u8],
+    ) -> Result<Option<BtrfsDirItem>, std::io::Error> {
+        let key = BtrfsKey::new(dir, BTRFS_DIR_ITEM_KEY, name_hash(name));
+        let Some(data) = self.search(&key)? else {
+            return Ok(None);
+        };
+        // other names with the same hash share the item
+        Ok(BtrfsDirItem::parse_items(&data)?
+            .into_iter()
+            .find(|entry| entry.name == name))
+    }
+
+    /// Directory holding `inode`, from its first INODE_REF. The top directory is its own parent.
+    pub fn parent_dir(&self, inode: u64) -> Result<u64, std::io::Error> {
+        if inode == self.root_dirid() {
+            return Ok(inode);
+        }
+        let min = BtrfsKey::new(inode, BTRFS_INODE_REF_KEY, 0);
+        let max = BtrfsKey::new(inode, BTRFS_INODE_REF_KEY, u64::MAX);
+        let refs = self.items_in_range(&min, &max)?;
+        let (key, _) = refs.first().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("inode {} has no INODE_REF", inode),
+            )
+        })?;
+        Ok(key.offset)
+    }
+
+    /// Resolves a path from the top directory of this (fs) tree, following "." and "..", and
+    /// returns the INODE_ITEM key of the target with its inode item. Paths are relative to the
+    /// top directory whether or not they start with '/'. Entries pointing into another
+    /// subvolume aren't followed.
+    pub fn lookup_path(&self, path: &str) -> Result<(BtrfsKey, BtrfsInodeItem), std::io::Error> {
+        let mut inode = self.root_dirid();
+        let mut item = self.read_inode(inode)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("top directory {} has no inode item", inode),
+            )
+        })?;
+
+        let mut walked = String::new();
+        for name in path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+        {
+            if !item.is_dir() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotADirectory,
+                    format!("/{} is not a directory", walked.trim_start_matches('/')),
+                ));
+            }
+            walked.push('/');
+            walked.push_str(name);
+
+            inode = if name == ".." {
+                self.parent_dir(inode)?
+            } else {
+                let entry = self.lookup_dir(inode, name.as_bytes())?.ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{}: no such file or directory", walked),
+                    )
+                })?;
+                if entry.location.type_id != BTRFS_INODE_ITEM_KEY {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!(
+                            "{} is subvolume {}, open it with open_tree",
+                            walked, entry.location.object_id
+                        ),
+                    ));
+                }
+                entry.location.object_id
+            };
+            item = self.read_inode(inode)?.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} points to inode {} which doesn't exist", walked, inode),
+                )
+            })?;
+        }
+        Ok((BtrfsKey::new(inode, BTRFS_INODE_ITEM_KEY, 0), item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inode::{S_IFDIR, S_IFREG};
+    use crate::test_image::{dir_item, fs_tree, fs_tree_leaves, inode_item};
+
+    #[test]
+    fn name_hash_matches_the_kernel() {
+        // the "default" entry of the root tree directory, as shown by btrfs inspect-internal
+        assert_eq!(name_hash(b"default"), 2378154706);
+    }
+
+    #[test]
+    fn lookup_path_walks_dir_items() {
+        let dir_key = |dir: u64, name: &str| {
+            BtrfsKey::new(dir, BTRFS_DIR_ITEM_KEY, name_hash(name.as_bytes()))
+        };
+        let inode_key = |inode: u64| BtrfsKey::new(inode, BTRFS_INODE_ITEM_KEY, 0);
+        // "b" shares its DIR_ITEM with a colliding name, as if both had the same hash
+        let mut collision = dir_item(300, BTRFS_FT_REG_FILE, "not b");
+        collision.extend(dir_item(258, BTRFS_FT_REG_FILE, "b"));
+        let tree = fs_tree(
+            vec![
+                (inode_key(256), inode_item(S_IFDIR | 0o755, 0)),
+                (dir_key(256, "a"), dir_item(257, BTRFS_FT_DIR, "a")),
+                (inode_key(257), inode_item(S_IFDIR | 0o755, 0)),
+                (BtrfsKey::new(257, BTRFS_INODE_REF_KEY, 256), vec![0; 11]),
+                (dir_key(257, "b"), collision),
+                (inode_key(258), inode_item(S_IFREG | 0o644, 1234)),
+                (BtrfsKey::new(258, BTRFS_INODE_REF_KEY, 257), vec![0; 11]),
+            ],
+            &[],
+        );
+
+        let (key, inode) = tree.lookup_path("/a/b").unwrap();
+        assert_eq!(key, inode_key(258));
+        assert_eq!(inode.size, 1234);
+        assert_eq!(tree.lookup_path("a/./../a//b").unwrap().0, key);
+        assert_eq!(tree.lookup_path("/").unwrap().0, inode_key(256));
+        assert_eq!(tree.lookup_path("/..").unwrap().0, inode_key(256));
+
+        let err = tree.lookup_path("/a/c").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("/a/c"));
+        let err = tree.lookup_path("/a/b/c").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotADirectory);
+    }
+
+    #[test]
+    fn readdir_lists_dir_index_in_order() {
+        let index_key = |index: u64| BtrfsKey::new(256, BTRFS_DIR_INDEX_KEY, index);
+        let tree = fs_tree(
+            vec![
+                (
+                    BtrfsKey::new(256, BTRFS_INODE_ITEM_KEY, 0),
+                    inode_item(S_IFDIR, 0),
+                ),
+                (index_key(2), dir_item(257, BTRFS_FT_DIR, "z")),
+                (index_key(3), dir_item(258, BTRFS_FT_REG_FILE, "a")),
+                (index_key(7), dir_item(259, BTRFS_FT_SYMLINK, "m")),
+                (
+                    BtrfsKey::new(257, BTRFS_INODE_ITEM_KEY, 0),
+                    inode_item(S_IFDIR, 0),
+                ),
+                (
+                    BtrfsKey::new(257, BTRFS_DIR_INDEX_KEY, 2),
+                    dir_item(260, BTRFS_FT_REG_FILE, "x"),
+                ),
+            ],
+            &[],
+        );
+
+        let entries: Vec<DirEntry> = tree.readdir(256, 0).collect::<Result<_, _>>().unwrap();
+        let names: Vec<&[u8]> = entries.iter().map(|entry| entry.name.as_slice()).collect();
+        assert_eq!(names, [&b"z"[..], b"a", b"m"]);
+        assert_eq!(
+            entries[2],
+            DirEntry {
+                name: b"m".to_vec(),
+                inode: 259,
+                file_type: BTRFS_FT_SYMLINK,
+                index: 7,
+            }
+        );
+
+        // paging: resume after the second entry
+        let rest: Vec<u64> = tree
+            .readdir(256, entries[1].index + 1)
+            .map(|entry| entry.unwrap().inode)
+            .collect();
+        assert_eq!(rest, [259]);
+        assert_eq!(tree.readdir(256, 8).count(), 0);
+        assert_eq!(tree.readdir(300, 0).count(), 0);
+    }
+
+    #[test]
+    fn readdir_continues_in_the_next_leaf() {
+        let index_key = |index: u64| BtrfsKey::new(256, BTRFS_DIR_INDEX_KEY, index);
+        let tree = fs_tree_leaves(
+            vec![
+                vec![
+                    (
+                        BtrfsKey::new(256, BTRFS_INODE_ITEM_KEY, 0),
+                        inode_item(S_IFDIR, 0),
+                    ),
+                    (index_key(2), dir_item(257, BTRFS_FT_REG_FILE, "first")),
+                    (index_key(3), dir_item(258, BTRFS_FT_REG_FILE, "second")),
+                ],
+                vec![
+                    (index_key(4), dir_item(259, BTRFS_FT_REG_FILE, "third")),
+                    (
+                        BtrfsKey::new(257, BTRFS_INODE_ITEM_KEY, 0),
+                        inode_item(S_IFREG, 0),
+                    ),
+                ],
+            ],
+            &[],
+        );
+
+        let inodes: Vec<u64> = tree
+            .readdir(256, 0)
+            .map(|entry| entry.unwrap().inode)
+            .collect();
+        assert_eq!(inodes, [257, 258, 259]);
+        // starting in the second leaf
+        let rest: Vec<u64> = tree
+            .readdir(256, 4)
+            .map(|entry| entry.unwrap().inode)
+            .collect();
+        assert_eq!(rest, [259]);
+    }
+}
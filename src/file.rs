@@ -0,0 +1,679 @@
+// ** File extents
+// The data of a file is described by EXTENT_DATA items in the fs tree keyed by
+// |inode| EXTENT_DATA| offset in the file|
+// An extent is either inline, with the data in the item itself (small files, always at offset
+// 0), or points to a range of a data extent on disk: disk_bytenr/disk_num_bytes locate the whole
+// extent (logical addresses), and the file uses num_bytes of it starting `offset` bytes in.
+// Prealloc extents are allocated but never written and read as zeros, as do regular extents
+// with disk_bytenr 0 and ranges of the file not covered by any extent (the NO_HOLES feature
+// drops the explicit hole extents). Nothing past the inode size is part of the file.
+// Compressed extents, inline or not, are decompressed whole (see compression) and `offset`
+// then indexes the decompressed bytes.
+use crate::btrees::{BTree, Node};
+use crate::btrfs::{check_len, read_le_u16, read_le_u64, BtrfsKey, BTRFS_EXTENT_DATA_KEY};
+use crate::compression::{check_extent_sizes, decompress, BTRFS_COMPRESS_NONE};
+use crate::csum::ChecksumType;
+use crate::inode::BTRFS_INODE_NODATASUM;
+use crate::storage::BlockStorage;
+
+// BtrfsFileExtentItem::extent_type
+pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+/// An EXTENT_DATA item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsFileExtentItem {
+    pub generation: u64,     // 0x00: transaction the extent was written in
+    pub ram_bytes: u64,      // 0x08: size of the extent once decompressed
+    pub compression: u8,     // 0x10: BTRFS_COMPRESS_*
+    pub encryption: u8,      // 0x11: always 0
+    pub other_encoding: u16, // 0x12: always 0
+    pub extent_type: u8,     // 0x14: BTRFS_FILE_EXTENT_*
+
+    // Inline extents only
+    pub inline_data: Vec<u8>, // 0x15: the rest of the item
+
+    // Regular and prealloc extents only, zero for inline ones
+    pub disk_bytenr: u64,    // 0x15: logical address of the extent, 0 for a hole
+    pub disk_num_bytes: u64, // 0x1d: size of the extent on disk
+    pub offset: u64,         // 0x25: start of the file's part in the (decompressed) extent
+    pub num_bytes: u64,      // 0x2d: bytes of the file covered by this extent
+}
+
+impl BtrfsFileExtentItem {
+    /// Size of the item before the inline data
+    pub const INLINE_HEADER_SIZE: usize = 0x15;
+    /// Size of a regular or prealloc extent item
+    pub const SIZE: usize = 0x35;
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, Self::INLINE_HEADER_SIZE)?;
+        let extent_type = buffer[0x14];
+        let mut item = BtrfsFileExtentItem {
+            generation: read_le_u64(buffer, 0x00),
+            ram_bytes: read_le_u64(buffer, 0x08),
+            compression: buffer[0x10],
+            encryption: buffer[0x11],
+            other_encoding: read_le_u16(buffer, 0x12),
+            extent_type,
+            inline_data: Vec::new(),
+            disk_bytenr: 0,
+            disk_num_bytes: 0,
+            offset: 0,
+            num_bytes: 0,
+        };
+        if extent_type == BTRFS_FILE_EXTENT_INLINE {
+            item.inline_data = buffer[Self::INLINE_HEADER_SIZE..].to_vec();
+        } else {
+            check_len(buffer, Self::SIZE)?;
+            item.disk_bytenr = read_le_u64(buffer, 0x15);
+            item.disk_num_bytes = read_le_u64(buffer, 0x1d);
+            item.offset = read_le_u64(buffer, 0x25);
+            item.num_bytes = read_le_u64(buffer, 0x2d);
+        }
+        Ok(item)
+    }
+
+    /// Number of bytes of the file the extent covers
+    pub fn len(&self) -> u64 {
+        match self.extent_type {
+            BTRFS_FILE_EXTENT_INLINE => self.ram_bytes,
+            _ => self.num_bytes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the extent reads as zeros without touching the disk
+    pub fn is_hole(&self) -> bool {
+        self.extent_type == BTRFS_FILE_EXTENT_PREALLOC
+            || (self.extent_type == BTRFS_FILE_EXTENT_REG && self.disk_bytenr == 0)
+    }
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// Reads up to `len` bytes of file `inode` starting at `offset`. The read stops at the
+    /// inode size, so fewer bytes (none past the end) may come back.
+    pub fn read_file(
+        &self,
+        inode: u64,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        self.read_file_from(inode, offset, len, None)
+    }
+
+    /// Same as read_file, but every sector read from a data extent is checked against its
+    /// checksum in `csum_tree`, the csum tree of the filesystem. Copies that don't match are
+    /// skipped; when no copy matches, the read fails with an InvalidData error naming the
+    /// inode, file offset and logical address of the sector. Inline extents and NODATASUM
+    /// inodes have no checksums and are read unchecked.
+    pub fn read_file_verified(
+        &self,
+        csum_tree: &BTree<S>,
+        inode: u64,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        self.read_file_from(inode, offset, len, Some(csum_tree))
+    }
+
+    /// Reads the target of symlink `inode`, kept as the inline extent of the link
+    pub fn read_link(&self, inode: u64) -> Result<Vec<u8>, std::io::Error> {
+        let item = self.read_inode(inode)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("inode {} doesn't exist", inode),
+            )
+        })?;
+        if !item.is_symlink() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("inode {} is not a symlink", inode),
+            ));
+        }
+        let key = BtrfsKey::new(inode, BTRFS_EXTENT_DATA_KEY, 0);
+        let payload = self.search(&key)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("symlink {} has no target", inode),
+            )
+        })?;
+        let extent = BtrfsFileExtentItem::from_buffer(&payload)?;
+        if extent.extent_type != BTRFS_FILE_EXTENT_INLINE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("target of symlink {} is not inline", inode),
+            ));
+        }
+        let mut target = if extent.compression != BTRFS_COMPRESS_NONE {
+            self.decompress_extent(&extent, None, |_| String::new())?
+        } else {
+            extent.inline_data
+        };
+        target.truncate(item.size as usize);
+        Ok(target)
+    }
+
+    fn read_file_from(
+        &self,
+        inode: u64,
+        offset: u64,
+        len: usize,
+        csum_tree: Option<&BTree<S>>,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let item = self.read_inode(inode)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("inode {} doesn't exist", inode),
+            )
+        })?;
+        let csum_tree = csum_tree.filter(|_| item.flags & BTRFS_INODE_NODATASUM == 0);
+        let end = offset.saturating_add(len as u64).min(item.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        // holes, implicit or not, stay zero
+        let mut data = vec![0u8; (end - offset) as usize];
+
+        let first = self.extent_at_or_before(inode, offset)?;
+        let min = BtrfsKey::new(inode, BTRFS_EXTENT_DATA_KEY, first);
+        let max = BtrfsKey::new(inode, BTRFS_EXTENT_DATA_KEY, end - 1);
+        for (key, payload) in self.items_in_range(&min, &max)? {
+            let extent = BtrfsFileExtentItem::from_buffer(&payload)?;
+            let extent_start = key.offset;
+            let start = extent_start.max(offset);
+            let stop = extent_start.saturating_add(extent.len()).min(end);
+            if start >= stop || extent.is_hole() {
+                continue;
+            }
+
+            let dest = &mut data[(start - offset) as usize..(stop - offset) as usize];
+            let in_extent = start - extent_start;
+            let out_of_range = || {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "extent of inode {} at file offset {} points out of range",
+                        inode, extent_start
+                    ),
+                )
+            };
+            if extent.compression != BTRFS_COMPRESS_NONE {
+                // checksums cover the compressed extent, a bad sector is blamed on the
+                // start of the file extent
+                let plain = self.decompress_extent(&extent, csum_tree, |logical| {
+                    describe_sector(inode, extent_start, logical)
+                })?;
+                let skip = extent
+                    .offset
+                    .checked_add(in_extent)
+                    .ok_or_else(out_of_range)?;
+                let source = plain.get(skip as usize..).unwrap_or_default();
+                let copied = dest.len().min(source.len());
+                dest[..copied].copy_from_slice(&source[..copied]);
+            } else if extent.extent_type == BTRFS_FILE_EXTENT_INLINE {
+                // an inline extent can't be larger than a leaf, so these fit in usize
+                let source = extent
+                    .inline_data
+                    .get(in_extent as usize..)
+                    .unwrap_or_default();
+                let copied = dest.len().min(source.len());
+                dest[..copied].copy_from_slice(&source[..copied]);
+            } else {
+                let logical = extent
+                    .disk_bytenr
+                    .checked_add(extent.offset)
+                    .and_then(|logical| logical.checked_add(in_extent))
+                    .ok_or_else(out_of_range)?;
+                // `at` is a sector start, the first one may begin before `logical`; the offset
+                // stays below `stop` so it can't overflow
+                let bytes = self.read_data(csum_tree, logical, dest.len(), |at| {
+                    describe_sector(inode, start + at.saturating_sub(logical), at)
+                })?;
+                dest.copy_from_slice(&bytes);
+            }
+        }
+        Ok(data)
+    }
+
+    /// File offset of the last EXTENT_DATA item of the inode at or before `offset`, where a
+    /// read at `offset` starts, 0 when there is none
+    fn extent_at_or_before(&self, inode: u64, offset: u64) -> Result<u64, std::io::Error> {
+        let key = BtrfsKey::new(inode, BTRFS_EXTENT_DATA_KEY, offset);
+        let Some(position) = self.leaf_for(&key)? else {
+            return Ok(0);
+        };
+        let Node::Leaf(leaf) = position.leaf.as_ref() else {
+            unreachable!("leaf_for returns leaves");
+        };
+        // the leaf holds every key from its first one up to `key`, so the item before `key`
+        // is in it, one step back from where `key` would be inserted
+        let idx = leaf.items.partition_point(|item| item.key <= key);
+        Ok(idx
+            .checked_sub(1)
+            .map(|idx| &leaf.items[idx].key)
+            .filter(|found| found.object_id == inode && found.type_id == BTRFS_EXTENT_DATA_KEY)
+            .map_or(0, |found| found.offset))
+    }
+
+    /// Reads and decompresses the whole extent a compressed file extent points into
+    fn decompress_extent<F>(
+        &self,
+        extent: &BtrfsFileExtentItem,
+        csum_tree: Option<&BTree<S>>,
+        describe: F,
+    ) -> Result<Vec<u8>, std::io::Error>
+    where
+        F: Fn(u64) -> String,
+    {
+        check_extent_sizes(extent.disk_num_bytes, extent.ram_bytes)?;
+        let compressed = if extent.extent_type == BTRFS_FILE_EXTENT_INLINE {
+            extent.inline_data.clone()
+        } else {
+            self.read_data(
+                csum_tree,
+                extent.disk_bytenr,
+                extent.disk_num_bytes as usize,
+                describe,
+            )?
+        };
+        decompress(
+            extent.compression,
+            &compressed,
+            extent.ram_bytes as usize,
+            self.superblock.sectorsize as usize,
+        )
+    }
+
+    /// Reads `len` bytes of data extents at `logical`. With a csum tree, whole sectors are
+    /// read and each copy of them must match its checksum; `describe` names the file range of
+    /// a sector (by logical address) in errors.
+    fn read_data<F>(
+        &self,
+        csum_tree: Option<&BTree<S>>,
+        logical: u64,
+        len: usize,
+        describe: F,
+    ) -> Result<Vec<u8>, std::io::Error>
+    where
+        F: Fn(u64) -> String,
+    {
+        let Some(csum_tree) = csum_tree else {
+            return self.read_logical(logical, len, |_, _| true);
+        };
+        let csum_type = ChecksumType::from_superblock(&self.superblock)?;
+        let sector = self.superblock.sectorsize as u64;
+        let sectors = csum_tree.data_checksums(logical, len as u64)?;
+        let mut expected = Vec::with_capacity(sectors.len());
+        for sector_csum in sectors {
+            let csum = sector_csum.csum.ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: no data checksum", describe(sector_csum.logical)),
+                )
+            })?;
+            expected.push(csum);
+        }
+
+        let first = logical - logical % sector;
+        let mut bad = None;
+        let read = self.read_logical(first, expected.len() * sector as usize, |at, data| {
+            data.chunks(sector as usize)
+                .enumerate()
+                .all(|(idx, block)| {
+                    let at = at + idx as u64 * sector;
+                    let csum = &expected[((at - first) / sector) as usize];
+                    let good = csum_type.verify(block, csum);
+                    if !good {
+                        bad.get_or_insert(at);
+                    }
+                    good
+                })
+        });
+        match (read, bad) {
+            (Ok(data), _) => Ok(data[(logical - first) as usize..][..len].to_vec()),
+            (Err(err), Some(at)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: data checksum mismatch: {}", describe(at), err),
+            )),
+            (Err(err), None) => Err(err),
+        }
+    }
+}
+
+fn describe_sector(inode: u64, file_offset: u64, logical: u64) -> String {
+    format!(
+        "inode {} offset {} (logical {})",
+        inode, file_offset, logical
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::BTRFS_INODE_ITEM_KEY;
+    use crate::btrfs::{BTRFS_EXTENT_CSUM_KEY, BTRFS_EXTENT_CSUM_OBJECTID};
+    use crate::compression::tests::{lzo_extent, zlib, zstd};
+    use crate::compression::{BTRFS_COMPRESS_LZO, BTRFS_COMPRESS_ZLIB, BTRFS_COMPRESS_ZSTD};
+    use crate::inode::{S_IFLNK, S_IFREG};
+    use crate::test_image::fs_and_csum_trees;
+    use crate::test_image::{fs_tree, fs_tree_leaves, inode_item, DATA_LOGICAL};
+
+    fn inode_key(inode: u64) -> BtrfsKey {
+        BtrfsKey::new(inode, BTRFS_INODE_ITEM_KEY, 0)
+    }
+
+    fn extent_key(inode: u64, offset: u64) -> BtrfsKey {
+        BtrfsKey::new(inode, BTRFS_EXTENT_DATA_KEY, offset)
+    }
+
+    fn csum_key(logical: u64) -> BtrfsKey {
+        BtrfsKey::new(BTRFS_EXTENT_CSUM_OBJECTID, BTRFS_EXTENT_CSUM_KEY, logical)
+    }
+
+    fn inline_extent(data: &[u8]) -> Vec<u8> {
+        let mut item = vec![0u8; BtrfsFileExtentItem::INLINE_HEADER_SIZE];
+        item[0x08..0x10].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        item.extend_from_slice(data);
+        item
+    }
+
+    fn disk_extent(extent_type: u8, disk_bytenr: u64, offset: u64, num_bytes: u64) -> Vec<u8> {
+        let mut item = vec![0u8; BtrfsFileExtentItem::SIZE];
+        item[0x08..0x10].copy_from_slice(&0x1_0000u64.to_le_bytes());
+        item[0x14] = extent_type;
+        item[0x15..0x1d].copy_from_slice(&disk_bytenr.to_le_bytes());
+        item[0x1d..0x25].copy_from_slice(&0x1_0000u64.to_le_bytes());
+        item[0x25..0x2d].copy_from_slice(&offset.to_le_bytes());
+        item[0x2d..0x35].copy_from_slice(&num_bytes.to_le_bytes());
+        item
+    }
+
+    /// Marks an extent item as compressed, `disk_num_bytes` only applies to non inline ones
+    fn compressed(
+        mut item: Vec<u8>,
+        compression: u8,
+        ram_bytes: u64,
+        disk_num_bytes: u64,
+    ) -> Vec<u8> {
+        item[0x08..0x10].copy_from_slice(&ram_bytes.to_le_bytes());
+        item[0x10] = compression;
+        if item[0x14] != BTRFS_FILE_EXTENT_INLINE {
+            item[0x1d..0x25].copy_from_slice(&disk_num_bytes.to_le_bytes());
+        }
+        item
+    }
+
+    #[test]
+    fn reads_every_kind_of_extent() {
+        let disk: Vec<u8> = (0..0x4000u32).map(|i| (i % 251) as u8).collect();
+        // 0x0000..0x1000 regular (0x100 into the extent), 0x1000..0x2000 implicit hole,
+        // 0x2000..0x3000 prealloc, 0x3000..0x4000 explicit hole, 0x4000..0x6000 regular but
+        // the file ends at 0x4800
+        let tree = fs_tree(
+            vec![
+                (inode_key(257), inode_item(S_IFREG, 5)),
+                (extent_key(257, 0), inline_extent(b"hello")),
+                (inode_key(258), inode_item(S_IFREG, 0x4800)),
+                (
+                    extent_key(258, 0),
+                    disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0x100, 0x1000),
+                ),
+                (
+                    extent_key(258, 0x2000),
+                    disk_extent(BTRFS_FILE_EXTENT_PREALLOC, DATA_LOGICAL, 0, 0x1000),
+                ),
+                (
+                    extent_key(258, 0x3000),
+                    disk_extent(BTRFS_FILE_EXTENT_REG, 0, 0, 0x1000),
+                ),
+                (
+                    extent_key(258, 0x4000),
+                    disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0x2000, 0x2000),
+                ),
+            ],
+            &[(DATA_LOGICAL, disk.clone())],
+        );
+
+        assert_eq!(tree.read_file(257, 0, 100).unwrap(), b"hello");
+        assert_eq!(tree.read_file(257, 1, 3).unwrap(), b"ell");
+        assert!(tree.read_file(257, 5, 10).unwrap().is_empty());
+
+        let file = tree.read_file(258, 0, 0x10000).unwrap();
+        assert_eq!(file.len(), 0x4800);
+        assert_eq!(file[..0x1000], disk[0x100..0x1100]);
+        assert!(file[0x1000..0x4000].iter().all(|&b| b == 0));
+        assert_eq!(file[0x4000..], disk[0x2000..0x2800]);
+
+        // a read starting inside an extent
+        assert_eq!(
+            tree.read_file(258, 0xff0, 0x20).unwrap()[..0x10],
+            disk[0x10f0..0x1100]
+        );
+        assert!(tree.read_file(999, 0, 1).is_err());
+    }
+
+    #[test]
+    fn reads_start_at_the_extent_before_the_offset() {
+        let disk: Vec<u8> = (0..0x4000u32).map(|i| (i % 251) as u8).collect();
+        // the extents of 258 are split over two leaves, the last one points past the end of
+        // the address space
+        let tree = fs_tree_leaves(
+            vec![
+                vec![
+                    (inode_key(258), inode_item(S_IFREG, 0x5000)),
+                    (
+                        extent_key(258, 0),
+                        disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0, 0x1000),
+                    ),
+                    (
+                        extent_key(258, 0x1000),
+                        disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0x1000, 0x2000),
+                    ),
+                ],
+                vec![
+                    (
+                        extent_key(258, 0x3000),
+                        disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0x3000, 0x1000),
+                    ),
+                    (
+                        extent_key(258, 0x4000),
+                        disk_extent(BTRFS_FILE_EXTENT_REG, u64::MAX - 0x10, 0x100, 0x1000),
+                    ),
+                ],
+            ],
+            &[(DATA_LOGICAL, disk.clone())],
+        );
+
+        assert_eq!(tree.extent_at_or_before(258, 0).unwrap(), 0);
+        assert_eq!(tree.extent_at_or_before(258, 0x2000).unwrap(), 0x1000);
+        assert_eq!(tree.extent_at_or_before(258, 0x3000).unwrap(), 0x3000);
+        assert_eq!(tree.extent_at_or_before(258, 0x3800).unwrap(), 0x3000);
+        assert_eq!(tree.extent_at_or_before(257, 0x3800).unwrap(), 0);
+
+        assert_eq!(
+            tree.read_file(258, 0x2000, 0x10).unwrap(),
+            disk[0x2000..0x2010]
+        );
+        assert_eq!(
+            tree.read_file(258, 0x2ff0, 0x20).unwrap(),
+            disk[0x2ff0..0x3010]
+        );
+        assert_eq!(
+            tree.read_file(258, 0x3800, 0x10).unwrap(),
+            disk[0x3800..0x3810]
+        );
+        let err = tree.read_file(258, 0x3ff0, 0x20).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reads_compressed_extents() {
+        let plain: Vec<u8> = (0..0x4000u32)
+            .map(|i| (i * 7 % 13 + i / 512) as u8)
+            .collect();
+        let sectors = |data: Vec<u8>| {
+            let len = data.len().next_multiple_of(4096) as u64;
+            (data, len)
+        };
+        let (zstd_data, zstd_len) = sectors(zstd(&plain));
+        let (lzo_data, lzo_len) = sectors(lzo_extent(&plain[..0x3000]));
+        let text = b"hello hello hello hello";
+        let lzo_logical = DATA_LOGICAL + 0x1_0000;
+
+        // 0x0000..0x2000 is 0x1000 into the zstd extent, 0x2000..0x5000 the whole lzo one
+        let tree = fs_tree(
+            vec![
+                (inode_key(259), inode_item(S_IFREG, 0x5000)),
+                (
+                    extent_key(259, 0),
+                    compressed(
+                        disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0x1000, 0x2000),
+                        BTRFS_COMPRESS_ZSTD,
+                        0x4000,
+                        zstd_len,
+                    ),
+                ),
+                (
+                    extent_key(259, 0x2000),
+                    compressed(
+                        disk_extent(BTRFS_FILE_EXTENT_REG, lzo_logical, 0, 0x3000),
+                        BTRFS_COMPRESS_LZO,
+                        0x3000,
+                        lzo_len,
+                    ),
+                ),
+                // claims to expand to 1TiB
+                (inode_key(261), inode_item(S_IFREG, 0x1000)),
+                (
+                    extent_key(261, 0),
+                    compressed(
+                        disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0, 0x1000),
+                        BTRFS_COMPRESS_ZSTD,
+                        1 << 40,
+                        zstd_len,
+                    ),
+                ),
+                (inode_key(260), inode_item(S_IFREG, text.len() as u64)),
+                (
+                    extent_key(260, 0),
+                    compressed(
+                        inline_extent(&zlib(text)),
+                        BTRFS_COMPRESS_ZLIB,
+                        text.len() as u64,
+                        0,
+                    ),
+                ),
+            ],
+            &[(DATA_LOGICAL, zstd_data), (lzo_logical, lzo_data)],
+        );
+
+        let file = tree.read_file(259, 0, 0x5000).unwrap();
+        assert_eq!(file[..0x2000], plain[0x1000..0x3000]);
+        assert_eq!(file[0x2000..], plain[..0x3000]);
+        assert_eq!(
+            tree.read_file(259, 0x2ffe, 4).unwrap(),
+            plain[0xffe..0x1002]
+        );
+        assert_eq!(tree.read_file(260, 6, 100).unwrap(), &text[6..]);
+        let err = tree.read_file(261, 0, 0x1000).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn verified_reads_check_data_checksums() {
+        let csums = |data: &[u8]| -> Vec<u8> {
+            data.chunks(4096)
+                .flat_map(|sector| ChecksumType::Crc32c.compute(sector))
+                .collect()
+        };
+        let plain: Vec<u8> = (0..0x3000u32).map(|i| (i % 253) as u8).collect();
+        let mut compressed_data = zlib(&plain);
+        compressed_data.resize(4096, 0);
+        let compressed_logical = DATA_LOGICAL + 0x1_0000;
+        let unsummed_logical = DATA_LOGICAL + 0x2_0000;
+
+        // the second sector of inode 258 has a wrong checksum
+        let mut plain_csums = csums(&plain);
+        plain_csums[4] ^= 0xff;
+        let mut nodatasum = inode_item(S_IFREG, 0x1000);
+        nodatasum[0x40] = BTRFS_INODE_NODATASUM as u8;
+        let (tree, csum_tree) = fs_and_csum_trees(
+            vec![
+                (inode_key(258), inode_item(S_IFREG, 0x3000)),
+                (
+                    extent_key(258, 0),
+                    disk_extent(BTRFS_FILE_EXTENT_REG, DATA_LOGICAL, 0, 0x3000),
+                ),
+                (inode_key(259), inode_item(S_IFREG, 0x3000)),
+                (
+                    extent_key(259, 0),
+                    compressed(
+                        disk_extent(BTRFS_FILE_EXTENT_REG, compressed_logical, 0, 0x3000),
+                        BTRFS_COMPRESS_ZLIB,
+                        0x3000,
+                        0x1000,
+                    ),
+                ),
+                (inode_key(260), nodatasum),
+                (
+                    extent_key(260, 0),
+                    disk_extent(BTRFS_FILE_EXTENT_REG, unsummed_logical, 0, 0x1000),
+                ),
+                (inode_key(261), inode_item(S_IFREG, 0x1000)),
+                (
+                    extent_key(261, 0),
+                    disk_extent(BTRFS_FILE_EXTENT_REG, unsummed_logical, 0, 0x1000),
+                ),
+            ],
+            vec![
+                (csum_key(DATA_LOGICAL), plain_csums),
+                (csum_key(compressed_logical), csums(&compressed_data)),
+            ],
+            &[
+                (DATA_LOGICAL, plain.clone()),
+                (compressed_logical, compressed_data),
+                (unsummed_logical, vec![7; 0x1000]),
+            ],
+        );
+
+        let verified = |inode, offset, len| tree.read_file_verified(&csum_tree, inode, offset, len);
+        assert_eq!(verified(258, 0x10, 0x100).unwrap(), plain[0x10..0x110]);
+        assert_eq!(verified(258, 0x2000, 0x1000).unwrap(), plain[0x2000..]);
+        let err = verified(258, 0x800, 0x1000).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let culprit = format!("inode 258 offset 4096 (logical {})", DATA_LOGICAL + 0x1000);
+        assert!(err.to_string().contains(&culprit), "{}", err);
+        // unverified reads don't look at checksums
+        assert_eq!(tree.read_file(258, 0, 0x3000).unwrap(), plain);
+
+        assert_eq!(verified(259, 0x1000, 0x10).unwrap(), plain[0x1000..0x1010]);
+        assert_eq!(verified(260, 0, 0x1000).unwrap(), vec![7; 0x1000]);
+        let err = verified(261, 0, 0x1000).unwrap_err();
+        assert!(err.to_string().contains("no data checksum"), "{}", err);
+    }
+
+    #[test]
+    fn read_link_returns_the_inline_target() {
+        let target = b"../lib/libfoo.so.1";
+        let tree = fs_tree(
+            vec![
+                (
+                    inode_key(257),
+                    inode_item(S_IFLNK | 0o777, target.len() as u64),
+                ),
+                (extent_key(257, 0), inline_extent(target)),
+                (inode_key(258), inode_item(S_IFREG | 0o644, 5)),
+                (extent_key(258, 0), inline_extent(b"hello")),
+            ],
+            &[],
+        );
+        assert_eq!(tree.read_link(257).unwrap(), target);
+        let err = tree.read_link(258).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,365 @@
+// ** Free space tree
+// The free space tree (objectid 10 in the tree of tree roots) records the unallocated space of every
+// block group. Each block group has one FREE_SPACE_INFO item keyed by
+// |block group start| FREE_SPACE_INFO| block group length|
+// followed by the free space itself, which is stored in one of two ways:
+// 1. FREE_SPACE_EXTENT items: |start| FREE_SPACE_EXTENT| length| with an empty payload
+// 2. FREE_SPACE_BITMAP items: |start| FREE_SPACE_BITMAP| length| whose payload has one bit per
+//    sector, set when the sector is free
+// The kernel switches a block group to bitmaps when it gets too fragmented, and flags the
+// FREE_SPACE_INFO item with BTRFS_FREE_SPACE_USING_BITMAPS.
+use crate::btrees::BTree;
+use crate::btrfs::{
+    check_len, read_le_u32, BtrfsKey, BTRFS_FREE_SPACE_BITMAP_KEY, BTRFS_FREE_SPACE_EXTENT_KEY,
+    BTRFS_FREE_SPACE_INFO_KEY,
+};
+use crate::storage::BlockStorage;
+
+/// The block group's free space is stored as FREE_SPACE_BITMAP items instead of extents
+pub const BTRFS_FREE_SPACE_USING_BITMAPS: u32 = 1 << 0;
+
+/// Summary of the free space of one block group
+#[derive(Debug, Clone)]
+pub struct BtrfsFreeSpaceInfo {
+    pub extent_count: u32, // number of free extents in the block group
+    pub flags: u32,        // BTRFS_FREE_SPACE_USING_BITMAPS
+}
+
+/// A free range of a block group. Has no payload, the key carries everything.
+#[derive(Debug, Clone)]
+pub struct BtrfsFreeSpaceExtent {
+    pub start: u64,  // key object_id
+    pub length: u64, // key offset
+}
+
+/// A bitmap covering [start, start + length), one bit per sector
+#[derive(Debug, Clone)]
+pub struct BtrfsFreeSpaceBitmap {
+    pub start: u64,      // key object_id
+    pub length: u64,     // key offset
+    pub bitmap: Vec<u8>, // bit n (little-endian bit order) set => sector n is free
+}
+
+/// A contiguous free range of logical addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeSpaceRange {
+    pub start: u64,
+    pub length: u64,
+}
+
+impl BtrfsFreeSpaceInfo {
+    /// Deserializes a FREE_SPACE_INFO item payload (0x08 bytes)
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x08)?;
+        Ok(BtrfsFreeSpaceInfo {
+            extent_count: read_le_u32(buffer, 0x00),
+            flags: read_le_u32(buffer, 0x04),
+        })
+    }
+
+    pub fn using_bitmaps(&self) -> bool {
+        self.flags & BTRFS_FREE_SPACE_USING_BITMAPS != 0
+    }
+}
+
+impl BtrfsFreeSpaceExtent {
+    pub fn from_key(key: &BtrfsKey) -> Self {
+        BtrfsFreeSpaceExtent {
+            start: key.object_id,
+            length: key.offset,
+        }
+    }
+}
+
+impl BtrfsFreeSpaceBitmap {
+    /// Builds a bitmap item from its key and payload
+    pub fn from_item(key: &BtrfsKey, buffer: &[u8]) -> Self {
+        BtrfsFreeSpaceBitmap {
+            start: key.object_id,
+            length: key.offset,
+            bitmap: buffer.to_vec(),
+        }
+    }
+
+    /// Converts the set bits into free ranges, merging runs of consecutive free sectors
+    pub fn free_ranges(&self, sectorsize: u32) -> Result<Vec<FreeSpaceRange>, std::io::Error> {
+        check_sectorsize(sectorsize)?;
+        let sectorsize = sectorsize as u64;
+        let nr_sectors = (self.length / sectorsize).min(self.bitmap.len() as u64 * 8);
+        let mut ranges: Vec<FreeSpaceRange> = Vec::new();
+
+        for sector in 0..nr_sectors {
+            let is_free = self.bitmap[(sector / 8) as usize] & (1 << (sector % 8)) != 0;
+            if !is_free {
+                continue;
+            }
+            let start = sector
+                .checked_mul(sectorsize)
+                .and_then(|offset| self.start.checked_add(offset))
+                .ok_or_else(|| out_of_range(self.start, self.length))?;
+            push_range(&mut ranges, start, sectorsize)?;
+        }
+        Ok(ranges)
+    }
+}
+
+/// Bitmaps have one bit per sector, which needs a sector size
+fn check_sectorsize(sectorsize: u32) -> Result<(), std::io::Error> {
+    if sectorsize == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "sectorsize is 0",
+        ));
+    }
+    Ok(())
+}
+
+fn out_of_range(start: u64, length: u64) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "free space item at {} of {} bytes runs past the end of the address space",
+            start, length
+        ),
+    )
+}
+
+/// Appends [start, start + length) to the list, extending the last range when they touch
+fn push_range(
+    ranges: &mut Vec<FreeSpaceRange>,
+    start: u64,
+    length: u64,
+) -> Result<(), std::io::Error> {
+    if start.checked_add(length).is_none() {
+        return Err(out_of_range(start, length));
+    }
+    if let Some(last) = ranges.last_mut() {
+        // both ranges were checked when added, so neither end overflows
+        if last.start + last.length == start {
+            last.length += length;
+            return Ok(());
+        }
+    }
+    ranges.push(FreeSpaceRange { start, length });
+    Ok(())
+}
+
+/// Returns the free ranges of the block group starting at `block_group_start`.
+///
+/// `items` are the (key, payload) pairs of the free space tree in key order, for example the
+/// items of its leaves. Extents and bitmaps are both handled, whatever the block group's
+/// FREE_SPACE_INFO flags say, and adjacent ranges are merged.
+pub fn block_group_free_space<'a, I>(
+    items: I,
+    block_group_start: u64,
+    sectorsize: u32,
+) -> Result<Vec<FreeSpaceRange>, std::io::Error>
+where
+    I: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    check_sectorsize(sectorsize)?;
+    let mut items = items.into_iter();
+
+    let block_group_end = loop {
+        match items.next() {
+            Some((key, data))
+                if key.object_id == block_group_start
+                    && key.type_id == BTRFS_FREE_SPACE_INFO_KEY =>
+            {
+                BtrfsFreeSpaceInfo::from_buffer(data)?;
+                break block_group_start
+                    .checked_add(key.offset)
+                    .ok_or_else(|| out_of_range(block_group_start, key.offset))?;
+            }
+            Some(_) => continue,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no FREE_SPACE_INFO for block group {}", block_group_start),
+                ))
+            }
+        }
+    };
+
+    let mut ranges = Vec::new();
+    for (key, data) in items {
+        if key.object_id >= block_group_end {
+            break;
+        }
+        match key.type_id {
+            BTRFS_FREE_SPACE_EXTENT_KEY => {
+                let extent = BtrfsFreeSpaceExtent::from_key(key);
+                push_range(&mut ranges, extent.start, extent.length)?;
+            }
+            BTRFS_FREE_SPACE_BITMAP_KEY => {
+                let bitmap = BtrfsFreeSpaceBitmap::from_item(key, data);
+                for range in bitmap.free_ranges(sectorsize)? {
+                    push_range(&mut ranges, range.start, range.length)?;
+                }
+            }
+            _ => continue,
+        }
+    }
+    Ok(ranges)
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// block_group_free_space of the block group starting at `block_group_start`, looked up in
+    /// this tree, the free space tree
+    pub fn block_group_free_space(
+        &self,
+        block_group_start: u64,
+    ) -> Result<Vec<FreeSpaceRange>, std::io::Error> {
+        // the key of the FREE_SPACE_INFO item holds the length of the block group, which
+        // bounds the items to scan
+        let min = BtrfsKey::new(block_group_start, BTRFS_FREE_SPACE_INFO_KEY, 0);
+        let info_max = BtrfsKey::new(block_group_start, BTRFS_FREE_SPACE_INFO_KEY, u64::MAX);
+        let length = self
+            .items_in_range(&min, &info_max)?
+            .first()
+            .map_or(0, |(key, _)| key.offset);
+        let last = block_group_start
+            .saturating_add(length)
+            .saturating_sub(1)
+            .max(block_group_start);
+        let max = BtrfsKey::new(last, u8::MAX, u64::MAX);
+        let items = self.items_in_range(&min, &max)?;
+        block_group_free_space(
+            items.iter().map(|(key, data)| (key, data.as_slice())),
+            block_group_start,
+            self.superblock.sectorsize,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::fs_tree;
+
+    #[test]
+    fn extents_are_read_from_keys() {
+        let info = BtrfsKey::new(0x100000, BTRFS_FREE_SPACE_INFO_KEY, 0x10000);
+        let e1 = BtrfsKey::new(0x100000, BTRFS_FREE_SPACE_EXTENT_KEY, 0x1000);
+        let e2 = BtrfsKey::new(0x104000, BTRFS_FREE_SPACE_EXTENT_KEY, 0x2000);
+        let next = BtrfsKey::new(0x110000, BTRFS_FREE_SPACE_EXTENT_KEY, 0x1000);
+        let info_data = [2u8, 0, 0, 0, 0, 0, 0, 0];
+        let items: Vec<(&BtrfsKey, &[u8])> =
+            vec![(&info, &info_data), (&e1, &[]), (&e2, &[]), (&next, &[])];
+
+        let ranges = block_group_free_space(items, 0x100000, 4096).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                FreeSpaceRange {
+                    start: 0x100000,
+                    length: 0x1000
+                },
+                FreeSpaceRange {
+                    start: 0x104000,
+                    length: 0x2000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bitmap_runs_are_merged() {
+        let info = BtrfsKey::new(0, BTRFS_FREE_SPACE_INFO_KEY, 16 * 4096);
+        let bitmap = BtrfsKey::new(0, BTRFS_FREE_SPACE_BITMAP_KEY, 16 * 4096);
+        let info_data = [2u8, 0, 0, 0, 1, 0, 0, 0];
+        // sectors 1,2,3 and 8 free
+        let bitmap_data = [0b0000_1110u8, 0b0000_0001];
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![(&info, &info_data), (&bitmap, &bitmap_data)];
+
+        assert!(BtrfsFreeSpaceInfo::from_buffer(&info_data)
+            .unwrap()
+            .using_bitmaps());
+        let ranges = block_group_free_space(items, 0, 4096).unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                FreeSpaceRange {
+                    start: 4096,
+                    length: 3 * 4096
+                },
+                FreeSpaceRange {
+                    start: 8 * 4096,
+                    length: 4096
+                },
+            ]
+        );
+
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![(&info, &info_data), (&bitmap, &bitmap_data)];
+        assert!(block_group_free_space(items, 0, 0).is_err());
+        let item = BtrfsFreeSpaceBitmap::from_item(&bitmap, &bitmap_data);
+        assert!(item.free_ranges(0).is_err());
+    }
+
+    #[test]
+    fn overflowing_items_are_rejected() {
+        let start = u64::MAX - 0x1000;
+        let info_data = [1u8, 0, 0, 0, 0, 0, 0, 0];
+        let info = BtrfsKey::new(start, BTRFS_FREE_SPACE_INFO_KEY, 0x2000);
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![(&info, &info_data)];
+        let err = block_group_free_space(items, start, 4096).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let info = BtrfsKey::new(start, BTRFS_FREE_SPACE_INFO_KEY, 0x1000);
+        let extent = BtrfsKey::new(start, BTRFS_FREE_SPACE_EXTENT_KEY, 0x2000);
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![(&info, &info_data), (&extent, &[])];
+        assert!(block_group_free_space(items, start, 4096).is_err());
+
+        // the second sector of the bitmap runs past the end of the address space
+        let bitmap = BtrfsKey::new(start, BTRFS_FREE_SPACE_BITMAP_KEY, 2 * 4096);
+        let item = BtrfsFreeSpaceBitmap::from_item(&bitmap, &[0b10]);
+        assert!(item.free_ranges(4096).is_err());
+    }
+
+    #[test]
+    fn block_group_lookup_in_the_tree() {
+        let info = |start, length| {
+            (
+                BtrfsKey::new(start, BTRFS_FREE_SPACE_INFO_KEY, length),
+                vec![1u8, 0, 0, 0, 0, 0, 0, 0],
+            )
+        };
+        let extent = |start, length| {
+            (
+                BtrfsKey::new(start, BTRFS_FREE_SPACE_EXTENT_KEY, length),
+                Vec::new(),
+            )
+        };
+        let tree = fs_tree(
+            vec![
+                info(0x100000, 0x10000),
+                extent(0x101000, 0x1000),
+                extent(0x10e000, 0x2000),
+                info(0x110000, 0x10000),
+                (
+                    BtrfsKey::new(0x110000, BTRFS_FREE_SPACE_BITMAP_KEY, 0x10000),
+                    vec![0b0000_0110, 0],
+                ),
+            ],
+            &[],
+        );
+
+        let range = |start, length| FreeSpaceRange { start, length };
+        assert_eq!(
+            tree.block_group_free_space(0x100000).unwrap(),
+            [range(0x101000, 0x1000), range(0x10e000, 0x2000)]
+        );
+        assert_eq!(
+            tree.block_group_free_space(0x110000).unwrap(),
+            [range(0x111000, 0x2000)]
+        );
+        let err = tree.block_group_free_space(0x120000).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn missing_info_is_an_error() {
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![];
+        assert!(block_group_free_space(items, 0, 4096).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+// ** Inode items
+// Each file, directory, symlink... of a subvolume is an inode of its fs tree, numbered from
+// FIRST_FREE_OBJECTID (256, the top directory of the subvolume) on. Its stat data is keyed by
+// |inode number| INODE_ITEM| 0|
+// and every other item of the inode (names, extents, xattrs) shares the same objectid, so they
+// all sort right after it.
+use serde::{Deserialize, Serialize};
+
+use crate::btrees::BTree;
+use crate::btrfs::{
+    check_len, read_le_u32, read_le_u64, BtrfsKey, BtrfsTimespec, BTRFS_INODE_ITEM_KEY,
+};
+use crate::dir::{
+    BTRFS_FT_BLKDEV, BTRFS_FT_CHRDEV, BTRFS_FT_DIR, BTRFS_FT_FIFO, BTRFS_FT_REG_FILE,
+    BTRFS_FT_SOCK, BTRFS_FT_SYMLINK, BTRFS_FT_UNKNOWN,
+};
+use crate::storage::BlockStorage;
+
+// BtrfsInodeItem::mode, the file type bits as in st_mode
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFIFO: u32 = 0o010000;
+
+// BtrfsInodeItem::flags
+pub const BTRFS_INODE_NODATASUM: u64 = 1 << 0; // data has no checksums
+
+/// Stat data of an inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsInodeItem {
+    pub generation: u64,      // 0x00: transaction the inode was created in
+    pub transid: u64,         // 0x08: transaction that last changed it
+    pub size: u64,            // 0x10: file size in bytes
+    pub nbytes: u64,          // 0x18: bytes allocated to it, holes excluded
+    pub block_group: u64,     // 0x20: unused
+    pub nlink: u32,           // 0x28
+    pub uid: u32,             // 0x2c
+    pub gid: u32,             // 0x30
+    pub mode: u32,            // 0x34: file type and permissions, as in st_mode
+    pub rdev: u64,            // 0x38: device number of a device node
+    pub flags: u64,           // 0x40: BTRFS_INODE_*
+    pub sequence: u64,        // 0x48: NFS change count
+    pub atime: BtrfsTimespec, // 0x70
+    pub ctime: BtrfsTimespec, // 0x7c
+    pub mtime: BtrfsTimespec, // 0x88
+    pub otime: BtrfsTimespec, // 0x94: creation time
+}
+
+impl BtrfsInodeItem {
+    /// Size of an inode item as stored on disk, the 0x50..0x70 range is reserved
+    pub const SIZE: usize = 0xa0;
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, Self::SIZE)?;
+        Ok(BtrfsInodeItem {
+            generation: read_le_u64(buffer, 0x00),
+            transid: read_le_u64(buffer, 0x08),
+            size: read_le_u64(buffer, 0x10),
+            nbytes: read_le_u64(buffer, 0x18),
+            block_group: read_le_u64(buffer, 0x20),
+            nlink: read_le_u32(buffer, 0x28),
+            uid: read_le_u32(buffer, 0x2c),
+            gid: read_le_u32(buffer, 0x30),
+            mode: read_le_u32(buffer, 0x34),
+            rdev: read_le_u64(buffer, 0x38),
+            flags: read_le_u64(buffer, 0x40),
+            sequence: read_le_u64(buffer, 0x48),
+            atime: BtrfsTimespec::from_buffer(&buffer[0x70..])?,
+            ctime: BtrfsTimespec::from_buffer(&buffer[0x7c..])?,
+            mtime: BtrfsTimespec::from_buffer(&buffer[0x88..])?,
+            otime: BtrfsTimespec::from_buffer(&buffer[0x94..])?,
+        })
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub fn is_fifo(&self) -> bool {
+        self.mode & S_IFMT == S_IFIFO
+    }
+
+    pub fn is_socket(&self) -> bool {
+        self.mode & S_IFMT == S_IFSOCK
+    }
+
+    pub fn is_char_device(&self) -> bool {
+        self.mode & S_IFMT == S_IFCHR
+    }
+
+    pub fn is_block_device(&self) -> bool {
+        self.mode & S_IFMT == S_IFBLK
+    }
+
+    /// File type as found in directory entries (BTRFS_FT_*)
+    pub fn file_type(&self) -> u8 {
+        match self.mode & S_IFMT {
+            S_IFREG => BTRFS_FT_REG_FILE,
+            S_IFDIR => BTRFS_FT_DIR,
+            S_IFCHR => BTRFS_FT_CHRDEV,
+            S_IFBLK => BTRFS_FT_BLKDEV,
+            S_IFIFO => BTRFS_FT_FIFO,
+            S_IFSOCK => BTRFS_FT_SOCK,
+            S_IFLNK => BTRFS_FT_SYMLINK,
+            _ => BTRFS_FT_UNKNOWN,
+        }
+    }
+
+    /// Major number of a device node. rdev is stored the way the kernel keeps dev_t,
+    /// major << 20 | minor.
+    pub fn rdev_major(&self) -> u32 {
+        (self.rdev >> 20) as u32
+    }
+
+    /// Minor number of a device node, see rdev_major
+    pub fn rdev_minor(&self) -> u32 {
+        (self.rdev & 0xf_ffff) as u32
+    }
+}
+
+/// Metadata of an inode as returned by stat. Serializable, to compare files across images.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stat {
+    pub subvolume: u64, // id of the fs tree holding the inode
+    pub inode: u64,
+    pub mode: u32, // file type and permissions, as in st_mode
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub size: u64,
+    pub nbytes: u64,     // bytes allocated, holes excluded
+    pub flags: u64,      // BTRFS_INODE_*
+    pub generation: u64, // transaction the inode was created in
+    pub transid: u64,    // transaction that last changed it
+    pub atime: BtrfsTimespec,
+    pub ctime: BtrfsTimespec,
+    pub mtime: BtrfsTimespec,
+    pub otime: BtrfsTimespec, // birth time
+}
+
+impl Stat {
+    pub fn new(subvolume: u64, inode: u64, item: &BtrfsInodeItem) -> Self {
+        Stat {
+            subvolume,
+            inode,
+            mode: item.mode,
+            nlink: item.nlink,
+            uid: item.uid,
+            gid: item.gid,
+            rdev: item.rdev,
+            size: item.size,
+            nbytes: item.nbytes,
+            flags: item.flags,
+            generation: item.generation,
+            transid: item.transid,
+            atime: item.atime,
+            ctime: item.ctime,
+            mtime: item.mtime,
+            otime: item.otime,
+        }
+    }
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// Reads the INODE_ITEM of an inode of this (fs) tree, None if there is no such inode
+    pub fn read_inode(&self, inode: u64) -> Result<Option<BtrfsInodeItem>, std::io::Error> {
+        let key = BtrfsKey::new(inode, BTRFS_INODE_ITEM_KEY, 0);
+        self.search(&key)?
+            .map(|data| BtrfsInodeItem::from_buffer(&data))
+            .transpose()
+    }
+
+    /// Stat of the file at `path` of this (fs) tree, see lookup_path
+    pub fn stat(&self, path: &str) -> Result<Stat, std::io::Error> {
+        let (key, item) = self.lookup_path(path)?;
+        Ok(Stat::new(self.tree_id, key.object_id, &item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::BTRFS_DIR_ITEM_KEY;
+    use crate::dir::name_hash;
+    use crate::test_image::{dir_item, fs_tree, inode_item};
+
+    #[test]
+    fn mode_helpers_and_device_numbers() {
+        let mut buffer = inode_item(S_IFCHR | 0o620, 0);
+        buffer[0x38..0x40].copy_from_slice(&((4u64 << 20) | 64).to_le_bytes());
+        let tty = BtrfsInodeItem::from_buffer(&buffer).unwrap();
+        assert!(tty.is_char_device() && !tty.is_block_device() && !tty.is_file());
+        assert_eq!((tty.rdev_major(), tty.rdev_minor()), (4, 64));
+        assert_eq!(tty.file_type(), BTRFS_FT_CHRDEV);
+
+        for (mode, file_type) in [
+            (S_IFREG, BTRFS_FT_REG_FILE),
+            (S_IFDIR, BTRFS_FT_DIR),
+            (S_IFBLK, BTRFS_FT_BLKDEV),
+            (S_IFIFO, BTRFS_FT_FIFO),
+            (S_IFSOCK, BTRFS_FT_SOCK),
+            (S_IFLNK, BTRFS_FT_SYMLINK),
+        ] {
+            let item = BtrfsInodeItem::from_buffer(&inode_item(mode | 0o755, 0)).unwrap();
+            assert_eq!(item.file_type(), file_type);
+        }
+        let fifo = BtrfsInodeItem::from_buffer(&inode_item(S_IFIFO | 0o600, 0)).unwrap();
+        assert!(fifo.is_fifo() && !fifo.is_socket() && !fifo.is_symlink());
+    }
+
+    #[test]
+    fn stat_reports_the_inode_item() {
+        let mut file = inode_item(S_IFREG | 0o4755, 1234);
+        file[0x18..0x20].copy_from_slice(&4096u64.to_le_bytes());
+        file[0x28..0x2c].copy_from_slice(&2u32.to_le_bytes());
+        file[0x2c..0x30].copy_from_slice(&1000u32.to_le_bytes());
+        file[0x40..0x48].copy_from_slice(&BTRFS_INODE_NODATASUM.to_le_bytes());
+        // mtime 1700000000.5, otime 1600000000.123456789
+        file[0x88..0x90].copy_from_slice(&1_700_000_000u64.to_le_bytes());
+        file[0x90..0x94].copy_from_slice(&500_000_000u32.to_le_bytes());
+        file[0x94..0x9c].copy_from_slice(&1_600_000_000u64.to_le_bytes());
+        file[0x9c..0xa0].copy_from_slice(&123_456_789u32.to_le_bytes());
+        let tree = fs_tree(
+            vec![
+                (
+                    BtrfsKey::new(256, BTRFS_INODE_ITEM_KEY, 0),
+                    inode_item(S_IFDIR | 0o755, 0),
+                ),
+                (
+                    BtrfsKey::new(256, BTRFS_DIR_ITEM_KEY, name_hash(b"su")),
+                    dir_item(257, BTRFS_FT_REG_FILE, "su"),
+                ),
+                (BtrfsKey::new(257, BTRFS_INODE_ITEM_KEY, 0), file),
+            ],
+            &[],
+        );
+
+        let stat = tree.stat("/su").unwrap();
+        assert_eq!((stat.subvolume, stat.inode), (5, 257));
+        assert_eq!(stat.mode, S_IFREG | 0o4755);
+        assert_eq!(
+            (stat.size, stat.nbytes, stat.nlink, stat.uid),
+            (1234, 4096, 2, 1000)
+        );
+        assert_eq!(stat.flags, BTRFS_INODE_NODATASUM);
+        assert_eq!(
+            stat.mtime,
+            BtrfsTimespec {
+                sec: 1_700_000_000,
+                nsec: 500_000_000
+            }
+        );
+        assert_eq!(stat.otime.nsec, 123_456_789);
+        assert!(tree.stat("/").unwrap().mode & S_IFMT == S_IFDIR);
+
+        let json = serde_json::to_value(&stat).unwrap();
+        assert_eq!(json["otime"]["sec"], 1_600_000_000u64);
+        assert_eq!(json["flags"], BTRFS_INODE_NODATASUM);
+        let back: Stat = serde_json::from_value(json).unwrap();
+        assert_eq!(back, stat);
+    }
+}
@@ -0,0 +1,270 @@
+// ** Item decoding
+// decode_item picks the decoder for an item from its key type (and object id where one type is
+// shared by unrelated items) so dump tools can print any leaf. Items we don't know how to decode
+// are kept as raw bytes and printed as a hex dump, and items whose decoder rejects them are kept
+// along with the reason, so a single odd item never stops a dump.
+use std::fmt;
+
+use crate::balance::BtrfsBalanceItem;
+use crate::btrfs::{
+    read_le_u64, BtrfsKey, BtrfsSuperblock, BTRFS_BALANCE_OBJECTID, BTRFS_DEV_STATS_OBJECTID,
+    BTRFS_EXTENT_CSUM_KEY, BTRFS_FREE_SPACE_BITMAP_KEY, BTRFS_FREE_SPACE_EXTENT_KEY,
+    BTRFS_FREE_SPACE_INFO_KEY, BTRFS_ORPHAN_ITEM_KEY, BTRFS_PERSISTENT_ITEM_KEY,
+    BTRFS_QGROUP_INFO_KEY, BTRFS_QGROUP_LIMIT_KEY, BTRFS_QGROUP_RELATION_KEY,
+    BTRFS_QGROUP_STATUS_KEY, BTRFS_ROOT_ITEM_KEY, BTRFS_TEMPORARY_ITEM_KEY,
+    BTRFS_UUID_KEY_RECEIVED_SUBVOL, BTRFS_UUID_KEY_SUBVOL, BTRFS_VERITY_DESC_ITEM_KEY,
+    BTRFS_VERITY_MERKLE_ITEM_KEY,
+};
+use crate::csum::{BtrfsExtentCsum, ChecksumType};
+use crate::free_space::{BtrfsFreeSpaceBitmap, BtrfsFreeSpaceExtent, BtrfsFreeSpaceInfo};
+use crate::qgroup::{BtrfsQgroupInfo, BtrfsQgroupLimit, BtrfsQgroupRelation, BtrfsQgroupStatus};
+use crate::root_tree::BtrfsRootItem;
+use crate::uuid_tree::BtrfsUuidItem;
+use crate::verity::{BtrfsVerityChunk, BtrfsVerityDescriptorItem};
+
+/// Number of error counters in a dev stats item
+const BTRFS_DEV_STAT_VALUES_MAX: usize = 5;
+
+/// An inode (in an fs tree) or a subvolume (in the root tree) whose deletion hasn't finished.
+/// Keyed by |ORPHAN_OBJECTID| ORPHAN_ITEM| orphan objectid| with no payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsOrphanItem {
+    pub objectid: u64, // key offset
+}
+
+impl BtrfsOrphanItem {
+    pub fn from_key(key: &BtrfsKey) -> Self {
+        BtrfsOrphanItem {
+            objectid: key.offset,
+        }
+    }
+}
+
+/// Persistent I/O error counters of a device, in the device tree as
+/// |DEV_STATS_OBJECTID (0)| PERSISTENT_ITEM| devid|
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BtrfsDevStats {
+    pub devid: u64,           // key offset
+    pub write_errs: u64,      // 0x00: failed writes
+    pub read_errs: u64,       // 0x08: failed reads
+    pub flush_errs: u64,      // 0x10: failed cache flushes
+    pub corruption_errs: u64, // 0x18: checksum errors, bytenr or generation mismatches
+    pub generation_errs: u64, // 0x20: blocks with an unexpected generation
+}
+
+impl BtrfsDevStats {
+    /// Items written by older kernels may hold fewer counters, missing ones read as zero
+    pub fn from_item(key: &BtrfsKey, buffer: &[u8]) -> Self {
+        let mut values = [0u64; BTRFS_DEV_STAT_VALUES_MAX];
+        for (value, raw) in values.iter_mut().zip(buffer.chunks_exact(8)) {
+            *value = read_le_u64(raw, 0);
+        }
+        BtrfsDevStats {
+            devid: key.offset,
+            write_errs: values[0],
+            read_errs: values[1],
+            flush_errs: values[2],
+            corruption_errs: values[3],
+            generation_errs: values[4],
+        }
+    }
+}
+
+/// What decode_item needs to know about the filesystem
+#[derive(Debug, Clone, Copy)]
+pub struct ItemContext {
+    pub sectorsize: u32,
+    pub csum_type: ChecksumType,
+}
+
+impl ItemContext {
+    pub fn from_superblock(superblock: &BtrfsSuperblock) -> Result<Self, std::io::Error> {
+        Ok(ItemContext {
+            sectorsize: superblock.sectorsize,
+            csum_type: ChecksumType::from_superblock(superblock)?,
+        })
+    }
+}
+
+/// A decoded item payload
+#[derive(Debug, Clone)]
+pub enum ItemPayload {
+    FreeSpaceInfo(BtrfsFreeSpaceInfo),
+    FreeSpaceExtent(BtrfsFreeSpaceExtent),
+    FreeSpaceBitmap(BtrfsFreeSpaceBitmap),
+    QgroupStatus(BtrfsQgroupStatus),
+    QgroupInfo(BtrfsQgroupInfo),
+    QgroupLimit(BtrfsQgroupLimit),
+    QgroupRelation(BtrfsQgroupRelation),
+    Uuid(BtrfsUuidItem),
+    ExtentCsum(BtrfsExtentCsum),
+    RootItem(Box<BtrfsRootItem>),
+    Orphan(BtrfsOrphanItem),
+    DevStats(BtrfsDevStats),
+    Balance(Box<BtrfsBalanceItem>),
+    VerityDescriptor(BtrfsVerityDescriptorItem),
+    VerityDescriptorChunk(BtrfsVerityChunk),
+    VerityMerkleChunk(BtrfsVerityChunk),
+    Raw(Vec<u8>),                             // no decoder for this item
+    Invalid { error: String, data: Vec<u8> }, // the decoder rejected the payload
+}
+
+/// Decodes any item. Never fails: unknown items come back as Raw and malformed ones as Invalid.
+pub fn decode_item(key: &BtrfsKey, data: &[u8], context: &ItemContext) -> ItemPayload {
+    let decoded = match key.type_id {
+        BTRFS_FREE_SPACE_INFO_KEY => {
+            BtrfsFreeSpaceInfo::from_buffer(data).map(ItemPayload::FreeSpaceInfo)
+        }
+        BTRFS_FREE_SPACE_EXTENT_KEY => Ok(ItemPayload::FreeSpaceExtent(
+            BtrfsFreeSpaceExtent::from_key(key),
+        )),
+        BTRFS_FREE_SPACE_BITMAP_KEY => Ok(ItemPayload::FreeSpaceBitmap(
+            BtrfsFreeSpaceBitmap::from_item(key, data),
+        )),
+        BTRFS_QGROUP_STATUS_KEY => {
+            BtrfsQgroupStatus::from_buffer(data).map(ItemPayload::QgroupStatus)
+        }
+        BTRFS_QGROUP_INFO_KEY => BtrfsQgroupInfo::from_buffer(data).map(ItemPayload::QgroupInfo),
+        BTRFS_QGROUP_LIMIT_KEY => BtrfsQgroupLimit::from_buffer(data).map(ItemPayload::QgroupLimit),
+        BTRFS_QGROUP_RELATION_KEY => Ok(ItemPayload::QgroupRelation(
+            BtrfsQgroupRelation::from_key(key),
+        )),
+        BTRFS_UUID_KEY_SUBVOL | BTRFS_UUID_KEY_RECEIVED_SUBVOL => {
+            BtrfsUuidItem::from_item(key, data).map(ItemPayload::Uuid)
+        }
+        BTRFS_EXTENT_CSUM_KEY => {
+            BtrfsExtentCsum::from_item(key, data, context.sectorsize, context.csum_type)
+                .map(ItemPayload::ExtentCsum)
+        }
+        BTRFS_ROOT_ITEM_KEY => {
+            BtrfsRootItem::from_buffer(data).map(|item| ItemPayload::RootItem(Box::new(item)))
+        }
+        BTRFS_ORPHAN_ITEM_KEY => Ok(ItemPayload::Orphan(BtrfsOrphanItem::from_key(key))),
+        BTRFS_PERSISTENT_ITEM_KEY if key.object_id == BTRFS_DEV_STATS_OBJECTID => {
+            Ok(ItemPayload::DevStats(BtrfsDevStats::from_item(key, data)))
+        }
+        BTRFS_TEMPORARY_ITEM_KEY if key.object_id == BTRFS_BALANCE_OBJECTID => {
+            BtrfsBalanceItem::from_buffer(data).map(|item| ItemPayload::Balance(Box::new(item)))
+        }
+        BTRFS_VERITY_DESC_ITEM_KEY if key.offset == 0 => {
+            BtrfsVerityDescriptorItem::from_buffer(data).map(ItemPayload::VerityDescriptor)
+        }
+        BTRFS_VERITY_DESC_ITEM_KEY => Ok(ItemPayload::VerityDescriptorChunk(
+            BtrfsVerityChunk::from_item(key, data, true),
+        )),
+        BTRFS_VERITY_MERKLE_ITEM_KEY => Ok(ItemPayload::VerityMerkleChunk(
+            BtrfsVerityChunk::from_item(key, data, false),
+        )),
+        _ => Ok(ItemPayload::Raw(data.to_vec())),
+    };
+
+    decoded.unwrap_or_else(|error| ItemPayload::Invalid {
+        error: error.to_string(),
+        data: data.to_vec(),
+    })
+}
+
+/// Formats bytes as a classic hex dump: offset, 16 bytes in hex, then printable ASCII
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            line * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    out
+}
+
+impl fmt::Display for ItemPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemPayload::Raw(data) => write!(f, "{}", hex_dump(data)),
+            ItemPayload::Invalid { error, data } => {
+                writeln!(f, "invalid item: {}", error)?;
+                write!(f, "{}", hex_dump(data))
+            }
+            decoded => write!(f, "{:#?}", decoded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::BTRFS_ORPHAN_OBJECTID;
+
+    const CONTEXT: ItemContext = ItemContext {
+        sectorsize: 4096,
+        csum_type: ChecksumType::Crc32c,
+    };
+
+    #[test]
+    fn dev_stats_counters() {
+        let key = BtrfsKey::new(BTRFS_DEV_STATS_OBJECTID, BTRFS_PERSISTENT_ITEM_KEY, 1);
+        let data: Vec<u8> = [1u64, 2, 3, 4, 5]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        match decode_item(&key, &data, &CONTEXT) {
+            ItemPayload::DevStats(stats) => {
+                assert_eq!(stats.devid, 1);
+                assert_eq!(stats.read_errs, 2);
+                assert_eq!(stats.generation_errs, 5);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn orphan_items_come_from_the_key() {
+        let key = BtrfsKey::new(BTRFS_ORPHAN_OBJECTID, BTRFS_ORPHAN_ITEM_KEY, 258);
+        match decode_item(&key, &[], &CONTEXT) {
+            ItemPayload::Orphan(orphan) => assert_eq!(orphan.objectid, 258),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn balance_filters() {
+        let key = BtrfsKey::new(BTRFS_BALANCE_OBJECTID, BTRFS_TEMPORARY_ITEM_KEY, 0);
+        let mut data = vec![0u8; 0x1c0];
+        data[0..8].copy_from_slice(&crate::balance::BTRFS_BALANCE_METADATA.to_le_bytes());
+        // meta usage_min 10, usage_max 90
+        data[0x08 + 0x88 + 0x08..0x08 + 0x88 + 0x10]
+            .copy_from_slice(&((90u64 << 32) | 10).to_le_bytes());
+        match decode_item(&key, &data, &CONTEXT) {
+            ItemPayload::Balance(balance) => assert_eq!(balance.meta.usage_range(), (10, 90)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_and_malformed_items_fall_back_to_hex() {
+        let unknown = BtrfsKey::new(256, 1, 0);
+        let payload = decode_item(&unknown, b"hello", &CONTEXT);
+        assert!(matches!(payload, ItemPayload::Raw(_)));
+        let dump = payload.to_string();
+        assert!(dump.starts_with("00000000  68 65 6c 6c 6f  "));
+        assert!(dump.ends_with("  |hello|\n"));
+
+        let short = BtrfsKey::new(0, BTRFS_QGROUP_INFO_KEY, 256);
+        assert!(matches!(
+            decode_item(&short, &[0; 4], &CONTEXT),
+            ItemPayload::Invalid { .. }
+        ));
+    }
+}
@@ -1,5 +1,29 @@
+pub mod balance;
 pub mod btrees;
 pub mod btrfs;
+pub mod cache;
+pub mod chunk;
+pub mod compression;
+pub mod csum;
+pub mod devices;
+pub mod dir;
+pub mod file;
+pub mod free_space;
+pub mod inode;
+pub mod items;
+pub mod partition;
+pub mod qgroup;
+pub mod raid56;
+pub mod root_tree;
+pub mod storage;
+#[cfg(test)]
+mod test_image;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+pub mod uuid_tree;
+pub mod verity;
+pub mod view;
+pub mod xattr;
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
 }
@@ -0,0 +1,410 @@
+// ** Partition tables
+// Whole disk images start with a partition table instead of a filesystem. Two formats matter:
+// MBR: sector 0 ends with 0x55 0xaa and holds 4 primary entries at 0x1be. An extended entry
+//      points at a chain of EBRs, each describing one logical partition and the next EBR.
+// GPT: sector 0 is a protective MBR (a single entry of type 0xee), the GPT header
+//      ("EFI PART") is in LBA 1 and points at an array of partition entries.
+// Sector sizes other than 512 only exist for GPT in practice, the header is looked for in
+// LBA 1 of 512 and 4096 byte sectors.
+use std::sync::Arc;
+
+use crate::btrees::{BTree, BlockDevice, BTRFS_SUPER_INFO_OFFSET, BTRFS_SUPER_INFO_SIZE};
+use crate::btrfs::{check_len, read_le_u32, read_le_u64};
+use crate::storage::BlockStorage;
+
+const MBR_SIZE: usize = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES_OFFSET: usize = 0x1be;
+const MBR_ENTRY_SIZE: usize = 0x10;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Bound on the size of the GPT entry array (128 entries of 128 bytes is the usual)
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+/// Bound on the length of an EBR chain, guarding against loops
+const MBR_MAX_LOGICAL: usize = 128;
+
+// MBR partition types
+pub const MBR_TYPE_EMPTY: u8 = 0x00;
+pub const MBR_TYPE_EXTENDED_CHS: u8 = 0x05;
+pub const MBR_TYPE_EXTENDED_LBA: u8 = 0x0f;
+pub const MBR_TYPE_LINUX_EXTENDED: u8 = 0x85;
+pub const MBR_TYPE_LINUX: u8 = 0x83;
+pub const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt([u8; 16]), // type GUID as stored on disk (mixed endian)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub number: u32, // 1 based, as in /dev/sdaN. MBR logical partitions start at 5
+    pub start: u64,  // offset in bytes
+    pub length: u64, // in bytes
+    pub partition_type: PartitionType,
+    pub name: String, // GPT partition name, empty for MBR
+}
+
+/// Reads the partition table of a disk. Returns an empty list when there is none (e.g. the
+/// storage holds a bare filesystem).
+pub fn read_partitions<S: BlockStorage>(storage: &S) -> Result<Vec<Partition>, std::io::Error> {
+    let size = storage.size()?;
+    if size < MBR_SIZE as u64 {
+        return Ok(Vec::new());
+    }
+    let mut mbr = [0u8; MBR_SIZE];
+    storage.read_at(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    if mbr_entries(&mbr)
+        .iter()
+        .any(|entry| entry.0 == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        for sector_size in [512u64, 4096] {
+            if let Some(partitions) = read_gpt(storage, sector_size)? {
+                return Ok(partitions);
+            }
+        }
+    }
+    read_mbr(storage, &mbr)
+}
+
+/// (type, first sector, number of sectors) of the 4 entries of an MBR or EBR
+fn mbr_entries(sector: &[u8]) -> [(u8, u64, u64); 4] {
+    std::array::from_fn(|idx| {
+        let entry = &sector[MBR_ENTRIES_OFFSET + idx * MBR_ENTRY_SIZE..];
+        (
+            entry[0x04],
+            read_le_u32(entry, 0x08) as u64,
+            read_le_u32(entry, 0x0c) as u64,
+        )
+    })
+}
+
+fn is_extended(partition_type: u8) -> bool {
+    matches!(
+        partition_type,
+        MBR_TYPE_EXTENDED_CHS | MBR_TYPE_EXTENDED_LBA | MBR_TYPE_LINUX_EXTENDED
+    )
+}
+
+fn read_mbr<S: BlockStorage>(storage: &S, mbr: &[u8]) -> Result<Vec<Partition>, std::io::Error> {
+    let sector = MBR_SIZE as u64;
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (idx, (partition_type, first, count)) in mbr_entries(mbr).into_iter().enumerate() {
+        if partition_type == MBR_TYPE_EMPTY || count == 0 {
+            continue;
+        }
+        if is_extended(partition_type) {
+            extended = Some(first);
+            continue;
+        }
+        partitions.push(Partition {
+            number: idx as u32 + 1,
+            start: first * sector,
+            length: count * sector,
+            partition_type: PartitionType::Mbr(partition_type),
+            name: String::new(),
+        });
+    }
+
+    // logical partitions: each EBR describes one partition relative to itself, and the next
+    // EBR relative to the start of the extended partition. An EBR that can't be read (e.g.
+    // past the end of a truncated image) ends the chain, the partitions found so far are kept.
+    if let Some(extended_start) = extended {
+        let mut ebr_sector = extended_start;
+        for number in 5..5 + MBR_MAX_LOGICAL as u32 {
+            let mut ebr = [0u8; MBR_SIZE];
+            if storage.read_at(ebr_sector * sector, &mut ebr).is_err()
+                || ebr[510..512] != MBR_SIGNATURE
+            {
+                break;
+            }
+            let [(partition_type, first, count), (next_type, next, _), ..] = mbr_entries(&ebr);
+            if partition_type != MBR_TYPE_EMPTY && count != 0 {
+                partitions.push(Partition {
+                    number,
+                    start: (ebr_sector + first) * sector,
+                    length: count * sector,
+                    partition_type: PartitionType::Mbr(partition_type),
+                    name: String::new(),
+                });
+            }
+            if next_type == MBR_TYPE_EMPTY || next == 0 {
+                break;
+            }
+            ebr_sector = extended_start + next;
+        }
+    }
+    Ok(partitions)
+}
+
+/// Reads a GPT assuming `sector_size`, None if there is no GPT header at LBA 1
+fn read_gpt<S: BlockStorage>(
+    storage: &S,
+    sector_size: u64,
+) -> Result<Option<Vec<Partition>>, std::io::Error> {
+    let mut header = [0u8; 0x5c];
+    if storage.read_at(sector_size, &mut header).is_err() || &header[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let entries_lba = read_le_u64(&header, 0x48);
+    let entry_count = read_le_u32(&header, 0x50) as usize;
+    let entry_size = read_le_u32(&header, 0x54) as usize;
+    let entries_size = entry_count.saturating_mul(entry_size);
+    if entry_size < 0x80 || entries_size > GPT_MAX_ENTRIES_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "GPT header with {} entries of {} bytes",
+                entry_count, entry_size
+            ),
+        ));
+    }
+    let entries_offset = entries_lba.checked_mul(sector_size).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("GPT entries at LBA {} are out of range", entries_lba),
+        )
+    })?;
+    let mut entries = vec![0u8; entries_size];
+    storage.read_at(entries_offset, &mut entries)?;
+
+    let mut partitions = Vec::new();
+    for (idx, entry) in entries.chunks_exact(entry_size).enumerate() {
+        check_len(entry, 0x80)?;
+        let type_guid: [u8; 16] = entry[0x00..0x10].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first = read_le_u64(entry, 0x20);
+        let last = read_le_u64(entry, 0x28); // inclusive
+        let Some(sectors) = last.checked_sub(first) else {
+            continue;
+        };
+        let start = first.checked_mul(sector_size);
+        let length = sectors
+            .checked_add(1)
+            .and_then(|sectors| sectors.checked_mul(sector_size));
+        let (Some(start), Some(length)) = (start, length) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "GPT entry {} spans LBA {} to {}, out of range",
+                    idx + 1,
+                    first,
+                    last
+                ),
+            ));
+        };
+        let name: Vec<u16> = entry[0x38..0x80]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        partitions.push(Partition {
+            number: idx as u32 + 1,
+            start,
+            length,
+            partition_type: PartitionType::Gpt(type_guid),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Ok(Some(partitions))
+}
+
+/// Whether the window holds a btrfs superblock (only the magic is checked)
+pub fn has_btrfs_magic<S: BlockStorage>(
+    storage: &S,
+    start: u64,
+    length: u64,
+) -> Result<bool, std::io::Error> {
+    // partitions running past the end of a truncated image are skipped, not errors
+    let storage_size = storage.size()?;
+    let fits = start
+        .checked_add(length)
+        .is_some_and(|end| end <= storage_size);
+    if !fits || length < BTRFS_SUPER_INFO_OFFSET + BTRFS_SUPER_INFO_SIZE as u64 {
+        return Ok(false);
+    }
+    let mut magic = [0u8; 8];
+    storage.read_at(start + BTRFS_SUPER_INFO_OFFSET + 0x40, &mut magic)?;
+    Ok(&magic == b"_BHRfS_M")
+}
+
+/// Lists the partitions of a disk image holding a btrfs filesystem
+pub fn find_btrfs_partitions<S: BlockStorage>(
+    storage: &S,
+) -> Result<Vec<Partition>, std::io::Error> {
+    let mut found = Vec::new();
+    for partition in read_partitions(storage)? {
+        if has_btrfs_magic(storage, partition.start, partition.length)? {
+            found.push(partition);
+        }
+    }
+    Ok(found)
+}
+
+/// A btrfs partition and the result of opening its filesystem
+pub type OpenedPartition<S> = (Partition, Result<BTree<S>, std::io::Error>);
+
+/// Opens every btrfs filesystem found in the partitions of a disk image. They all share the
+/// storage. A filesystem that fails to open doesn't hide the others, each partition comes with
+/// its own result.
+pub fn open_btrfs_partitions<S: BlockStorage>(
+    storage: &Arc<S>,
+) -> Result<Vec<OpenedPartition<S>>, std::io::Error> {
+    let partitions = find_btrfs_partitions(storage.as_ref())?;
+    Ok(partitions
+        .into_iter()
+        .map(|partition| {
+            let tree = BlockDevice::from_shared_storage_window(
+                storage.clone(),
+                partition.start,
+                partition.length,
+            )
+            .and_then(BTree::from_device);
+            (partition, tree)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::{leaf, TestImage, CHUNK_LOGICAL, CHUNK_PHYSICAL};
+
+    const MIB: u64 = 1 << 20;
+
+    fn btrfs_image() -> Vec<u8> {
+        let root = CHUNK_LOGICAL + 0x1000;
+        let mut image = TestImage::new(root, CHUNK_LOGICAL);
+        image.put_node(root, &leaf(root, 1, &[]));
+        image.into_image()
+    }
+
+    fn mbr_entry(disk: &mut [u8], sector: usize, idx: usize, kind: u8, first: u32, count: u32) {
+        let entry = sector * 512 + MBR_ENTRIES_OFFSET + idx * MBR_ENTRY_SIZE;
+        disk[entry + 4] = kind;
+        disk[entry + 8..entry + 12].copy_from_slice(&first.to_le_bytes());
+        disk[entry + 12..entry + 16].copy_from_slice(&count.to_le_bytes());
+        disk[sector * 512 + 510..sector * 512 + 512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    #[test]
+    fn finds_btrfs_in_a_gpt_disk() {
+        let fs = btrfs_image();
+        let mut disk = vec![0u8; (MIB as usize) + fs.len() + 0x10000];
+        disk[MIB as usize..MIB as usize + fs.len()].copy_from_slice(&fs);
+        let sectors = (disk.len() / 512) as u32;
+        mbr_entry(&mut disk, 0, 0, MBR_TYPE_GPT_PROTECTIVE, 1, sectors - 1);
+
+        disk[512..520].copy_from_slice(GPT_SIGNATURE);
+        disk[512 + 0x48..512 + 0x50].copy_from_slice(&2u64.to_le_bytes());
+        disk[512 + 0x50..512 + 0x54].copy_from_slice(&128u32.to_le_bytes());
+        disk[512 + 0x54..512 + 0x58].copy_from_slice(&128u32.to_le_bytes());
+        // entry 1: empty filler partition, entry 2: the filesystem
+        for (idx, first, last) in [
+            (0usize, 34u64, 2047u64),
+            (1, 2048, 2047 + fs.len() as u64 / 512),
+        ] {
+            let entry = 1024 + idx * 128;
+            disk[entry] = 0x0f; // any non zero type GUID
+            disk[entry + 0x20..entry + 0x28].copy_from_slice(&first.to_le_bytes());
+            disk[entry + 0x28..entry + 0x30].copy_from_slice(&last.to_le_bytes());
+        }
+        disk[1024 + 128 + 0x38] = b'f';
+        disk[1024 + 128 + 0x3a] = b's';
+
+        let partitions = read_partitions(&disk).unwrap();
+        assert_eq!(partitions.len(), 2);
+        let found = find_btrfs_partitions(&disk).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].number, found[0].start), (2, MIB));
+        assert_eq!(found[0].length, fs.len() as u64);
+        assert_eq!(found[0].name, "fs");
+
+        let opened = open_btrfs_partitions(&Arc::new(disk.clone())).unwrap();
+        let tree = opened[0].1.as_ref().unwrap();
+        assert_eq!(tree.devices.device(1).unwrap().start, MIB);
+        assert_eq!(tree.superblock.generation, 1);
+
+        // entries ending before they start are skipped, sizes that overflow are rejected
+        let mut reversed = disk.clone();
+        reversed[1024 + 0x20..1024 + 0x28].copy_from_slice(&4096u64.to_le_bytes());
+        assert_eq!(read_partitions(&reversed).unwrap().len(), 1);
+        let mut huge = disk.clone();
+        huge[1024 + 0x28..1024 + 0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        let err = read_partitions(&huge).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let mut far = disk;
+        far[512 + 0x48..512 + 0x50].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            read_partitions(&far).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn finds_btrfs_in_an_mbr_logical_partition() {
+        let fs = btrfs_image();
+        let fs_sectors = (fs.len() / 512) as u32;
+        let mut disk = vec![0u8; 3 * MIB as usize + fs.len()];
+        // primary 1 at 1MiB (not btrfs), extended at 1MiB + 2048 sectors holding one logical
+        mbr_entry(&mut disk, 0, 0, MBR_TYPE_LINUX, 2048, 16);
+        mbr_entry(
+            &mut disk,
+            0,
+            1,
+            MBR_TYPE_EXTENDED_LBA,
+            4096,
+            fs_sectors + 64,
+        );
+        mbr_entry(&mut disk, 4096, 0, MBR_TYPE_LINUX, 63, fs_sectors);
+        let start = (4096 + 63) * 512;
+        disk[start..start + fs.len()].copy_from_slice(&fs);
+
+        let partitions = read_partitions(&disk).unwrap();
+        let numbers: Vec<_> = partitions.iter().map(|p| p.number).collect();
+        assert_eq!(numbers, [1, 5]);
+        let found = find_btrfs_partitions(&disk).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].number, found[0].start), (5, start as u64));
+
+        // a bare filesystem has no partition table
+        assert!(read_partitions(&fs).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_corrupt_partition_does_not_hide_the_others() {
+        let fs = btrfs_image();
+        let mut corrupt = fs.clone();
+        corrupt[(CHUNK_PHYSICAL + 0x1800) as usize] ^= 0xff; // root tree block
+        let fs_sectors = (fs.len() / 512) as u32;
+        let second = 2048 + fs_sectors;
+        let mut disk = vec![0u8; (second as usize + fs_sectors as usize) * 512];
+        mbr_entry(&mut disk, 0, 0, MBR_TYPE_LINUX, 2048, fs_sectors);
+        mbr_entry(&mut disk, 0, 1, MBR_TYPE_LINUX, second, fs_sectors);
+        // the EBR is past the end of the truncated image
+        mbr_entry(&mut disk, 0, 2, MBR_TYPE_EXTENDED_LBA, 1 << 30, 64);
+        disk[MIB as usize..MIB as usize + fs.len()].copy_from_slice(&corrupt);
+        let start = second as usize * 512;
+        disk[start..start + fs.len()].copy_from_slice(&fs);
+
+        let numbers: Vec<_> = read_partitions(&disk)
+            .unwrap()
+            .iter()
+            .map(|p| p.number)
+            .collect();
+        assert_eq!(numbers, [1, 2]);
+
+        let opened = open_btrfs_partitions(&Arc::new(disk)).unwrap();
+        assert_eq!(opened.len(), 2);
+        let err = opened[0].1.as_ref().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(opened[1].0.number, 2);
+        assert_eq!(opened[1].1.as_ref().unwrap().superblock.generation, 1);
+    }
+}
@@ -0,0 +1,369 @@
+// ** Quota tree
+// The quota tree (objectid 8 in the tree of tree roots) tracks how much space each qgroup uses.
+// A qgroup id is split in two: the upper 16 bits are the level and the lower 48 bits the id.
+// Level 0 qgroups are created automatically for every subvolume and use the subvolume id,
+// higher levels are user defined groups of qgroups.
+// |0| QGROUP_STATUS| 0|            global quota state
+// |0| QGROUP_INFO| qgroupid|       referenced/exclusive bytes
+// |0| QGROUP_LIMIT| qgroupid|      configured limits
+// |child| QGROUP_RELATION| parent| and |parent| QGROUP_RELATION| child|, no payload
+use crate::btrees::BTree;
+use crate::btrfs::{
+    check_len, read_le_u64, BtrfsKey, BTRFS_QGROUP_INFO_KEY, BTRFS_QGROUP_LIMIT_KEY,
+    BTRFS_QGROUP_RELATION_KEY, BTRFS_QGROUP_STATUS_KEY, BTRFS_QUOTA_TREE_OBJECTID,
+};
+use crate::storage::BlockStorage;
+
+// BtrfsQgroupStatus::flags
+pub const BTRFS_QGROUP_STATUS_FLAG_ON: u64 = 1 << 0;
+pub const BTRFS_QGROUP_STATUS_FLAG_RESCAN: u64 = 1 << 1;
+pub const BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT: u64 = 1 << 2;
+pub const BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE: u64 = 1 << 3;
+
+// BtrfsQgroupLimit::flags, a limit only applies when its bit is set
+pub const BTRFS_QGROUP_LIMIT_MAX_RFER: u64 = 1 << 0;
+pub const BTRFS_QGROUP_LIMIT_MAX_EXCL: u64 = 1 << 1;
+pub const BTRFS_QGROUP_LIMIT_RSV_RFER: u64 = 1 << 2;
+pub const BTRFS_QGROUP_LIMIT_RSV_EXCL: u64 = 1 << 3;
+
+/// A qgroup id split into its level and id parts, displayed as `level/id` like btrfs-progs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QgroupId {
+    pub level: u16,
+    pub id: u64,
+}
+
+impl QgroupId {
+    pub fn from_raw(raw: u64) -> Self {
+        QgroupId {
+            level: (raw >> 48) as u16,
+            id: raw & ((1 << 48) - 1),
+        }
+    }
+
+    pub fn to_raw(self) -> u64 {
+        ((self.level as u64) << 48) | self.id
+    }
+}
+
+impl std::fmt::Display for QgroupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.level, self.id)
+    }
+}
+
+/// Global quota state
+#[derive(Debug, Clone)]
+pub struct BtrfsQgroupStatus {
+    pub version: u64,
+    pub generation: u64,         // transaction that last wrote the status
+    pub flags: u64,              // BTRFS_QGROUP_STATUS_FLAG_*
+    pub rescan: u64,             // progress of a running rescan (next objectid to scan)
+    pub enable_gen: Option<u64>, // generation quotas were enabled in, simple quota mode only
+}
+
+/// Usage of a qgroup. The cmpr fields are never different from the plain ones in practice.
+#[derive(Debug, Clone)]
+pub struct BtrfsQgroupInfo {
+    pub generation: u64,
+    pub referenced: u64, // bytes reachable from the qgroup
+    pub referenced_compressed: u64,
+    pub exclusive: u64, // bytes reachable only from the qgroup
+    pub exclusive_compressed: u64,
+}
+
+/// Limits of a qgroup
+#[derive(Debug, Clone)]
+pub struct BtrfsQgroupLimit {
+    pub flags: u64, // BTRFS_QGROUP_LIMIT_*
+    pub max_referenced: u64,
+    pub max_exclusive: u64,
+    pub rsv_referenced: u64,
+    pub rsv_exclusive: u64,
+}
+
+/// Membership of a qgroup in a higher level qgroup. Described entirely by the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsQgroupRelation {
+    pub src: QgroupId, // key object_id
+    pub dst: QgroupId, // key offset
+}
+
+impl BtrfsQgroupStatus {
+    /// Deserializes a QGROUP_STATUS payload, 0x20 bytes or 0x28 with enable_gen
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x20)?;
+        Ok(BtrfsQgroupStatus {
+            version: read_le_u64(buffer, 0x00),
+            generation: read_le_u64(buffer, 0x08),
+            flags: read_le_u64(buffer, 0x10),
+            rescan: read_le_u64(buffer, 0x18),
+            enable_gen: (buffer.len() >= 0x28).then(|| read_le_u64(buffer, 0x20)),
+        })
+    }
+
+    /// Quotas are marked inconsistent until a rescan completes, and a running rescan is still
+    /// recounting; in both cases the numbers can't be trusted
+    pub fn is_consistent(&self) -> bool {
+        self.flags & (BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT | BTRFS_QGROUP_STATUS_FLAG_RESCAN) == 0
+    }
+}
+
+impl BtrfsQgroupInfo {
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x28)?;
+        Ok(BtrfsQgroupInfo {
+            generation: read_le_u64(buffer, 0x00),
+            referenced: read_le_u64(buffer, 0x08),
+            referenced_compressed: read_le_u64(buffer, 0x10),
+            exclusive: read_le_u64(buffer, 0x18),
+            exclusive_compressed: read_le_u64(buffer, 0x20),
+        })
+    }
+}
+
+impl BtrfsQgroupLimit {
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x28)?;
+        Ok(BtrfsQgroupLimit {
+            flags: read_le_u64(buffer, 0x00),
+            max_referenced: read_le_u64(buffer, 0x08),
+            max_exclusive: read_le_u64(buffer, 0x10),
+            rsv_referenced: read_le_u64(buffer, 0x18),
+            rsv_exclusive: read_le_u64(buffer, 0x20),
+        })
+    }
+
+    /// The referenced limit, if one is set
+    pub fn referenced_limit(&self) -> Option<u64> {
+        (self.flags & BTRFS_QGROUP_LIMIT_MAX_RFER != 0).then_some(self.max_referenced)
+    }
+
+    /// The exclusive limit, if one is set
+    pub fn exclusive_limit(&self) -> Option<u64> {
+        (self.flags & BTRFS_QGROUP_LIMIT_MAX_EXCL != 0).then_some(self.max_exclusive)
+    }
+}
+
+impl BtrfsQgroupRelation {
+    pub fn from_key(key: &BtrfsKey) -> Self {
+        BtrfsQgroupRelation {
+            src: QgroupId::from_raw(key.object_id),
+            dst: QgroupId::from_raw(key.offset),
+        }
+    }
+
+    /// Relations are stored twice, once from each side. This is the child -> parent copy.
+    pub fn is_child_to_parent(&self) -> bool {
+        self.src.level < self.dst.level
+    }
+}
+
+/// Quota usage and limits of a subvolume (its level 0 qgroup)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubvolumeQuota {
+    pub subvolume_id: u64,
+    pub referenced: u64,
+    pub exclusive: u64,
+    pub max_referenced: Option<u64>,
+    pub max_exclusive: Option<u64>,
+    pub parents: Vec<QgroupId>, // higher level qgroups this subvolume belongs to
+}
+
+/// Contents of the quota tree: the global state and the per subvolume rows
+#[derive(Debug, Clone)]
+pub struct QuotaReport {
+    pub status: Option<BtrfsQgroupStatus>, // None when the QGROUP_STATUS item is missing
+    pub subvolumes: Vec<SubvolumeQuota>,
+}
+
+impl QuotaReport {
+    /// Whether the subvolume numbers can be trusted. A missing status item counts as
+    /// inconsistent.
+    pub fn is_consistent(&self) -> bool {
+        self.status
+            .as_ref()
+            .is_some_and(BtrfsQgroupStatus::is_consistent)
+    }
+}
+
+/// Reports referenced and exclusive bytes with their limits for every subvolume that has a
+/// level 0 qgroup, ordered by subvolume id.
+///
+/// `items` are the (key, payload) pairs of the quota tree in key order.
+pub fn subvolume_quotas<'a, I>(items: I) -> Result<Vec<SubvolumeQuota>, std::io::Error>
+where
+    I: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    let mut quotas: std::collections::BTreeMap<u64, SubvolumeQuota> =
+        std::collections::BTreeMap::new();
+    let entry = |id: u64| SubvolumeQuota {
+        subvolume_id: id,
+        referenced: 0,
+        exclusive: 0,
+        max_referenced: None,
+        max_exclusive: None,
+        parents: Vec::new(),
+    };
+
+    for (key, data) in items {
+        match key.type_id {
+            BTRFS_QGROUP_INFO_KEY | BTRFS_QGROUP_LIMIT_KEY => {
+                let qgroupid = QgroupId::from_raw(key.offset);
+                if qgroupid.level != 0 {
+                    continue;
+                }
+                let quota = quotas
+                    .entry(qgroupid.id)
+                    .or_insert_with(|| entry(qgroupid.id));
+                if key.type_id == BTRFS_QGROUP_INFO_KEY {
+                    let info = BtrfsQgroupInfo::from_buffer(data)?;
+                    quota.referenced = info.referenced;
+                    quota.exclusive = info.exclusive;
+                } else {
+                    let limit = BtrfsQgroupLimit::from_buffer(data)?;
+                    quota.max_referenced = limit.referenced_limit();
+                    quota.max_exclusive = limit.exclusive_limit();
+                }
+            }
+            BTRFS_QGROUP_RELATION_KEY => {
+                let relation = BtrfsQgroupRelation::from_key(key);
+                if relation.src.level != 0 || !relation.is_child_to_parent() {
+                    continue;
+                }
+                quotas
+                    .entry(relation.src.id)
+                    .or_insert_with(|| entry(relation.src.id))
+                    .parents
+                    .push(relation.dst);
+            }
+            _ => continue,
+        }
+    }
+    Ok(quotas.into_values().collect())
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// Reads the quota tree of this tree, the tree of tree roots. Fails with NotFound when
+    /// quotas were never enabled.
+    pub fn quota_report(&self) -> Result<QuotaReport, std::io::Error> {
+        let quota_tree = self.open_tree(BTRFS_QUOTA_TREE_OBJECTID)?;
+        let status = quota_tree
+            .search(&BtrfsKey::new(0, BTRFS_QGROUP_STATUS_KEY, 0))?
+            .map(|data| BtrfsQgroupStatus::from_buffer(&data))
+            .transpose()?;
+        let items = quota_tree.items_in_range(
+            &BtrfsKey::new(0, 0, 0),
+            &BtrfsKey::new(u64::MAX, u8::MAX, u64::MAX),
+        )?;
+        let subvolumes = subvolume_quotas(items.iter().map(|(key, data)| (key, data.as_slice())))?;
+        Ok(QuotaReport { status, subvolumes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::root_tree;
+
+    fn info(referenced: u64, exclusive: u64) -> Vec<u8> {
+        [1, referenced, referenced, exclusive, exclusive]
+            .iter()
+            .flat_map(|v: &u64| v.to_le_bytes())
+            .collect()
+    }
+
+    fn status(flags: u64) -> Vec<u8> {
+        [1, 7, flags, 0]
+            .iter()
+            .flat_map(|v: &u64| v.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn quota_report_from_the_quota_tree() {
+        let status_key = BtrfsKey::new(0, BTRFS_QGROUP_STATUS_KEY, 0);
+        let info_key = BtrfsKey::new(0, BTRFS_QGROUP_INFO_KEY, 256);
+        let quota_tree = |flags| {
+            root_tree(
+                Vec::new(),
+                vec![(
+                    BTRFS_QUOTA_TREE_OBJECTID,
+                    vec![
+                        (status_key.clone(), status(flags)),
+                        (info_key.clone(), info(8192, 4096)),
+                    ],
+                )],
+            )
+        };
+
+        let report = quota_tree(BTRFS_QGROUP_STATUS_FLAG_ON)
+            .quota_report()
+            .unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.status.unwrap().generation, 7);
+        assert_eq!(report.subvolumes.len(), 1);
+        assert_eq!(report.subvolumes[0].subvolume_id, 256);
+        assert_eq!(report.subvolumes[0].referenced, 8192);
+
+        for flag in [
+            BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT,
+            BTRFS_QGROUP_STATUS_FLAG_RESCAN,
+        ] {
+            let report = quota_tree(BTRFS_QGROUP_STATUS_FLAG_ON | flag)
+                .quota_report()
+                .unwrap();
+            assert!(!report.is_consistent());
+            assert_eq!(report.subvolumes.len(), 1);
+        }
+
+        // quotas never enabled
+        let err = root_tree(Vec::new(), Vec::new())
+            .quota_report()
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn qgroup_id_split() {
+        let id = QgroupId::from_raw((1 << 48) | 5);
+        assert_eq!(id, QgroupId { level: 1, id: 5 });
+        assert_eq!(id.to_raw(), (1 << 48) | 5);
+        assert_eq!(id.to_string(), "1/5");
+    }
+
+    #[test]
+    fn subvolume_usage_and_limits() {
+        let info_256 = BtrfsKey::new(0, BTRFS_QGROUP_INFO_KEY, 256);
+        let info_group = BtrfsKey::new(0, BTRFS_QGROUP_INFO_KEY, 1 << 48);
+        let limit_256 = BtrfsKey::new(0, BTRFS_QGROUP_LIMIT_KEY, 256);
+        let rel_up = BtrfsKey::new(256, BTRFS_QGROUP_RELATION_KEY, 1 << 48);
+        let rel_down = BtrfsKey::new(1 << 48, BTRFS_QGROUP_RELATION_KEY, 256);
+        let info_256_data = info(8192, 4096);
+        let info_group_data = info(1, 1);
+        let limit_256_data: Vec<u8> = [BTRFS_QGROUP_LIMIT_MAX_RFER, 1 << 30, 7, 0, 0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+
+        let items: Vec<(&BtrfsKey, &[u8])> = vec![
+            (&info_256, &info_256_data),
+            (&info_group, &info_group_data),
+            (&limit_256, &limit_256_data),
+            (&rel_up, &[]),
+            (&rel_down, &[]),
+        ];
+        let quotas = subvolume_quotas(items).unwrap();
+        assert_eq!(
+            quotas,
+            vec![SubvolumeQuota {
+                subvolume_id: 256,
+                referenced: 8192,
+                exclusive: 4096,
+                max_referenced: Some(1 << 30),
+                max_exclusive: None,
+                parents: vec![QgroupId { level: 1, id: 0 }],
+            }]
+        );
+    }
+}
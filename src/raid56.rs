@@ -0,0 +1,241 @@
+// ** RAID5/6 parity
+// A RAID5/6 chunk is cut in rows of stripe_len bytes per device. Each row holds nr_data data
+// stripes followed by P (and Q for RAID6), rotated by one device per row (see
+// ChunkMap::parity_row). Parity is computed byte by byte over the data stripes of a row:
+//   P = D0 ^ D1 ^ ... ^ Dn-1
+//   Q = g^0.D0 ^ g^1.D1 ^ ... ^ g^n-1.Dn-1   in GF(2^8) with g = 2, polynomial 0x11d
+// which is what Linux' raid6 library writes. Any one stripe of a row can be rebuilt from P,
+// any two from P and Q.
+
+/// 2^i in GF(2^8), twice over so that a product never needs a modulo
+const GF_EXP: [u8; 512] = gf_exp_table();
+/// Inverse of GF_EXP, GF_LOG[0] is unused
+const GF_LOG: [u8; 256] = gf_log_table();
+
+const fn gf_exp_table() -> [u8; 512] {
+    let mut table = [0u8; 512];
+    let mut value: u16 = 1;
+    let mut i = 0;
+    while i < 512 {
+        table[i] = value as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+        i += 1;
+    }
+    table
+}
+
+const fn gf_log_table() -> [u8; 256] {
+    let exp = gf_exp_table();
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        table[exp[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+pub fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+}
+
+/// a / b, b must not be 0
+pub fn gf_div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + 255 - GF_LOG[b as usize] as usize]
+}
+
+/// g^i
+fn gf_pow2(i: usize) -> u8 {
+    GF_EXP[i % 255]
+}
+
+/// P of the data stripes of a row
+pub fn parity_p(data: &[&[u8]]) -> Vec<u8> {
+    let mut p = vec![0u8; data.first().map_or(0, |stripe| stripe.len())];
+    for stripe in data {
+        p.iter_mut().zip(*stripe).for_each(|(p, d)| *p ^= d);
+    }
+    p
+}
+
+/// Q of the data stripes of a row
+pub fn parity_q(data: &[&[u8]]) -> Vec<u8> {
+    let mut q = vec![0u8; data.first().map_or(0, |stripe| stripe.len())];
+    for (idx, stripe) in data.iter().enumerate() {
+        let coef = gf_pow2(idx);
+        q.iter_mut()
+            .zip(*stripe)
+            .for_each(|(q, &d)| *q ^= gf_mul(coef, d));
+    }
+    q
+}
+
+/// Fills in the missing (None) stripes of a row laid out as [D0, .., Dn-1, P] for RAID5 or
+/// [D0, .., Dn-1, P, Q] for RAID6. All present stripes must have the same length. Fails when
+/// more stripes are missing than there is parity for.
+pub fn rebuild(row: &mut [Option<Vec<u8>>], nr_data: usize) -> Result<(), std::io::Error> {
+    let nr_parity = row.len().saturating_sub(nr_data);
+    if nr_data == 0 || !(1..=2).contains(&nr_parity) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "can't rebuild a row of {} stripes with {} data",
+                row.len(),
+                nr_data
+            ),
+        ));
+    }
+    let missing: Vec<usize> = (0..row.len()).filter(|&idx| row[idx].is_none()).collect();
+    if missing.len() > nr_parity {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{} of {} stripes missing, parity covers {}",
+                missing.len(),
+                row.len(),
+                nr_parity
+            ),
+        ));
+    }
+    let len = row.iter().flatten().map(Vec::len).next().unwrap_or(0);
+    let (p_idx, q_idx) = (nr_data, nr_data + 1);
+    let missing_data: Vec<usize> = missing
+        .iter()
+        .copied()
+        .filter(|&idx| idx < nr_data)
+        .collect();
+
+    match missing_data[..] {
+        [] => {}
+        [x] if row[p_idx].is_some() => {
+            let others: Vec<&[u8]> = (0..=nr_data)
+                .filter(|&idx| idx != x)
+                .map(|idx| row[idx].as_deref().unwrap())
+                .collect();
+            row[x] = Some(parity_p(&others));
+        }
+        [x] => {
+            // P is gone too, Q = g^x.Dx ^ (Q of the others)
+            let mut q = q_without(row, nr_data, &[x], len);
+            let coef = gf_pow2(x);
+            q.iter_mut().for_each(|byte| *byte = gf_div(*byte, coef));
+            row[x] = Some(q);
+        }
+        [x, y] => {
+            // Dx ^ Dy = pxy and g^x.Dx ^ g^y.Dy = qxy, so
+            // Dx = (qxy ^ g^y.pxy) / (g^x ^ g^y) and Dy = pxy ^ Dx
+            let others: Vec<&[u8]> = (0..=nr_data)
+                .filter(|&idx| idx != x && idx != y)
+                .map(|idx| row[idx].as_deref().unwrap())
+                .collect();
+            let pxy = parity_p(&others);
+            let qxy = q_without(row, nr_data, &[x, y], len);
+            let (gx, gy) = (gf_pow2(x), gf_pow2(y));
+            let denominator = gx ^ gy;
+            let dx: Vec<u8> = pxy
+                .iter()
+                .zip(&qxy)
+                .map(|(&p, &q)| gf_div(q ^ gf_mul(gy, p), denominator))
+                .collect();
+            let dy: Vec<u8> = pxy.iter().zip(&dx).map(|(p, d)| p ^ d).collect();
+            row[x] = Some(dx);
+            row[y] = Some(dy);
+        }
+        _ => unreachable!("at most two stripes are missing"),
+    }
+
+    let data: Vec<&[u8]> = row[..nr_data]
+        .iter()
+        .map(|d| d.as_deref().unwrap())
+        .collect();
+    let p = row[p_idx].is_none().then(|| parity_p(&data));
+    let q = (nr_parity == 2 && row[q_idx].is_none()).then(|| parity_q(&data));
+    if let Some(p) = p {
+        row[p_idx] = Some(p);
+    }
+    if let Some(q) = q {
+        row[q_idx] = Some(q);
+    }
+    Ok(())
+}
+
+/// Q with the contribution of every data stripe but `skip` removed, which leaves the sum of
+/// g^i.Di over the skipped ones
+fn q_without(row: &[Option<Vec<u8>>], nr_data: usize, skip: &[usize], len: usize) -> Vec<u8> {
+    let mut q = row[nr_data + 1].clone().unwrap_or_else(|| vec![0; len]);
+    for idx in (0..nr_data).filter(|idx| !skip.contains(idx)) {
+        let coef = gf_pow2(idx);
+        let data = row[idx].as_deref().unwrap();
+        q.iter_mut()
+            .zip(data)
+            .for_each(|(q, &d)| *q ^= gf_mul(coef, d));
+    }
+    q
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_row(raid6: bool) -> Vec<Option<Vec<u8>>> {
+        let data: Vec<Vec<u8>> = (0..4u8)
+            .map(|idx| {
+                (0..64u8)
+                    .map(|b| b.wrapping_mul(31) ^ idx.wrapping_mul(97))
+                    .collect()
+            })
+            .collect();
+        let refs: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+        let mut row: Vec<Option<Vec<u8>>> = data.iter().cloned().map(Some).collect();
+        row.push(Some(parity_p(&refs)));
+        if raid6 {
+            row.push(Some(parity_q(&refs)));
+        }
+        row
+    }
+
+    #[test]
+    fn galois_field_arithmetic() {
+        assert_eq!(gf_mul(2, 0x80), 0x1d);
+        assert_eq!(gf_mul(0x53, 0), 0);
+        for a in 1..=255u8 {
+            assert_eq!(gf_div(gf_mul(a, 0xca), 0xca), a);
+        }
+    }
+
+    #[test]
+    fn rebuilds_any_erasure_parity_allows() {
+        let raid5 = full_row(false);
+        for lost in 0..raid5.len() {
+            let mut row = raid5.clone();
+            row[lost] = None;
+            rebuild(&mut row, 4).unwrap();
+            assert_eq!(row, raid5);
+        }
+
+        let raid6 = full_row(true);
+        for x in 0..raid6.len() {
+            for y in x..raid6.len() {
+                let mut row = raid6.clone();
+                row[x] = None;
+                row[y] = None;
+                rebuild(&mut row, 4).unwrap();
+                assert_eq!(row, raid6, "lost {} and {}", x, y);
+            }
+        }
+
+        let mut row = raid5.clone();
+        row[0] = None;
+        row[1] = None;
+        assert!(rebuild(&mut row, 4).is_err());
+    }
+}
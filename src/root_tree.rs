@@ -0,0 +1,123 @@
+// ** Root items
+// Every tree other than the root tree and the chunk tree is found through a ROOT_ITEM in the
+// tree of tree roots keyed by
+// |tree objectid| ROOT_ITEM| 0 or the transid a snapshot was taken in|
+// so lookups go by objectid and type, and the offset is whatever is stored.
+// Subvolumes and snapshots get objectids from 256 on; the default fs tree is objectid 5.
+use crate::btrfs::{
+    check_len, read_le_u32, read_le_u64, BtrfsKey, BtrfsTimespec, BTRFS_ROOT_ITEM_KEY,
+};
+
+/// BtrfsRootItem::flags, the subvolume is a read-only snapshot
+pub const BTRFS_ROOT_SUBVOL_RDONLY: u64 = 1 << 0;
+
+/// Size of a root item written before the uuid/transid/time fields were added
+const ROOT_ITEM_V1_SIZE: usize = 0xef;
+/// Size of a current root item
+const ROOT_ITEM_V2_SIZE: usize = 0x1b7;
+
+/// Root of a tree. Starts with an embedded inode item (0xa0 bytes) that is not used for
+/// anything but the subvolume directory and is skipped here.
+#[derive(Debug, Clone)]
+pub struct BtrfsRootItem {
+    pub generation: u64,         // 0xa0: transaction that last changed the root
+    pub root_dirid: u64,         // 0xa8: objectid of the top directory inode of the subvolume
+    pub bytenr: u64,             // 0xb0: logical address of the root node
+    pub byte_limit: u64,         // 0xb8: unused
+    pub bytes_used: u64,         // 0xc0
+    pub last_snapshot: u64,      // 0xc8: transid of the last snapshot of this root
+    pub flags: u64,              // 0xd0: BTRFS_ROOT_SUBVOL_RDONLY
+    pub refs: u32,               // 0xd8: 0 once the subvolume is deleted
+    pub drop_progress: BtrfsKey, // 0xdc: where a pending deletion got to
+    pub drop_level: u8,          // 0xed
+    pub level: u8,               // 0xee: level of the root node
+
+    // Only present in v2 root items, zero otherwise
+    pub generation_v2: u64, // 0xef: equals generation when the fields below are valid
+    pub uuid: [u8; 16],     // 0xf7: uuid of the subvolume
+    pub parent_uuid: [u8; 16], // 0x107: uuid of the snapshot source
+    pub received_uuid: [u8; 16], // 0x117: uuid of the subvolume this one was received from
+    pub ctransid: u64,      // 0x127: transid of the last change
+    pub otransid: u64,      // 0x12f: transid of creation
+    pub stransid: u64,      // 0x137: transid of the send side, when received
+    pub rtransid: u64,      // 0x13f: transid when received
+    pub ctime: BtrfsTimespec, // 0x147
+    pub otime: BtrfsTimespec, // 0x153
+    pub stime: BtrfsTimespec, // 0x15f
+    pub rtime: BtrfsTimespec, // 0x16b
+}
+
+impl BtrfsRootItem {
+    /// Deserializes a ROOT_ITEM payload, either the 0xef byte v1 layout or the 0x1b7 byte v2 one
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, ROOT_ITEM_V1_SIZE)?;
+        let v2 = buffer.len() >= ROOT_ITEM_V2_SIZE;
+        let read_u64_v2 = |offset: usize| if v2 { read_le_u64(buffer, offset) } else { 0 };
+        let read_uuid_v2 = |offset: usize| -> [u8; 16] {
+            if v2 {
+                buffer[offset..offset + 16].try_into().unwrap()
+            } else {
+                [0; 16]
+            }
+        };
+        let read_time_v2 = |offset: usize| -> Result<BtrfsTimespec, std::io::Error> {
+            if v2 {
+                BtrfsTimespec::from_buffer(&buffer[offset..])
+            } else {
+                Ok(BtrfsTimespec::default())
+            }
+        };
+
+        Ok(BtrfsRootItem {
+            generation: read_le_u64(buffer, 0xa0),
+            root_dirid: read_le_u64(buffer, 0xa8),
+            bytenr: read_le_u64(buffer, 0xb0),
+            byte_limit: read_le_u64(buffer, 0xb8),
+            bytes_used: read_le_u64(buffer, 0xc0),
+            last_snapshot: read_le_u64(buffer, 0xc8),
+            flags: read_le_u64(buffer, 0xd0),
+            refs: read_le_u32(buffer, 0xd8),
+            drop_progress: BtrfsKey::from_buffer(&buffer[0xdc..])?,
+            drop_level: buffer[0xed],
+            level: buffer[0xee],
+            generation_v2: read_u64_v2(0xef),
+            uuid: read_uuid_v2(0xf7),
+            parent_uuid: read_uuid_v2(0x107),
+            received_uuid: read_uuid_v2(0x117),
+            ctransid: read_u64_v2(0x127),
+            otransid: read_u64_v2(0x12f),
+            stransid: read_u64_v2(0x137),
+            rtransid: read_u64_v2(0x13f),
+            ctime: read_time_v2(0x147)?,
+            otime: read_time_v2(0x153)?,
+            stime: read_time_v2(0x15f)?,
+            rtime: read_time_v2(0x16b)?,
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.flags & BTRFS_ROOT_SUBVOL_RDONLY != 0
+    }
+}
+
+/// Finds the ROOT_ITEM of the tree with objectid `root_id`.
+///
+/// `items` are the (key, payload) pairs of the tree of tree roots in key order. Returns the key
+/// that was found, since snapshots keep their creation transid in the key offset.
+pub fn find_root_item<'a, I>(
+    items: I,
+    root_id: u64,
+) -> Result<Option<(BtrfsKey, BtrfsRootItem)>, std::io::Error>
+where
+    I: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    for (key, data) in items {
+        if key.object_id > root_id {
+            break;
+        }
+        if key.object_id == root_id && key.type_id == BTRFS_ROOT_ITEM_KEY {
+            return Ok(Some((key.clone(), BtrfsRootItem::from_buffer(data)?)));
+        }
+    }
+    Ok(None)
+}
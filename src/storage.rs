@@ -0,0 +1,277 @@
+// ** Storage backends
+// BlockDevice doesn't care where the bytes come from. Anything that can do positioned reads and
+// writes can back it: a regular file or block device, an image held in memory (handy for tests
+// and for images built on the fly), or a file opened without write permission.
+// Reads take &self so that several readers can share one backend.
+// Backends that hold the whole image in memory (a Vec, a memory mapped file) are also
+// MappedStorage, which lets tree blocks be read in place instead of copied.
+use std::fs::File;
+use std::sync::Arc;
+
+use memmap2::{Mmap, MmapOptions};
+
+pub trait BlockStorage {
+    /// Fills `buf` with the bytes starting at `offset`. Reading past the end is an error.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error>;
+    /// Writes all of `buf` starting at `offset`
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), std::io::Error>;
+    /// Size of the backend in bytes
+    fn size(&self) -> Result<u64, std::io::Error>;
+    /// Makes previous writes durable
+    fn flush(&mut self) -> Result<(), std::io::Error>;
+
+    /// Fills each buffer with the bytes at its offset. Backends able to keep several reads in
+    /// flight (see uring::UringFile) override this, the others read one buffer after another.
+    fn read_batch(&self, reads: &mut [(u64, &mut [u8])]) -> Result<(), std::io::Error> {
+        for (offset, buf) in reads.iter_mut() {
+            self.read_at(*offset, buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage that accepts writes. BlockDevice and BTree only offer their write paths on top of
+/// it, so a device opened read-only can't even express a write:
+///
+/// ```compile_fail
+/// let mut device = btrfs::btrees::BlockDevice::open_read_only("image.img").unwrap();
+/// device.write_block(0, &[0; 4096]).unwrap();
+/// ```
+pub trait WritableStorage: BlockStorage {}
+
+impl WritableStorage for File {}
+impl WritableStorage for Vec<u8> {}
+
+/// Storage whose whole content is addressable in memory
+pub trait MappedStorage: BlockStorage {
+    fn bytes(&self) -> &[u8];
+}
+
+impl MappedStorage for Vec<u8> {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn file_read_at(file: &File, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn file_write_at(file: &File, offset: u64, buf: &[u8]) -> Result<(), std::io::Error> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn file_read_at(file: &File, mut offset: u64, mut buf: &mut [u8]) -> Result<(), std::io::Error> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn file_write_at(file: &File, mut offset: u64, mut buf: &[u8]) -> Result<(), std::io::Error> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::WriteZero.into()),
+            n => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Files and block devices opened read+write
+impl BlockStorage for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        file_read_at(self, offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), std::io::Error> {
+        file_write_at(self, offset, buf)
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        // metadata().len() is 0 for block devices, seeking to the end works for both
+        let mut file = self;
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::End(0))
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.sync_all()
+    }
+}
+
+/// An image held in memory. Writes past the end grow the buffer.
+impl BlockStorage for Vec<u8> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        let start = usize::try_from(offset).map_err(|_| std::io::ErrorKind::UnexpectedEof)?;
+        let src = start
+            .checked_add(buf.len())
+            .and_then(|end| self.get(start..end))
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), std::io::Error> {
+        let start = usize::try_from(offset).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+        let end = start + buf.len();
+        if self.len() < end {
+            self.resize(end, 0);
+        }
+        self[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.len() as u64)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// A file opened without write permission. It isn't WritableStorage, and should a write reach
+/// it through the BlockStorage trait anyway it fails with PermissionDenied.
+/// Clones share the file handle, so trees opened from one another don't reopen the device.
+#[derive(Clone)]
+pub struct ReadOnlyFile(Arc<File>);
+
+impl ReadOnlyFile {
+    pub fn open(path: &str) -> Result<Self, std::io::Error> {
+        Ok(ReadOnlyFile(Arc::new(File::open(path)?)))
+    }
+}
+
+impl BlockStorage for ReadOnlyFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        file_read_at(&self.0, offset, buf)
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "storage was opened read-only",
+        ))
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.0.as_ref().size()
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// A file mapped read-only into memory. Reads are plain memory copies, and MappedStorage gives
+/// direct access to the mapping. Clones share the mapping.
+#[derive(Clone)]
+pub struct MmapFile(Arc<Mmap>);
+
+impl MmapFile {
+    pub fn open(path: &str) -> Result<Self, std::io::Error> {
+        let file = File::open(path)?;
+        // block devices report a length of 0 in their metadata, so map the size we can seek to
+        let len = usize::try_from(file.size()?).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+        // SAFETY: the mapping is read-only and private to this process. If another process
+        // modifies the image while it is mapped we may read torn data, which is the same
+        // guarantee a regular read of an image in use gives.
+        let map = unsafe { MmapOptions::new().len(len).map(&file)? };
+        Ok(MmapFile(Arc::new(map)))
+    }
+}
+
+impl MappedStorage for MmapFile {
+    fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl BlockStorage for MmapFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        let start = usize::try_from(offset).map_err(|_| std::io::ErrorKind::UnexpectedEof)?;
+        let src = start
+            .checked_add(buf.len())
+            .and_then(|end| self.0.get(start..end))
+            .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "memory mapped storage is read-only",
+        ))
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        Ok(self.0.len() as u64)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_storage_round_trip() {
+        let mut storage: Vec<u8> = Vec::new();
+        storage.write_at(4, b"btrfs").unwrap();
+        assert_eq!(storage.size().unwrap(), 9);
+
+        let mut buf = [0u8; 5];
+        storage.read_at(4, &mut buf).unwrap();
+        assert_eq!(&buf, b"btrfs");
+        assert!(storage.read_at(5, &mut buf).is_err());
+    }
+
+    #[test]
+    fn read_only_file_rejects_writes() {
+        let path = std::env::temp_dir().join(format!("btrfs-ro-{}.img", std::process::id()));
+        std::fs::write(&path, [7u8; 16]).unwrap();
+
+        let mut storage = ReadOnlyFile::open(path.to_str().unwrap()).unwrap();
+        let mut buf = [0u8; 4];
+        storage.read_at(12, &mut buf).unwrap();
+        assert_eq!(buf, [7; 4]);
+        let err = storage.write_at(0, &[0]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn mmap_file_reads_in_place() {
+        let path = std::env::temp_dir().join(format!("btrfs-mmap-{}.img", std::process::id()));
+        let content: Vec<u8> = (0..=255).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let mut storage = MmapFile::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(storage.bytes(), &content[..]);
+        let mut buf = [0u8; 4];
+        storage.read_at(252, &mut buf).unwrap();
+        assert_eq!(buf, [252, 253, 254, 255]);
+        assert!(storage.read_at(253, &mut buf).is_err());
+        assert!(storage.write_at(0, &[0]).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
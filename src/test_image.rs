@@ -0,0 +1,467 @@
+// ** Synthetic images for tests
+// Just enough of the on-disk format to exercise the read paths: a superblock whose
+// sys_chunk_array maps one SYSTEM chunk, and leaves/internal nodes written into it.
+use crate::btrees::{BTree, BlockDevice, BTRFS_SUPER_INFO_OFFSET};
+use crate::btrfs::{
+    BtrfsChunkItem, BtrfsKey, BTRFS_CHUNK_ITEM_KEY, BTRFS_CHUNK_TREE_OBJECTID,
+    BTRFS_CSUM_TREE_OBJECTID, BTRFS_DEV_ITEMS_OBJECTID, BTRFS_DEV_ITEM_KEY,
+    BTRFS_FIRST_CHUNK_TREE_OBJECTID, BTRFS_FS_TREE_OBJECTID, BTRFS_INODE_ITEM_KEY,
+    BTRFS_ROOT_ITEM_KEY,
+};
+use crate::chunk::{
+    ChunkMap, BTRFS_BLOCK_GROUP_DATA, BTRFS_BLOCK_GROUP_RAID10, BTRFS_BLOCK_GROUP_SYSTEM,
+};
+use crate::csum::ChecksumType;
+use crate::dir::BTRFS_FT_XATTR;
+use crate::raid56;
+
+pub(crate) const NODESIZE: u32 = 4096;
+pub(crate) const SECTORSIZE: u32 = 4096;
+pub(crate) const DEVID: u64 = 1;
+/// The single chunk of the image, logical CHUNK_LOGICAL.. is at physical CHUNK_PHYSICAL..
+pub(crate) const CHUNK_LOGICAL: u64 = 0x40_0000;
+pub(crate) const CHUNK_PHYSICAL: u64 = 0x2_0000;
+pub(crate) const CHUNK_SIZE: u64 = 0x10_0000;
+pub(crate) const FSID: [u8; 16] = [0x42; 16];
+
+fn put_u64(buffer: &mut [u8], offset: usize, value: u64) {
+    buffer[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn key_bytes(key: &BtrfsKey) -> [u8; 0x11] {
+    let mut buffer = [0u8; 0x11];
+    put_u64(&mut buffer, 0x00, key.object_id);
+    buffer[0x08] = key.type_id;
+    put_u64(&mut buffer, 0x09, key.offset);
+    buffer
+}
+
+/// A chunk item with one stripe per (devid, physical offset)
+pub(crate) fn chunk_item(size: u64, type_: u64, stripes: &[(u64, u64)]) -> Vec<u8> {
+    let mut buffer = vec![0u8; 0x30 + 0x20 * stripes.len()];
+    put_u64(&mut buffer, 0x00, size);
+    put_u64(&mut buffer, 0x08, 2); // extent tree
+    put_u64(&mut buffer, 0x10, 0x1_0000);
+    put_u64(&mut buffer, 0x18, type_);
+    put_u32(&mut buffer, 0x20, SECTORSIZE);
+    put_u32(&mut buffer, 0x24, SECTORSIZE);
+    put_u32(&mut buffer, 0x28, SECTORSIZE);
+    put_u16(&mut buffer, 0x2c, stripes.len() as u16);
+    let sub_stripes = if type_ & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+        2
+    } else {
+        1
+    };
+    put_u16(&mut buffer, 0x2e, sub_stripes);
+    for (idx, (devid, offset)) in stripes.iter().enumerate() {
+        let stripe = 0x30 + idx * 0x20;
+        put_u64(&mut buffer, stripe, *devid);
+        put_u64(&mut buffer, stripe + 0x08, *offset);
+    }
+    buffer
+}
+
+/// A v2 ROOT_ITEM for a tree whose root node is the leaf at `bytenr`, written in generation 1
+pub(crate) fn root_item(bytenr: u64) -> Vec<u8> {
+    let mut buffer = vec![0u8; 0x1b7];
+    put_u64(&mut buffer, 0xa0, 1);
+    put_u64(&mut buffer, 0xa8, 256);
+    put_u64(&mut buffer, 0xb0, bytenr);
+    put_u32(&mut buffer, 0xd8, 1);
+    put_u64(&mut buffer, 0xef, 1);
+    buffer
+}
+
+fn header(logical: u64, owner: u64, nritems: usize, level: u8) -> Vec<u8> {
+    let mut buffer = vec![0u8; NODESIZE as usize];
+    put_u64(&mut buffer, 0x30, logical);
+    put_u64(&mut buffer, 0x50, 1);
+    put_u64(&mut buffer, 0x58, owner);
+    put_u32(&mut buffer, 0x60, nritems as u32);
+    buffer[0x64] = level;
+    buffer
+}
+
+/// A leaf holding `items` (which must be sorted), with the payloads packed at the end
+pub(crate) fn leaf(logical: u64, owner: u64, items: &[(BtrfsKey, Vec<u8>)]) -> Vec<u8> {
+    let mut buffer = header(logical, owner, items.len(), 0);
+    let mut data_end = NODESIZE as usize - 0x65;
+    for (idx, (key, data)) in items.iter().enumerate() {
+        data_end -= data.len();
+        let item = 0x65 + idx * 0x19;
+        buffer[item..item + 0x11].copy_from_slice(&key_bytes(key));
+        put_u32(&mut buffer, item + 0x11, data_end as u32);
+        put_u32(&mut buffer, item + 0x15, data.len() as u32);
+        buffer[0x65 + data_end..0x65 + data_end + data.len()].copy_from_slice(data);
+    }
+    buffer
+}
+
+/// An internal node pointing at the children (first key, logical address)
+pub(crate) fn internal(logical: u64, owner: u64, level: u8, ptrs: &[(BtrfsKey, u64)]) -> Vec<u8> {
+    let mut buffer = header(logical, owner, ptrs.len(), level);
+    for (idx, (key, block_ptr)) in ptrs.iter().enumerate() {
+        let key_ptr = 0x65 + idx * 0x21;
+        buffer[key_ptr..key_ptr + 0x11].copy_from_slice(&key_bytes(key));
+        put_u64(&mut buffer, key_ptr + 0x11, *block_ptr);
+        put_u64(&mut buffer, key_ptr + 0x19, 1);
+    }
+    buffer
+}
+
+/// An inode item with the given mode and size, one link
+pub(crate) fn inode_item(mode: u32, size: u64) -> Vec<u8> {
+    let mut buffer = vec![0u8; 0xa0];
+    put_u64(&mut buffer, 0x00, 1);
+    put_u64(&mut buffer, 0x10, size);
+    put_u32(&mut buffer, 0x28, 1);
+    put_u32(&mut buffer, 0x34, mode);
+    buffer
+}
+
+/// A directory entry (DIR_ITEM or DIR_INDEX payload) pointing at an inode
+pub(crate) fn dir_item(inode: u64, file_type: u8, name: &str) -> Vec<u8> {
+    let mut buffer = vec![0u8; 0x1e];
+    let location = BtrfsKey::new(inode, BTRFS_INODE_ITEM_KEY, 0);
+    buffer[..0x11].copy_from_slice(&key_bytes(&location));
+    put_u64(&mut buffer, 0x11, 1);
+    put_u16(&mut buffer, 0x1b, name.len() as u16);
+    buffer[0x1d] = file_type;
+    buffer.extend_from_slice(name.as_bytes());
+    buffer
+}
+
+/// An extended attribute (XATTR_ITEM payload)
+pub(crate) fn xattr_item(name: &str, value: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0u8; 0x1e];
+    put_u64(&mut buffer, 0x11, 1);
+    put_u16(&mut buffer, 0x19, value.len() as u16);
+    put_u16(&mut buffer, 0x1b, name.len() as u16);
+    buffer[0x1d] = BTRFS_FT_XATTR;
+    buffer.extend_from_slice(name.as_bytes());
+    buffer.extend_from_slice(value);
+    buffer
+}
+
+/// Stores the crc32c of a tree block in its header, as the superblock's csum_type is 0
+pub(crate) fn set_checksum(node: &mut [u8]) {
+    let csum = ChecksumType::Crc32c.compute(&node[0x20..]);
+    node[..csum.len()].copy_from_slice(&csum);
+}
+
+/// A DEV_ITEM for device `devid` of the test filesystem
+pub(crate) fn dev_item(devid: u64) -> Vec<u8> {
+    let mut buffer = vec![0u8; 0x62];
+    put_u64(&mut buffer, 0x00, devid);
+    put_u32(&mut buffer, 0x20, SECTORSIZE);
+    buffer[0x42..0x52].copy_from_slice(&dev_uuid(devid));
+    buffer[0x52..0x62].copy_from_slice(&FSID);
+    buffer
+}
+
+pub(crate) fn dev_uuid(devid: u64) -> [u8; 16] {
+    [devid as u8; 16]
+}
+
+struct TestChunk {
+    logical: u64,
+    item: Vec<u8>,
+}
+
+/// An in-memory filesystem over one or more devices (devid = index + 1). It starts with one
+/// SYSTEM chunk on device 1, more chunks can be added with add_chunk. Tree blocks are
+/// collected and written, together with a chunk tree leaf describing every chunk and device,
+/// by finish().
+pub(crate) struct TestImage {
+    pub images: Vec<Vec<u8>>,
+    chunks: Vec<TestChunk>,
+    nodes: Vec<(u64, Vec<u8>)>,
+    chunk_root: u64,
+}
+
+impl TestImage {
+    /// Single device filesystem, see with_devices
+    pub fn new(root: u64, chunk_root: u64) -> Self {
+        Self::with_devices(root, chunk_root, 1)
+    }
+
+    /// `root` and `chunk_root` are logical addresses inside the SYSTEM chunk. The root tree is
+    /// left to the caller.
+    pub fn with_devices(root: u64, chunk_root: u64, num_devices: u64) -> Self {
+        let key = BtrfsKey::new(
+            BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            BTRFS_CHUNK_ITEM_KEY,
+            CHUNK_LOGICAL,
+        );
+        let chunk = chunk_item(
+            CHUNK_SIZE,
+            BTRFS_BLOCK_GROUP_SYSTEM,
+            &[(DEVID, CHUNK_PHYSICAL)],
+        );
+        let mut array = key_bytes(&key).to_vec();
+        array.extend_from_slice(&chunk);
+
+        let images = (1..=num_devices)
+            .map(|devid| {
+                let mut superblock = vec![0u8; 0x1000];
+                superblock[0x20..0x30].copy_from_slice(&FSID);
+                put_u64(&mut superblock, 0x30, BTRFS_SUPER_INFO_OFFSET);
+                superblock[0x40..0x48].copy_from_slice(b"_BHRfS_M");
+                put_u64(&mut superblock, 0x48, 1);
+                put_u64(&mut superblock, 0x50, root);
+                put_u64(&mut superblock, 0x58, chunk_root);
+                put_u64(&mut superblock, 0x80, 6);
+                put_u64(&mut superblock, 0x88, num_devices);
+                put_u32(&mut superblock, 0x90, SECTORSIZE);
+                put_u32(&mut superblock, 0x94, NODESIZE);
+                put_u32(&mut superblock, 0x98, NODESIZE);
+                put_u32(&mut superblock, 0xa0, array.len() as u32);
+                put_u64(&mut superblock, 0xa4, 1);
+                superblock[0xc9..0xc9 + 0x62].copy_from_slice(&dev_item(devid));
+                superblock[0x32b..0x32b + array.len()].copy_from_slice(&array);
+
+                let mut image = vec![0u8; (CHUNK_PHYSICAL + CHUNK_SIZE) as usize];
+                let start = BTRFS_SUPER_INFO_OFFSET as usize;
+                image[start..start + 0x1000].copy_from_slice(&superblock);
+                image
+            })
+            .collect();
+
+        TestImage {
+            images,
+            chunks: vec![TestChunk {
+                logical: CHUNK_LOGICAL,
+                item: chunk,
+            }],
+            nodes: Vec::new(),
+            chunk_root,
+        }
+    }
+
+    /// Adds a chunk to the chunk tree, with stripes given as (devid, physical offset). What
+    /// put_node and put_data write in it is laid out over the stripes, see put_data.
+    pub fn add_chunk(&mut self, logical: u64, size: u64, type_: u64, stripes: &[(u64, u64)]) {
+        self.chunks.push(TestChunk {
+            logical,
+            item: chunk_item(size, type_, stripes),
+        });
+        self.chunks.sort_by_key(|chunk| chunk.logical);
+    }
+
+    /// Queues a tree block at a logical address, checksummed and written by finish()
+    pub fn put_node(&mut self, logical: u64, node: &[u8]) {
+        self.nodes.push((logical, node.to_vec()));
+    }
+
+    /// Writes data at a logical address, laid out over the stripes of its chunk the way
+    /// ChunkMap reads it back: every copy for the mirrored profiles, striped for RAID0/10,
+    /// striped with the parity of the rows updated for RAID5/6
+    pub fn put_data(&mut self, logical: u64, data: &[u8]) {
+        let mut map = ChunkMap::new();
+        for chunk in &self.chunks {
+            map.insert(
+                chunk.logical,
+                BtrfsChunkItem::from_buffer(&chunk.item).unwrap(),
+            )
+            .unwrap();
+        }
+        let pieces = map
+            .map_range(logical, data.len() as u64)
+            .expect("logical range outside of the test chunks");
+        for piece in pieces {
+            let start = (piece.logical - logical) as usize;
+            let bytes = &data[start..start + piece.len as usize];
+            for copy in piece.copies {
+                self.put_physical(copy.devid, copy.offset, bytes);
+            }
+            if let Some(row) = map.parity_row(piece.logical).unwrap() {
+                let data: Vec<Vec<u8>> = row
+                    .data
+                    .iter()
+                    .map(|member| self.physical(member.devid, member.offset, bytes.len()))
+                    .collect();
+                // written back so that every image covers the whole row
+                for (member, bytes) in row.data.iter().zip(&data) {
+                    self.put_physical(member.devid, member.offset, bytes);
+                }
+                let data: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+                self.put_physical(row.p.devid, row.p.offset, &raid56::parity_p(&data));
+                if let Some(q) = row.q {
+                    self.put_physical(q.devid, q.offset, &raid56::parity_q(&data));
+                }
+            }
+        }
+    }
+
+    /// Reads bytes at a physical offset of a device, zeroes past the end of its image
+    fn physical(&self, devid: u64, offset: u64, len: usize) -> Vec<u8> {
+        let image = &self.images[devid as usize - 1];
+        let mut bytes = vec![0u8; len];
+        let start = (offset as usize).min(image.len());
+        let end = (offset as usize + len).min(image.len());
+        bytes[..end - start].copy_from_slice(&image[start..end]);
+        bytes
+    }
+
+    /// Writes bytes at a physical offset of a device, growing its image as needed
+    pub fn put_physical(&mut self, devid: u64, offset: u64, data: &[u8]) {
+        let image = &mut self.images[devid as usize - 1];
+        let end = offset as usize + data.len();
+        if image.len() < end {
+            image.resize(end, 0);
+        }
+        image[offset as usize..end].copy_from_slice(data);
+    }
+
+    /// Writes the chunk tree and the queued tree blocks, and returns the device images
+    pub fn finish(mut self) -> Vec<Vec<u8>> {
+        let mut items: Vec<(BtrfsKey, Vec<u8>)> = (1..=self.images.len() as u64)
+            .map(|devid| {
+                let key = BtrfsKey::new(BTRFS_DEV_ITEMS_OBJECTID, BTRFS_DEV_ITEM_KEY, devid);
+                (key, dev_item(devid))
+            })
+            .collect();
+        items.extend(self.chunks.iter().map(|chunk| {
+            let key = BtrfsKey::new(
+                BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                BTRFS_CHUNK_ITEM_KEY,
+                chunk.logical,
+            );
+            (key, chunk.item.clone())
+        }));
+        let chunk_tree = leaf(self.chunk_root, BTRFS_CHUNK_TREE_OBJECTID, &items);
+        self.put_node(self.chunk_root, &chunk_tree);
+
+        for (logical, mut node) in std::mem::take(&mut self.nodes) {
+            set_checksum(&mut node);
+            self.put_data(logical, &node);
+        }
+        self.images
+    }
+
+    /// Single device image, see finish
+    pub fn into_image(self) -> Vec<u8> {
+        self.finish().remove(0)
+    }
+}
+
+/// Where fs_tree puts its DATA chunk, on device 1
+pub(crate) const DATA_LOGICAL: u64 = 0x100_0000;
+
+type Items = Vec<(BtrfsKey, Vec<u8>)>;
+
+/// Opens the tree of tree roots of a single device filesystem holding `root_items`, plus a
+/// ROOT_ITEM for each (tree id, items) of `trees`, a tree made of a single leaf
+pub(crate) fn root_tree(mut root_items: Items, trees: Vec<(u64, Items)>) -> BTree<Vec<u8>> {
+    let root = CHUNK_LOGICAL + 0x1000;
+    let mut image = TestImage::new(root, CHUNK_LOGICAL);
+    for (idx, (tree_id, mut items)) in trees.into_iter().enumerate() {
+        let logical = CHUNK_LOGICAL + 0x2000 + idx as u64 * NODESIZE as u64;
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        image.put_node(logical, &leaf(logical, tree_id, &items));
+        root_items.push((
+            BtrfsKey::new(tree_id, BTRFS_ROOT_ITEM_KEY, 0),
+            root_item(logical),
+        ));
+    }
+    root_items.sort_by(|a, b| a.0.cmp(&b.0));
+    image.put_node(root, &leaf(root, 1, &root_items));
+    BTree::from_device(BlockDevice::from_storage(image.into_image()).unwrap()).unwrap()
+}
+
+/// Opens the fs tree (tree 5) of a single device filesystem whose fs tree is a single leaf
+/// holding `items` (sorted here). `data` is written at logical addresses of a 1MiB DATA chunk
+/// starting at DATA_LOGICAL.
+pub(crate) fn fs_tree(items: Vec<(BtrfsKey, Vec<u8>)>, data: &[(u64, Vec<u8>)]) -> BTree<Vec<u8>> {
+    fs_and_csum_trees(items, Vec::new(), data).0
+}
+
+/// Same as fs_tree, with a csum tree of one leaf holding `csum_items` next to it
+pub(crate) fn fs_and_csum_trees(
+    mut items: Vec<(BtrfsKey, Vec<u8>)>,
+    csum_items: Vec<(BtrfsKey, Vec<u8>)>,
+    data: &[(u64, Vec<u8>)],
+) -> (BTree<Vec<u8>>, BTree<Vec<u8>>) {
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    open_test_trees(vec![items], csum_items, data)
+}
+
+/// Same as fs_tree, with the fs tree made of an internal node over one leaf per list of
+/// `leaves` (each sorted here, the lists themselves must be in key order)
+pub(crate) fn fs_tree_leaves(
+    mut leaves: Vec<Vec<(BtrfsKey, Vec<u8>)>>,
+    data: &[(u64, Vec<u8>)],
+) -> BTree<Vec<u8>> {
+    for items in &mut leaves {
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    open_test_trees(leaves, Vec::new(), data).0
+}
+
+/// Builds the image of fs_tree and opens its fs and csum trees. A single fs leaf is the root
+/// of the fs tree, several are put under an internal root.
+fn open_test_trees(
+    fs_leaves: Vec<Vec<(BtrfsKey, Vec<u8>)>>,
+    mut csum_items: Vec<(BtrfsKey, Vec<u8>)>,
+    data: &[(u64, Vec<u8>)],
+) -> (BTree<Vec<u8>>, BTree<Vec<u8>>) {
+    let root = CHUNK_LOGICAL + 0x1000;
+    let fs_leaf = CHUNK_LOGICAL + 0x2000;
+    let csum_leaf = CHUNK_LOGICAL + 0x3000;
+    let mut image = TestImage::new(root, CHUNK_LOGICAL);
+    image.add_chunk(
+        DATA_LOGICAL,
+        0x10_0000,
+        BTRFS_BLOCK_GROUP_DATA,
+        &[(DEVID, CHUNK_PHYSICAL + CHUNK_SIZE)],
+    );
+    let root_key = |tree_id| BtrfsKey::new(tree_id, BTRFS_ROOT_ITEM_KEY, 0);
+    image.put_node(
+        root,
+        &leaf(
+            root,
+            1,
+            &[
+                (root_key(BTRFS_FS_TREE_OBJECTID), root_item(fs_leaf)),
+                (root_key(BTRFS_CSUM_TREE_OBJECTID), root_item(csum_leaf)),
+            ],
+        ),
+    );
+    if let [items] = fs_leaves.as_slice() {
+        image.put_node(fs_leaf, &leaf(fs_leaf, BTRFS_FS_TREE_OBJECTID, items));
+    } else {
+        let mut ptrs = Vec::new();
+        for (idx, items) in fs_leaves.iter().enumerate() {
+            let logical = CHUNK_LOGICAL + 0x4000 + idx as u64 * NODESIZE as u64;
+            image.put_node(logical, &leaf(logical, BTRFS_FS_TREE_OBJECTID, items));
+            ptrs.push((items[0].0.clone(), logical));
+        }
+        image.put_node(
+            fs_leaf,
+            &internal(fs_leaf, BTRFS_FS_TREE_OBJECTID, 1, &ptrs),
+        );
+    }
+    csum_items.sort_by(|a, b| a.0.cmp(&b.0));
+    image.put_node(
+        csum_leaf,
+        &leaf(csum_leaf, BTRFS_CSUM_TREE_OBJECTID, &csum_items),
+    );
+    for (logical, bytes) in data {
+        image.put_data(*logical, bytes);
+    }
+    let mut device_image = image.into_image();
+    device_image.resize((CHUNK_PHYSICAL + CHUNK_SIZE + 0x10_0000) as usize, 0);
+    let tree = BTree::from_device(BlockDevice::from_storage(device_image).unwrap()).unwrap();
+    (
+        tree.open_tree(BTRFS_FS_TREE_OBJECTID).unwrap(),
+        tree.open_tree(BTRFS_CSUM_TREE_OBJECTID).unwrap(),
+    )
+}
@@ -0,0 +1,234 @@
+// ** io_uring backend (Linux, feature "io-uring")
+// Walking a tree one pread at a time leaves a fast NVMe drive mostly idle. UringFile queues a
+// whole batch of block reads (e.g. every child of an internal node, see
+// BTree::for_each_leaf) on an io_uring and waits for all of them, so the device sees many reads
+// in flight at once. Single reads still go through pread.
+// The kernel reads into buffers owned by the batch, copied out once every read has completed.
+// If the ring fails with reads possibly still in flight those buffers are leaked rather than
+// freed, the ring is marked poisoned and every later batch falls back to pread.
+// The file is opened read-only, like ReadOnlyFile.
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::storage::{file_read_at, BlockStorage};
+
+/// Number of reads kept in flight at once
+pub const URING_QUEUE_DEPTH: u32 = 64;
+
+struct UringInner {
+    file: File,
+    ring: Mutex<IoUring>,
+    poisoned: AtomicBool, // set when the ring failed with reads in flight
+}
+
+/// A file read through io_uring. Clones share the file and the ring.
+#[derive(Clone)]
+pub struct UringFile(Arc<UringInner>);
+
+impl UringFile {
+    /// Opens the file read-only. Fails if the kernel doesn't support io_uring (or it is
+    /// disabled), in which case ReadOnlyFile is the fallback.
+    pub fn open(path: &str) -> Result<Self, std::io::Error> {
+        let file = File::open(path)?;
+        let ring = IoUring::new(URING_QUEUE_DEPTH)?;
+        Ok(UringFile(Arc::new(UringInner {
+            file,
+            ring: Mutex::new(ring),
+            poisoned: AtomicBool::new(false),
+        })))
+    }
+}
+
+impl BlockStorage for UringFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        file_read_at(&self.0.file, offset, buf)
+    }
+
+    fn write_at(&mut self, _offset: u64, _buf: &[u8]) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "storage was opened read-only",
+        ))
+    }
+
+    fn size(&self) -> Result<u64, std::io::Error> {
+        self.0.file.size()
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn read_batch(&self, reads: &mut [(u64, &mut [u8])]) -> Result<(), std::io::Error> {
+        if reads
+            .iter()
+            .any(|(_, buf)| u32::try_from(buf.len()).is_err())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "io_uring reads are limited to 4GiB",
+            ));
+        }
+        let fd = types::Fd(self.0.file.as_raw_fd());
+        let mut ring = self.0.ring.lock().unwrap_or_else(|err| err.into_inner());
+
+        for batch in reads.chunks_mut(URING_QUEUE_DEPTH as usize) {
+            if self.0.poisoned.load(Ordering::Acquire) {
+                for (offset, buf) in batch.iter_mut() {
+                    file_read_at(&self.0.file, *offset, buf)?;
+                }
+                continue;
+            }
+
+            let mut owned: Vec<Vec<u8>> = batch.iter().map(|(_, buf)| vec![0; buf.len()]).collect();
+            let mut done = vec![0usize; batch.len()]; // bytes read per request
+            let mut first_error = None;
+            let mut queued = 0;
+            for (idx, ((offset, _), buf)) in batch.iter().zip(owned.iter_mut()).enumerate() {
+                let entry = opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(*offset)
+                    .build()
+                    .user_data(idx as u64);
+                // SAFETY: the owned buffers are only freed once wait_for_reads has reaped every
+                // queued read, and leaked if it can't
+                if unsafe { ring.submission().push(&entry) }.is_err() {
+                    first_error = Some(std::io::Error::other("io_uring submission queue is full"));
+                    break;
+                }
+                queued += 1;
+            }
+            if let Err(err) = wait_for_reads(&mut ring, queued, &mut done, &mut first_error) {
+                // the kernel may still write into the buffers
+                std::mem::forget(owned);
+                self.0.poisoned.store(true, Ordering::Release);
+                return Err(err);
+            }
+            if let Some(err) = first_error {
+                return Err(err);
+            }
+
+            // a short read (end of a regular file, or the kernel splitting the request) is
+            // finished synchronously, and fails with UnexpectedEof past the end
+            for (((offset, buf), read), data) in batch.iter_mut().zip(done).zip(owned) {
+                buf[..read].copy_from_slice(&data[..read]);
+                if read < buf.len() {
+                    file_read_at(&self.0.file, *offset + read as u64, &mut buf[read..])?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Submits the queued reads and waits until all of them have completed, recording the bytes
+/// read by each (indexed by user_data) and the first failed read.
+/// Fails only when the ring itself is unusable, possibly with reads still in flight.
+fn wait_for_reads(
+    ring: &mut IoUring,
+    queued: usize,
+    done: &mut [usize],
+    first_error: &mut Option<std::io::Error>,
+) -> Result<(), std::io::Error> {
+    let mut completed = 0;
+    while completed < queued {
+        match ring.submit_and_wait(queued - completed) {
+            Ok(_) => {}
+            // a signal, or the kernel waiting for completions to be reaped: retry
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::Interrupted
+                        | std::io::ErrorKind::WouldBlock
+                        | std::io::ErrorKind::ResourceBusy
+                ) => {}
+            Err(err) => return Err(err),
+        }
+        for cqe in ring.completion() {
+            completed += 1;
+            match cqe.result() {
+                res if res < 0 => {
+                    first_error.get_or_insert(std::io::Error::from_raw_os_error(-res));
+                }
+                res => {
+                    if let Some(read) = done.get_mut(cqe.user_data() as usize) {
+                        *read = res as usize;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batched_reads_match_the_file() {
+        let path = std::env::temp_dir().join(format!("btrfs-uring-{}.img", std::process::id()));
+        let content: Vec<u8> = (0..0x30000u32).map(|i| (i / 0x1000) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        // io_uring may be unavailable (old kernel, seccomp), nothing to test then
+        let Ok(storage) = UringFile::open(path.to_str().unwrap()) else {
+            std::fs::remove_file(path).unwrap();
+            return;
+        };
+        let mut blocks = vec![vec![0u8; 0x1000]; 100];
+        let mut reads: Vec<_> = blocks
+            .iter_mut()
+            .enumerate()
+            .map(|(idx, buf)| (((idx % 0x30) * 0x1000) as u64, buf.as_mut_slice()))
+            .collect();
+        storage.read_batch(&mut reads).unwrap();
+        for (idx, block) in blocks.iter().enumerate() {
+            assert!(block.iter().all(|&b| b == (idx % 0x30) as u8));
+        }
+
+        let mut past_end = [0u8; 0x10];
+        let err = storage
+            .read_batch(&mut [(0x2fff8, &mut past_end[..])])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn poisoned_ring_falls_back_to_pread() {
+        let path =
+            std::env::temp_dir().join(format!("btrfs-uring-poisoned-{}.img", std::process::id()));
+        let content: Vec<u8> = (0..0x3000u32).map(|i| (i / 0x1000) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        let Ok(storage) = UringFile::open(path.to_str().unwrap()) else {
+            std::fs::remove_file(path).unwrap();
+            return;
+        };
+        storage.0.poisoned.store(true, Ordering::Release);
+        let mut blocks = vec![vec![0u8; 0x1000]; 3];
+        let mut reads: Vec<_> = blocks
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .map(|(idx, buf)| ((idx * 0x1000) as u64, buf.as_mut_slice()))
+            .collect();
+        storage.read_batch(&mut reads).unwrap();
+        for (idx, block) in blocks.iter().enumerate() {
+            assert!(block.iter().all(|&b| b == idx as u8));
+        }
+
+        let mut past_end = [0u8; 0x10];
+        let err = storage
+            .read_batch(&mut [(0x2ff8, &mut past_end[..])])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+// ** UUID tree
+// The uuid tree (objectid 9 in the tree of tree roots) maps subvolume uuids back to subvolume ids.
+// A 128 bit uuid doesn't fit in a key, so it is split in two little-endian halves:
+// |uuid[0..8]| UUID_KEY_SUBVOL| uuid[8..16]|           the subvolume's own uuid
+// |uuid[0..8]| UUID_KEY_RECEIVED_SUBVOL| uuid[8..16]|  the uuid a subvolume was received from
+// The payload is an array of little-endian u64 subvolume ids. A received uuid can map to more
+// than one subvolume when the same snapshot was received several times.
+use crate::btrees::BTree;
+use crate::btrfs::{
+    read_le_u64, BtrfsKey, BTRFS_ROOT_ITEM_KEY, BTRFS_UUID_KEY_RECEIVED_SUBVOL,
+    BTRFS_UUID_KEY_SUBVOL, BTRFS_UUID_TREE_OBJECTID,
+};
+use crate::root_tree::BtrfsRootItem;
+use crate::storage::BlockStorage;
+
+/// Which uuid of the subvolume an entry indexes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UuidKind {
+    Subvolume,         // ROOT_ITEM uuid
+    ReceivedSubvolume, // ROOT_ITEM received_uuid
+}
+
+impl UuidKind {
+    pub fn key_type(self) -> u8 {
+        match self {
+            UuidKind::Subvolume => BTRFS_UUID_KEY_SUBVOL,
+            UuidKind::ReceivedSubvolume => BTRFS_UUID_KEY_RECEIVED_SUBVOL,
+        }
+    }
+}
+
+/// A uuid tree entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsUuidItem {
+    pub kind: UuidKind,
+    pub uuid: [u8; 16],          // rebuilt from the key
+    pub subvolume_ids: Vec<u64>, // payload
+}
+
+/// Builds the uuid tree key of `uuid`
+pub fn uuid_to_key(uuid: &[u8; 16], kind: UuidKind) -> BtrfsKey {
+    BtrfsKey::new(read_le_u64(uuid, 0), kind.key_type(), read_le_u64(uuid, 8))
+}
+
+/// Rebuilds the uuid stored in a uuid tree key
+pub fn uuid_from_key(key: &BtrfsKey) -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    uuid[..8].copy_from_slice(&key.object_id.to_le_bytes());
+    uuid[8..].copy_from_slice(&key.offset.to_le_bytes());
+    uuid
+}
+
+impl BtrfsUuidItem {
+    /// Decodes a UUID_KEY_SUBVOL or UUID_KEY_RECEIVED_SUBVOL item
+    pub fn from_item(key: &BtrfsKey, buffer: &[u8]) -> Result<Self, std::io::Error> {
+        let kind = match key.type_id {
+            BTRFS_UUID_KEY_SUBVOL => UuidKind::Subvolume,
+            BTRFS_UUID_KEY_RECEIVED_SUBVOL => UuidKind::ReceivedSubvolume,
+            other => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("key type {} is not a uuid tree item", other),
+                ))
+            }
+        };
+        if !buffer.len().is_multiple_of(8) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("uuid item size {} is not a multiple of 8", buffer.len()),
+            ));
+        }
+        Ok(BtrfsUuidItem {
+            kind,
+            uuid: uuid_from_key(key),
+            subvolume_ids: buffer
+                .chunks_exact(8)
+                .map(|id| read_le_u64(id, 0))
+                .collect(),
+        })
+    }
+}
+
+/// A subvolume found through the uuid tree, with its ROOT_ITEM
+#[derive(Debug, Clone)]
+pub struct SubvolumeMatch {
+    pub subvolume_id: u64,
+    pub root_key: BtrfsKey,
+    pub root_item: BtrfsRootItem,
+}
+
+/// Finds the subvolume whose own uuid is `uuid`.
+///
+/// `uuid_items` and `root_items` are the (key, payload) pairs of the uuid tree and the tree of
+/// tree roots, in key order.
+pub fn find_subvolume_by_uuid<'a, U, R>(
+    uuid_items: U,
+    root_items: R,
+    uuid: &[u8; 16],
+) -> Result<Option<SubvolumeMatch>, std::io::Error>
+where
+    U: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+    R: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    let mut found = find_by_uuid(uuid_items, root_items, uuid, UuidKind::Subvolume)?;
+    Ok(found.pop())
+}
+
+/// Finds every subvolume that was received from the subvolume with uuid `uuid`, which is how a
+/// send/receive parent is matched on the receiving side.
+pub fn find_by_received_uuid<'a, U, R>(
+    uuid_items: U,
+    root_items: R,
+    uuid: &[u8; 16],
+) -> Result<Vec<SubvolumeMatch>, std::io::Error>
+where
+    U: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+    R: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    find_by_uuid(uuid_items, root_items, uuid, UuidKind::ReceivedSubvolume)
+}
+
+/// Looks the uuid up in the uuid tree, then resolves the ids through the tree of tree roots.
+/// Entries whose ROOT_ITEM is gone or no longer carries the uuid are stale and skipped.
+fn find_by_uuid<'a, U, R>(
+    uuid_items: U,
+    root_items: R,
+    uuid: &[u8; 16],
+    kind: UuidKind,
+) -> Result<Vec<SubvolumeMatch>, std::io::Error>
+where
+    U: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+    R: IntoIterator<Item = (&'a BtrfsKey, &'a [u8])>,
+{
+    let search_key = uuid_to_key(uuid, kind);
+    let mut ids = match uuid_items.into_iter().find(|(key, _)| **key == search_key) {
+        Some((key, data)) => BtrfsUuidItem::from_item(key, data)?.subvolume_ids,
+        None => return Ok(Vec::new()),
+    };
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut matches = Vec::new();
+    for (key, data) in root_items {
+        if key.type_id != BTRFS_ROOT_ITEM_KEY || ids.binary_search(&key.object_id).is_err() {
+            continue;
+        }
+        matches.extend(match_root_item(key, data, uuid, kind)?);
+    }
+    Ok(matches)
+}
+
+/// Decodes a ROOT_ITEM, None when it doesn't carry `uuid` (a stale uuid tree entry)
+fn match_root_item(
+    key: &BtrfsKey,
+    data: &[u8],
+    uuid: &[u8; 16],
+    kind: UuidKind,
+) -> Result<Option<SubvolumeMatch>, std::io::Error> {
+    let root_item = BtrfsRootItem::from_buffer(data)?;
+    let root_uuid = match kind {
+        UuidKind::Subvolume => &root_item.uuid,
+        UuidKind::ReceivedSubvolume => &root_item.received_uuid,
+    };
+    Ok((root_uuid == uuid).then(|| SubvolumeMatch {
+        subvolume_id: key.object_id,
+        root_key: key.clone(),
+        root_item,
+    }))
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// find_subvolume_by_uuid in this tree, the tree of tree roots, and its uuid tree
+    pub fn find_subvolume_by_uuid(
+        &self,
+        uuid: &[u8; 16],
+    ) -> Result<Option<SubvolumeMatch>, std::io::Error> {
+        let mut found = self.find_by_uuid(uuid, UuidKind::Subvolume)?;
+        Ok(found.pop())
+    }
+
+    /// find_by_received_uuid in this tree, the tree of tree roots, and its uuid tree
+    pub fn find_by_received_uuid(
+        &self,
+        uuid: &[u8; 16],
+    ) -> Result<Vec<SubvolumeMatch>, std::io::Error> {
+        self.find_by_uuid(uuid, UuidKind::ReceivedSubvolume)
+    }
+
+    /// Searches the uuid tree for the key of `uuid`, then the ROOT_ITEM of each id it lists.
+    /// The ROOT_ITEM offset is the snapshot transid, so each id is looked up as a key range.
+    fn find_by_uuid(
+        &self,
+        uuid: &[u8; 16],
+        kind: UuidKind,
+    ) -> Result<Vec<SubvolumeMatch>, std::io::Error> {
+        let uuid_tree = self.open_tree(BTRFS_UUID_TREE_OBJECTID)?;
+        let key = uuid_to_key(uuid, kind);
+        let mut ids = match uuid_tree.search(&key)? {
+            Some(data) => BtrfsUuidItem::from_item(&key, &data)?.subvolume_ids,
+            None => return Ok(Vec::new()),
+        };
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut matches = Vec::new();
+        for id in ids {
+            let min = BtrfsKey::new(id, BTRFS_ROOT_ITEM_KEY, 0);
+            let max = BtrfsKey::new(id, BTRFS_ROOT_ITEM_KEY, u64::MAX);
+            for (key, data) in self.items_in_range(&min, &max)? {
+                matches.extend(match_root_item(&key, &data, uuid, kind)?);
+            }
+        }
+        Ok(matches)
+    }
+}
+
+/// Formats a uuid the usual way, 8-4-4-4-12 hex digits
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::root_tree;
+
+    const UUID: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
+    fn root_item(uuid: &[u8; 16], received_uuid: &[u8; 16]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x1b7];
+        data[0xf7..0x107].copy_from_slice(uuid);
+        data[0x117..0x127].copy_from_slice(received_uuid);
+        data
+    }
+
+    #[test]
+    fn uuid_key_round_trip() {
+        let key = uuid_to_key(&UUID, UuidKind::Subvolume);
+        assert_eq!(key.object_id, 0x0807060504030201);
+        assert_eq!(key.offset, 0x100f0e0d0c0b0a09);
+        assert_eq!(uuid_from_key(&key), UUID);
+    }
+
+    #[test]
+    fn lookup_by_uuid_and_received_uuid() {
+        let source = [0xaa; 16];
+        let subvol_key = uuid_to_key(&UUID, UuidKind::Subvolume);
+        let received_key = uuid_to_key(&source, UuidKind::ReceivedSubvolume);
+        let subvol_ids = 256u64.to_le_bytes();
+        let received_ids: Vec<u8> = [256u64, 300]
+            .iter()
+            .flat_map(|id| id.to_le_bytes())
+            .collect();
+        let uuid_items: Vec<(&BtrfsKey, &[u8])> =
+            vec![(&subvol_key, &subvol_ids), (&received_key, &received_ids)];
+
+        let root_256 = BtrfsKey::new(256, BTRFS_ROOT_ITEM_KEY, 0);
+        // 300 was deleted and its id reused by a subvolume with another received uuid
+        let root_300 = BtrfsKey::new(300, BTRFS_ROOT_ITEM_KEY, 12);
+        let data_256 = root_item(&UUID, &source);
+        let data_300 = root_item(&[0xbb; 16], &[0xcc; 16]);
+        let root_items: Vec<(&BtrfsKey, &[u8])> =
+            vec![(&root_256, &data_256), (&root_300, &data_300)];
+
+        let found = find_subvolume_by_uuid(uuid_items.clone(), root_items.clone(), &UUID)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.subvolume_id, 256);
+
+        let received =
+            find_by_received_uuid(uuid_items.clone(), root_items.clone(), &source).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].subvolume_id, 256);
+
+        assert!(find_subvolume_by_uuid(uuid_items, root_items, &[0; 16])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn lookup_through_the_trees() {
+        let source = [0xaa; 16];
+        let received_ids: Vec<u8> = [256u64, 300]
+            .iter()
+            .flat_map(|id| id.to_le_bytes())
+            .collect();
+        let uuid_items = vec![
+            (
+                uuid_to_key(&UUID, UuidKind::Subvolume),
+                256u64.to_le_bytes().to_vec(),
+            ),
+            (
+                uuid_to_key(&source, UuidKind::ReceivedSubvolume),
+                received_ids,
+            ),
+        ];
+        let root_items = vec![
+            (
+                BtrfsKey::new(256, BTRFS_ROOT_ITEM_KEY, 0),
+                root_item(&UUID, &source),
+            ),
+            // a snapshot, stale for the received uuid
+            (
+                BtrfsKey::new(300, BTRFS_ROOT_ITEM_KEY, 12),
+                root_item(&[0xbb; 16], &[0xcc; 16]),
+            ),
+            (
+                BtrfsKey::new(301, BTRFS_ROOT_ITEM_KEY, 14),
+                root_item(&[0xdd; 16], &source),
+            ),
+        ];
+        let tree = root_tree(root_items, vec![(BTRFS_UUID_TREE_OBJECTID, uuid_items)]);
+
+        let found = tree.find_subvolume_by_uuid(&UUID).unwrap().unwrap();
+        assert_eq!(found.subvolume_id, 256);
+        assert_eq!(found.root_item.received_uuid, source);
+
+        // 301 carries the received uuid but the uuid tree doesn't list it
+        let received = tree.find_by_received_uuid(&source).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].subvolume_id, 256);
+
+        assert!(tree.find_subvolume_by_uuid(&[0; 16]).unwrap().is_none());
+    }
+}
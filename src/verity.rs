@@ -0,0 +1,191 @@
+// ** fs-verity
+// An inode with fs-verity enabled stores its verity metadata in the fs tree next to its inode:
+// |inode| VERITY_DESC_ITEM| 0|          BtrfsVerityDescriptorItem, the size of the descriptor
+// |inode| VERITY_DESC_ITEM| 1 + offset| descriptor bytes starting at `offset`
+// |inode| VERITY_MERKLE_ITEM| offset|  merkle tree bytes starting at `offset`
+// The descriptor and merkle tree are split over as many items as needed to fit in leaves.
+use crate::btrfs::{check_len, read_le_u32, read_le_u64, BtrfsKey};
+
+// FsverityDescriptor::hash_algorithm
+pub const FS_VERITY_HASH_ALG_SHA256: u8 = 1;
+pub const FS_VERITY_HASH_ALG_SHA512: u8 = 2;
+
+/// First VERITY_DESC_ITEM of an inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsVerityDescriptorItem {
+    pub size: u64, // 0x00: size of the fs-verity descriptor that follows in the next items
+    pub encryption: u8, // 0x18: always 0
+}
+
+/// A VERITY_DESC_ITEM (offset > 0) or VERITY_MERKLE_ITEM holding a piece of the metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtrfsVerityChunk {
+    pub inode: u64,  // key object_id
+    pub offset: u64, // byte offset within the descriptor or merkle tree
+    pub data: Vec<u8>,
+}
+
+/// The fs-verity descriptor, as rebuilt from the VERITY_DESC_ITEM chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsverityDescriptor {
+    pub version: u8,         // 0x00: always 1
+    pub hash_algorithm: u8,  // 0x01: FS_VERITY_HASH_ALG_*
+    pub log_blocksize: u8,   // 0x02: log2 of the merkle tree block size
+    pub salt_size: u8,       // 0x03
+    pub sig_size: u32,       // 0x04: size of the signature after the descriptor
+    pub data_size: u64,      // 0x08: size of the file the tree was built over
+    pub root_hash: [u8; 64], // 0x10: only the first digest size bytes are used
+    pub salt: [u8; 32],      // 0x50: only the first salt_size bytes are used
+    pub signature: Vec<u8>,  // 0x100
+}
+
+impl BtrfsVerityDescriptorItem {
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x19)?;
+        Ok(BtrfsVerityDescriptorItem {
+            size: read_le_u64(buffer, 0x00),
+            encryption: buffer[0x18],
+        })
+    }
+}
+
+impl BtrfsVerityChunk {
+    /// Builds a chunk from a VERITY_DESC_ITEM with a non-zero offset (whose data starts at
+    /// offset - 1) or from a VERITY_MERKLE_ITEM
+    pub fn from_item(key: &BtrfsKey, buffer: &[u8], is_descriptor: bool) -> Self {
+        BtrfsVerityChunk {
+            inode: key.object_id,
+            offset: if is_descriptor {
+                key.offset.saturating_sub(1)
+            } else {
+                key.offset
+            },
+            data: buffer.to_vec(),
+        }
+    }
+}
+
+impl FsverityDescriptor {
+    pub fn from_buffer(buffer: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(buffer, 0x100)?;
+        let sig_size = read_le_u32(buffer, 0x04);
+        let signature_end = 0x100 + sig_size as usize;
+        check_len(buffer, signature_end)?;
+        Ok(FsverityDescriptor {
+            version: buffer[0x00],
+            hash_algorithm: buffer[0x01],
+            log_blocksize: buffer[0x02],
+            salt_size: buffer[0x03],
+            sig_size,
+            data_size: read_le_u64(buffer, 0x08),
+            root_hash: buffer[0x10..0x50].try_into().unwrap(),
+            salt: buffer[0x50..0x70].try_into().unwrap(),
+            signature: buffer[0x100..signature_end].to_vec(),
+        })
+    }
+
+    /// The used part of root_hash
+    pub fn digest(&self) -> &[u8] {
+        match self.hash_algorithm {
+            FS_VERITY_HASH_ALG_SHA512 => &self.root_hash[..64],
+            _ => &self.root_hash[..32],
+        }
+    }
+}
+
+/// Concatenates chunks (in key order) into one buffer, failing if there is a gap between them
+pub fn assemble_chunks(chunks: &[BtrfsVerityChunk]) -> Result<Vec<u8>, std::io::Error> {
+    let mut buffer = Vec::new();
+    for chunk in chunks {
+        if chunk.offset != buffer.len() as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "verity item at offset {} but {} bytes were read so far",
+                    chunk.offset,
+                    buffer.len()
+                ),
+            ));
+        }
+        buffer.extend_from_slice(&chunk.data);
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::{BTRFS_VERITY_DESC_ITEM_KEY, BTRFS_VERITY_MERKLE_ITEM_KEY};
+
+    fn descriptor(sig_size: u32) -> Vec<u8> {
+        let mut data = vec![0u8; 0x100 + sig_size as usize];
+        data[0x00] = 1;
+        data[0x01] = FS_VERITY_HASH_ALG_SHA256;
+        data[0x02] = 12;
+        data[0x03] = 4;
+        data[0x04..0x08].copy_from_slice(&sig_size.to_le_bytes());
+        data[0x08..0x10].copy_from_slice(&8192u64.to_le_bytes());
+        data[0x10..0x50].fill(0xaa);
+        data[0x50..0x54].copy_from_slice(b"salt");
+        data[0x100..].fill(0x55);
+        data
+    }
+
+    #[test]
+    fn descriptor_is_parsed() {
+        let parsed = FsverityDescriptor::from_buffer(&descriptor(3)).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.log_blocksize, 12);
+        assert_eq!(parsed.data_size, 8192);
+        assert_eq!(parsed.digest(), &[0xaa; 32]);
+        assert_eq!(&parsed.salt[..parsed.salt_size as usize], b"salt");
+        assert_eq!(parsed.signature, vec![0x55; 3]);
+
+        let mut sha512 = descriptor(0);
+        sha512[0x01] = FS_VERITY_HASH_ALG_SHA512;
+        let parsed = FsverityDescriptor::from_buffer(&sha512).unwrap();
+        assert_eq!(parsed.digest().len(), 64);
+        assert!(parsed.signature.is_empty());
+    }
+
+    #[test]
+    fn truncated_descriptors_are_rejected() {
+        let data = descriptor(0);
+        assert!(FsverityDescriptor::from_buffer(&data[..0xff]).is_err());
+        // the signature runs past the end of the buffer
+        let data = descriptor(16);
+        assert!(FsverityDescriptor::from_buffer(&data[..0x108]).is_err());
+        assert!(BtrfsVerityDescriptorItem::from_buffer(&[0; 0x18]).is_err());
+    }
+
+    #[test]
+    fn chunks_are_assembled_in_order() {
+        let desc = |offset| BtrfsKey::new(257, BTRFS_VERITY_DESC_ITEM_KEY, offset);
+        let merkle = |offset| BtrfsKey::new(257, BTRFS_VERITY_MERKLE_ITEM_KEY, offset);
+
+        // descriptor items start at key offset 1
+        let chunks = vec![
+            BtrfsVerityChunk::from_item(&desc(1), b"abc", true),
+            BtrfsVerityChunk::from_item(&desc(4), b"de", true),
+        ];
+        assert_eq!(chunks[1].offset, 3);
+        assert_eq!(assemble_chunks(&chunks).unwrap(), b"abcde");
+        assert!(assemble_chunks(&[]).unwrap().is_empty());
+
+        let first = BtrfsVerityChunk::from_item(&merkle(0), b"abcd", false);
+        let second = BtrfsVerityChunk::from_item(&merkle(4), b"efgh", false);
+        let third = BtrfsVerityChunk::from_item(&merkle(8), b"ij", false);
+        let assembled = [first.clone(), second.clone(), third.clone()];
+        assert_eq!(assemble_chunks(&assembled).unwrap(), b"abcdefghij");
+
+        let missing = [first.clone(), third.clone()];
+        let error = assemble_chunks(&missing).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let out_of_order = [first.clone(), third, second];
+        assert!(assemble_chunks(&out_of_order).is_err());
+        let missing_start = [BtrfsVerityChunk::from_item(&merkle(4), b"efgh", false)];
+        assert!(assemble_chunks(&missing_start).is_err());
+        let duplicate = [first.clone(), first];
+        assert!(assemble_chunks(&duplicate).is_err());
+    }
+}
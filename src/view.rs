@@ -0,0 +1,238 @@
+// ** Borrowed node views
+// BtrfsLeafNode and BtrfsInternalNode copy every key and the whole item area out of the block.
+// The views below read them in place from a borrowed tree block instead, typically a slice of
+// a memory mapped image, so a full tree scan doesn't allocate per node.
+// Only the item count is checked up front; keys, pointers and payloads are read on access.
+use crate::btrfs::{
+    read_le_u32, read_le_u64, BtrfsHeader, BtrfsInternalNode, BtrfsKey, BtrfsLeafNode,
+};
+
+/// A leaf read in place
+#[derive(Clone, Copy, Debug)]
+pub struct LeafView<'a> {
+    nritems: usize,
+    buffer: &'a [u8], // the whole tree block
+}
+
+/// An internal node read in place
+#[derive(Clone, Copy, Debug)]
+pub struct InternalView<'a> {
+    nritems: usize,
+    buffer: &'a [u8], // the whole tree block
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum NodeView<'a> {
+    Internal(InternalView<'a>),
+    Leaf(LeafView<'a>),
+}
+
+impl<'a> NodeView<'a> {
+    /// Checks that the block holds the items its header claims and picks the view by level
+    pub fn from_buffer(buffer: &'a [u8]) -> Result<Self, std::io::Error> {
+        let header = BtrfsHeader::from_buffer(buffer)?;
+        if header.level == 0 {
+            Ok(NodeView::Leaf(LeafView::from_buffer(buffer)?))
+        } else {
+            Ok(NodeView::Internal(InternalView::from_buffer(buffer)?))
+        }
+    }
+
+    pub fn header(&self) -> BtrfsHeader {
+        match self {
+            NodeView::Internal(node) => node.header(),
+            NodeView::Leaf(leaf) => leaf.header(),
+        }
+    }
+}
+
+/// Validates the item count of a block against its length and returns it
+fn checked_nritems(buffer: &[u8], entry_size: usize) -> Result<usize, std::io::Error> {
+    let header = BtrfsHeader::from_buffer(buffer)?;
+    let nritems = header.nritems as usize;
+    let needed = BtrfsHeader::SIZE + nritems * entry_size;
+    if buffer.len() < needed {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "tree block of {} bytes can't hold {} items",
+                buffer.len(),
+                nritems
+            ),
+        ));
+    }
+    Ok(nritems)
+}
+
+impl<'a> LeafView<'a> {
+    pub fn from_buffer(buffer: &'a [u8]) -> Result<Self, std::io::Error> {
+        let nritems = checked_nritems(buffer, BtrfsLeafNode::ITEM_SIZE)?;
+        Ok(LeafView { nritems, buffer })
+    }
+
+    pub fn header(&self) -> BtrfsHeader {
+        BtrfsHeader::from_buffer(self.buffer).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nritems
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nritems == 0
+    }
+
+    fn item(&self, idx: usize) -> &'a [u8] {
+        let start = BtrfsHeader::SIZE + idx * BtrfsLeafNode::ITEM_SIZE;
+        &self.buffer[start..start + BtrfsLeafNode::ITEM_SIZE]
+    }
+
+    /// Key of the idx-th item. Panics if idx >= len().
+    pub fn key(&self, idx: usize) -> BtrfsKey {
+        BtrfsKey::from_buffer(self.item(idx)).unwrap()
+    }
+
+    /// Payload of the idx-th item, or None if the item points outside the leaf
+    pub fn item_data(&self, idx: usize) -> Option<&'a [u8]> {
+        if idx >= self.nritems {
+            return None;
+        }
+        let item = self.item(idx);
+        let start = BtrfsHeader::SIZE + read_le_u32(item, 0x11) as usize;
+        let end = start.checked_add(read_le_u32(item, 0x15) as usize)?;
+        self.buffer.get(start..end)
+    }
+
+    /// Same as binary_search on the keys of the leaf
+    pub fn binary_search(&self, key: &BtrfsKey) -> Result<usize, usize> {
+        let (mut low, mut high) = (0, self.nritems);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.key(mid).cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    /// Iterates over the (key, payload) pairs of the leaf, skipping items pointing outside it
+    pub fn iter_items(&self) -> impl Iterator<Item = (BtrfsKey, &'a [u8])> + 'a {
+        let view = *self;
+        (0..self.nritems).filter_map(move |idx| Some((view.key(idx), view.item_data(idx)?)))
+    }
+
+    /// Copies the leaf into an owned BtrfsLeafNode
+    pub fn to_owned_node(&self) -> Result<BtrfsLeafNode, std::io::Error> {
+        BtrfsLeafNode::from_buffer(self.buffer)
+    }
+}
+
+impl<'a> InternalView<'a> {
+    pub fn from_buffer(buffer: &'a [u8]) -> Result<Self, std::io::Error> {
+        let nritems = checked_nritems(buffer, BtrfsInternalNode::KEY_PTR_SIZE)?;
+        Ok(InternalView { nritems, buffer })
+    }
+
+    pub fn header(&self) -> BtrfsHeader {
+        BtrfsHeader::from_buffer(self.buffer).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nritems
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nritems == 0
+    }
+
+    fn key_ptr(&self, idx: usize) -> &'a [u8] {
+        let start = BtrfsHeader::SIZE + idx * BtrfsInternalNode::KEY_PTR_SIZE;
+        &self.buffer[start..start + BtrfsInternalNode::KEY_PTR_SIZE]
+    }
+
+    /// Smallest key under the idx-th child. Panics if idx >= len().
+    pub fn key(&self, idx: usize) -> BtrfsKey {
+        BtrfsKey::from_buffer(self.key_ptr(idx)).unwrap()
+    }
+
+    /// Logical address of the idx-th child. Panics if idx >= len().
+    pub fn block_ptr(&self, idx: usize) -> u64 {
+        read_le_u64(self.key_ptr(idx), 0x11)
+    }
+
+    /// Generation the idx-th child was written in. Panics if idx >= len().
+    pub fn generation(&self, idx: usize) -> u64 {
+        read_le_u64(self.key_ptr(idx), 0x19)
+    }
+
+    /// Index of the child that may hold `key`, None if the key sorts before the whole node
+    pub fn child_for(&self, key: &BtrfsKey) -> Option<usize> {
+        let (mut low, mut high) = (0, self.nritems);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.key(mid) <= *key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low.checked_sub(1)
+    }
+
+    /// Copies the node into an owned BtrfsInternalNode
+    pub fn to_owned_node(&self) -> Result<BtrfsInternalNode, std::io::Error> {
+        BtrfsInternalNode::from_buffer(self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_image::{internal, leaf};
+
+    #[test]
+    fn leaf_view_matches_owned_leaf() {
+        let items: Vec<_> = (1..=3u64)
+            .map(|id| (BtrfsKey::new(id, 1, 0), vec![id as u8; id as usize]))
+            .collect();
+        let block = leaf(0x1000, 5, &items);
+        let NodeView::Leaf(view) = NodeView::from_buffer(&block).unwrap() else {
+            panic!("expected a leaf");
+        };
+        let owned = view.to_owned_node().unwrap();
+
+        assert_eq!(view.len(), 3);
+        assert_eq!(view.header().block_nr, 0x1000);
+        let viewed: Vec<_> = view.iter_items().collect();
+        let copied: Vec<_> = owned.iter_items().map(|(k, d)| (k.clone(), d)).collect();
+        assert_eq!(viewed, copied);
+        assert_eq!(view.binary_search(&BtrfsKey::new(2, 1, 0)), Ok(1));
+        assert_eq!(view.binary_search(&BtrfsKey::new(2, 2, 0)), Err(2));
+        assert!(view.item_data(3).is_none());
+        assert!(LeafView::from_buffer(&block[..0x80]).is_err());
+    }
+
+    #[test]
+    fn internal_view_picks_children() {
+        let ptrs = [
+            (BtrfsKey::new(10, 1, 0), 0x2000),
+            (BtrfsKey::new(20, 1, 0), 0x3000),
+        ];
+        let block = internal(0x1000, 1, 1, &ptrs);
+        let NodeView::Internal(view) = NodeView::from_buffer(&block).unwrap() else {
+            panic!("expected an internal node");
+        };
+        assert_eq!(view.block_ptr(1), 0x3000);
+        assert_eq!(view.generation(0), 1);
+        assert_eq!(view.child_for(&BtrfsKey::new(5, 1, 0)), None);
+        assert_eq!(view.child_for(&BtrfsKey::new(10, 1, 0)), Some(0));
+        assert_eq!(view.child_for(&BtrfsKey::new(25, 0, 0)), Some(1));
+        assert_eq!(view.to_owned_node().unwrap().keys, view_keys(&view));
+    }
+
+    fn view_keys(view: &InternalView) -> Vec<BtrfsKey> {
+        (0..view.len()).map(|idx| view.key(idx)).collect()
+    }
+}
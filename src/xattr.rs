@@ -0,0 +1,322 @@
+// ** Extended attributes
+// Xattrs of an inode are stored like directory entries, keyed by
+// |inode| XATTR_ITEM| name hash|
+// with the xattr name as the entry name and its value as the entry data (the location key is
+// unused). Names whose hashes collide share an item.
+// Two kinds of values have a binary format of their own:
+// - system.posix_acl_access / system.posix_acl_default, a POSIX ACL:
+//   |version: u32 (2)| entries of |tag: u16| perm: u16| id: u32|
+// - security.capability, the file capabilities (vfs_cap_data):
+//   |magic_etc: u32| permitted low: u32| inheritable low: u32| permitted high| inheritable high|
+//   rootid: u32 (revision 3)|
+//   revision 1 stops after the low words. All fields are little endian.
+use crate::btrees::BTree;
+use crate::btrfs::{check_len, read_le_u16, read_le_u32, BtrfsKey, BTRFS_XATTR_ITEM_KEY};
+use crate::dir::{name_hash, BtrfsDirItem};
+use crate::storage::BlockStorage;
+
+pub const XATTR_NAME_POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+pub const XATTR_NAME_POSIX_ACL_DEFAULT: &str = "system.posix_acl_default";
+pub const XATTR_NAME_CAPS: &str = "security.capability";
+
+/// An extended attribute of an inode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xattr {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl<S: BlockStorage> BTree<S> {
+    /// Lists the xattrs of `inode`, in name hash order
+    pub fn list_xattrs(&self, inode: u64) -> Result<Vec<Xattr>, std::io::Error> {
+        let min = BtrfsKey::new(inode, BTRFS_XATTR_ITEM_KEY, 0);
+        let max = BtrfsKey::new(inode, BTRFS_XATTR_ITEM_KEY, u64::MAX);
+        let mut xattrs = Vec::new();
+        for (_, data) in self.items_in_range(&min, &max)? {
+            xattrs.extend(
+                BtrfsDirItem::parse_items(&data)?
+                    .into_iter()
+                    .map(|entry| Xattr {
+                        name: entry.name,
+                        value: entry.data,
+                    }),
+            );
+        }
+        Ok(xattrs)
+    }
+
+    /// Value of the xattr `name` of `inode`, None if it isn't set
+    pub fn get_xattr(&self, inode: u64, name: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let key = BtrfsKey::new(inode, BTRFS_XATTR_ITEM_KEY, name_hash(name));
+        let Some(data) = self.search(&key)? else {
+            return Ok(None);
+        };
+        Ok(BtrfsDirItem::parse_items(&data)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.data))
+    }
+}
+
+// ** POSIX ACLs
+pub const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+// PosixAclEntry::tag
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+// PosixAclEntry::perm
+pub const ACL_READ: u16 = 0x04;
+pub const ACL_WRITE: u16 = 0x02;
+pub const ACL_EXECUTE: u16 = 0x01;
+
+/// Who an ACL entry applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclTag {
+    UserObj,    // the owner
+    User(u32),  // a uid
+    GroupObj,   // the owning group
+    Group(u32), // a gid
+    Mask,       // upper bound of the group class entries
+    Other,
+}
+
+/// One entry of a POSIX ACL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PosixAclEntry {
+    pub tag: AclTag,
+    pub perm: u16, // ACL_READ | ACL_WRITE | ACL_EXECUTE
+}
+
+impl PosixAclEntry {
+    /// Size of an entry in the xattr
+    pub const SIZE: usize = 8;
+
+    /// The permissions as "rwx" with '-' for the missing ones, as getfacl shows them
+    pub fn perm_string(&self) -> String {
+        [(ACL_READ, 'r'), (ACL_WRITE, 'w'), (ACL_EXECUTE, 'x')]
+            .iter()
+            .map(|&(bit, c)| if self.perm & bit != 0 { c } else { '-' })
+            .collect()
+    }
+}
+
+/// Decodes the value of a system.posix_acl_access or system.posix_acl_default xattr
+pub fn parse_posix_acl(value: &[u8]) -> Result<Vec<PosixAclEntry>, std::io::Error> {
+    check_len(value, 4)?;
+    let version = read_le_u32(value, 0);
+    if version != POSIX_ACL_XATTR_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unsupported POSIX ACL version {}", version),
+        ));
+    }
+    let entries = &value[4..];
+    if !entries.len().is_multiple_of(PosixAclEntry::SIZE) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("POSIX ACL of {} bytes", value.len()),
+        ));
+    }
+    entries
+        .chunks_exact(PosixAclEntry::SIZE)
+        .map(|entry| {
+            let id = read_le_u32(entry, 4);
+            let tag = match read_le_u16(entry, 0) {
+                ACL_USER_OBJ => AclTag::UserObj,
+                ACL_USER => AclTag::User(id),
+                ACL_GROUP_OBJ => AclTag::GroupObj,
+                ACL_GROUP => AclTag::Group(id),
+                ACL_MASK => AclTag::Mask,
+                ACL_OTHER => AclTag::Other,
+                other => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unknown POSIX ACL tag {:#x}", other),
+                    ))
+                }
+            };
+            Ok(PosixAclEntry {
+                tag,
+                perm: read_le_u16(entry, 2),
+            })
+        })
+        .collect()
+}
+
+// ** File capabilities
+pub const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+pub const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+pub const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+pub const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+pub const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x0000_0001;
+
+/// A decoded security.capability xattr. Capability sets are bit masks indexed by capability
+/// number (CAP_NET_BIND_SERVICE is bit 10...).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileCapabilities {
+    pub revision: u32,       // 1, 2 or 3
+    pub effective: bool,     // permitted capabilities are raised in the effective set on exec
+    pub permitted: u64,      // forced into the permitted set
+    pub inheritable: u64,    // ANDed with the inheritable set of the caller
+    pub rootid: Option<u32>, // revision 3: uid of root in the user namespace they apply to
+}
+
+impl FileCapabilities {
+    pub fn from_buffer(value: &[u8]) -> Result<Self, std::io::Error> {
+        check_len(value, 4)?;
+        let magic_etc = read_le_u32(value, 0);
+        let (revision, size) = match magic_etc & VFS_CAP_REVISION_MASK {
+            VFS_CAP_REVISION_1 => (1, 12),
+            VFS_CAP_REVISION_2 => (2, 20),
+            VFS_CAP_REVISION_3 => (3, 24),
+            other => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unknown file capabilities revision {:#x}", other),
+                ))
+            }
+        };
+        check_len(value, size)?;
+        let high = |offset| {
+            if revision > 1 {
+                (read_le_u32(value, offset) as u64) << 32
+            } else {
+                0
+            }
+        };
+        Ok(FileCapabilities {
+            revision,
+            effective: magic_etc & VFS_CAP_FLAGS_EFFECTIVE != 0,
+            permitted: read_le_u32(value, 4) as u64 | high(12),
+            inheritable: read_le_u32(value, 8) as u64 | high(16),
+            rootid: (revision == 3).then(|| read_le_u32(value, 20)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btrfs::BTRFS_INODE_ITEM_KEY;
+    use crate::inode::S_IFREG;
+    use crate::test_image::{fs_tree, inode_item, xattr_item};
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        value
+    }
+
+    #[test]
+    fn lists_and_gets_xattrs() {
+        let xattr_key =
+            |name: &str| BtrfsKey::new(257, BTRFS_XATTR_ITEM_KEY, name_hash(name.as_bytes()));
+        // two names forced into the same item, as a hash collision would
+        let mut shared = xattr_item("user.a", b"1");
+        shared.extend_from_slice(&xattr_item("user.b", b"22"));
+        let tree = fs_tree(
+            vec![
+                (
+                    BtrfsKey::new(257, BTRFS_INODE_ITEM_KEY, 0),
+                    inode_item(S_IFREG, 0),
+                ),
+                (xattr_key("user.a"), shared),
+                (
+                    xattr_key("user.mime_type"),
+                    xattr_item("user.mime_type", b"text/plain"),
+                ),
+            ],
+            &[],
+        );
+
+        let mut names: Vec<Vec<u8>> = tree
+            .list_xattrs(257)
+            .unwrap()
+            .into_iter()
+            .map(|xattr| xattr.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, [&b"user.a"[..], b"user.b", b"user.mime_type"]);
+        assert_eq!(
+            tree.get_xattr(257, b"user.mime_type").unwrap().unwrap(),
+            b"text/plain"
+        );
+        assert_eq!(tree.get_xattr(257, b"user.a").unwrap().unwrap(), b"1");
+        assert_eq!(tree.get_xattr(257, b"user.nope").unwrap(), None);
+        assert!(tree.list_xattrs(258).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decodes_posix_acls() {
+        // user::rw- user:1000:r-x group::r-- mask::r-x other::---
+        let value = acl(&[
+            (ACL_USER_OBJ, ACL_READ | ACL_WRITE, u32::MAX),
+            (ACL_USER, ACL_READ | ACL_EXECUTE, 1000),
+            (ACL_GROUP_OBJ, ACL_READ, u32::MAX),
+            (ACL_MASK, ACL_READ | ACL_EXECUTE, u32::MAX),
+            (ACL_OTHER, 0, u32::MAX),
+        ]);
+        let entries = parse_posix_acl(&value).unwrap();
+        let decoded: Vec<(AclTag, String)> = entries
+            .iter()
+            .map(|entry| (entry.tag, entry.perm_string()))
+            .collect();
+        assert_eq!(
+            decoded,
+            [
+                (AclTag::UserObj, "rw-".to_string()),
+                (AclTag::User(1000), "r-x".to_string()),
+                (AclTag::GroupObj, "r--".to_string()),
+                (AclTag::Mask, "r-x".to_string()),
+                (AclTag::Other, "---".to_string()),
+            ]
+        );
+
+        assert!(parse_posix_acl(&value[..10]).is_err());
+        let mut old = value.clone();
+        old[0] = 1;
+        assert!(parse_posix_acl(&old).is_err());
+    }
+
+    #[test]
+    fn decodes_file_capabilities() {
+        // cap_net_bind_service+ep, as setcap writes it (revision 2)
+        let mut value = (VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE)
+            .to_le_bytes()
+            .to_vec();
+        for word in [1u32 << 10, 0, 0, 0] {
+            value.extend_from_slice(&word.to_le_bytes());
+        }
+        let caps = FileCapabilities::from_buffer(&value).unwrap();
+        assert_eq!(
+            caps,
+            FileCapabilities {
+                revision: 2,
+                effective: true,
+                permitted: 1 << 10,
+                inheritable: 0,
+                rootid: None,
+            }
+        );
+
+        // revision 3 in a user namespace, CAP_CHECKPOINT_RESTORE (40) only fits the high word
+        let mut value = VFS_CAP_REVISION_3.to_le_bytes().to_vec();
+        for word in [0u32, 0, 1 << 8, 0, 100_000] {
+            value.extend_from_slice(&word.to_le_bytes());
+        }
+        let caps = FileCapabilities::from_buffer(&value).unwrap();
+        assert_eq!(caps.permitted, 1 << 40);
+        assert!(!caps.effective);
+        assert_eq!(caps.rootid, Some(100_000));
+        assert!(FileCapabilities::from_buffer(&value[..20]).is_err());
+    }
+}